@@ -0,0 +1,130 @@
+//! Compares delivering chan messages through one server wide broadcast, where every
+//! connection wakes up and checks membership, with the per-connection outboxes that
+//! only the chan's members get.
+//!
+//!     cargo run --release --example fanout_bench -- [chans] [members per chan] [msgs]
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use tokio::sync::{RwLock, broadcast};
+use txt_chat::chatsvc::{ChatService, Message};
+
+const BATCH: usize = 500; // stay under the old shared 1000 buffer so nobody lags
+
+#[tokio::main]
+async fn main() {
+    let mut args = std::env::args()
+        .skip(1)
+        .map(|a| a.parse::<usize>().expect("expect numbers"));
+    let chans = args.next().unwrap_or(2000);
+    let per_chan = args.next().unwrap_or(5).clamp(1, chans);
+    let msgs = args.next().unwrap_or(2000);
+
+    println!("{} chans, {} members each, {} users, {} msgs", chans, per_chan, chans, msgs);
+    let old = shared_broadcast(chans, per_chan, msgs).await;
+    println!("shared broadcast: {:>10.2?} ({:.2?}/msg)", old, old / msgs as u32);
+    let new = per_conn(chans, per_chan, msgs).await;
+    println!("per-connection:   {:>10.2?} ({:.2?}/msg)", new, new / msgs as u32);
+    println!("speedup: {:.1}x", old.as_secs_f64() / new.as_secs_f64());
+}
+
+/// One user per chan owns it, and joins the next `per_chan - 1` chans. Users are
+/// logged in on the conn `conn-<i>`, which is not connected yet.
+fn setup(chans: usize, per_chan: usize) -> (ChatService, Vec<String>, Vec<String>) {
+    let mut svc = ChatService::new(chans);
+    let uids: Vec<String> = (0..chans)
+        .map(|i| svc.login(format!("conn-{}", i), format!("u{}", i), None).expect("login").0)
+        .collect();
+
+    let chan_ids: Vec<String> = uids
+        .iter()
+        .enumerate()
+        .map(|(i, uid)| svc.create_chan(uid.clone(), format!("chan{}", i), None).expect("create chan"))
+        .collect();
+    for (i, uid) in uids.iter().enumerate() {
+        for j in 1..per_chan {
+            svc.join_chan(uid.clone(), chan_ids[(i + j) % chans].clone()).expect("join");
+        }
+    }
+    (svc, uids, chan_ids)
+}
+
+async fn post_all<F>(
+    svc: &Arc<RwLock<ChatService>>,
+    uids: &[String],
+    chan_ids: &[String],
+    msgs: usize,
+    expected_per_msg: usize,
+    delivered: &AtomicUsize,
+    mut fan_out: F,
+) -> Duration
+where
+    F: FnMut(Message),
+{
+    let start = Instant::now();
+    let mut sent = 0;
+    while sent < msgs {
+        let n = BATCH.min(msgs - sent);
+        for k in sent..sent + n {
+            let i = k % chan_ids.len();
+            let line = format!("msg {}", k);
+            svc.write()
+                .await
+                .post_msg(uids[i].clone(), chan_ids[i].clone(), line.clone(), None)
+                .expect("post");
+            fan_out(Message::new(uids[i].clone(), chan_ids[i].clone(), line));
+        }
+        sent += n;
+        while delivered.load(Ordering::Relaxed) < sent * expected_per_msg {
+            tokio::task::yield_now().await;
+        }
+    }
+    start.elapsed()
+}
+
+async fn shared_broadcast(chans: usize, per_chan: usize, msgs: usize) -> Duration {
+    let (svc, uids, chan_ids) = setup(chans, per_chan);
+    let svc = Arc::new(RwLock::new(svc));
+    let delivered = Arc::new(AtomicUsize::new(0));
+    let (tx, _) = broadcast::channel::<Message>(1000);
+
+    // what every connection's writer used to do
+    for uid in uids.iter() {
+        let (svc, uid, delivered) = (svc.clone(), uid.clone(), delivered.clone());
+        let mut rx = tx.subscribe();
+        tokio::spawn(async move {
+            while let Ok(msg) = rx.recv().await {
+                if svc.read().await.is_user_sub(&uid, &msg.chan_id) {
+                    delivered.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        });
+    }
+
+    // no conn is connected, so post_msg itself hands nothing out
+    post_all(&svc, &uids, &chan_ids, msgs, per_chan, &delivered, |msg| {
+        let _ = tx.send(msg);
+    })
+    .await
+}
+
+async fn per_conn(chans: usize, per_chan: usize, msgs: usize) -> Duration {
+    let (mut svc, uids, chan_ids) = setup(chans, per_chan);
+    let delivered = Arc::new(AtomicUsize::new(0));
+
+    for i in 0..uids.len() {
+        let mut outbox = svc.connect(format!("conn-{}", i));
+        let delivered = delivered.clone();
+        tokio::spawn(async move {
+            while outbox.rx.recv().await.is_some() {
+                delivered.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+    }
+
+    // post_msg already hands the message to the members' outboxes
+    let svc = Arc::new(RwLock::new(svc));
+    post_all(&svc, &uids, &chan_ids, msgs, per_chan, &delivered, |_| {}).await
+}
@@ -0,0 +1,121 @@
+//! Posts from many clients, each into its own chan and typing and marking it
+//! read along the way, once with every event taking the service lock
+//! exclusively (how `handle_event` used to run) and once through
+//! `handle_event`, where these events take it shared and only lock their chan.
+//!
+//!     cargo run --release --example load_test -- [clients] [msgs per client] [worker threads]
+//!
+//! The gain grows with the cores the machine can actually run at once.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::RwLock;
+use txt_chat::chatsvc::ChatService;
+use txt_chat::event::{Event, handler::handle_event};
+
+fn main() {
+    let mut args = std::env::args()
+        .skip(1)
+        .map(|a| a.parse::<usize>().expect("expect numbers"));
+    let clients = args.next().unwrap_or(200);
+    let msgs = args.next().unwrap_or(200);
+    let threads = args
+        .next()
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(4, |n| n.get()));
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(threads)
+        .build()
+        .expect("runtime");
+
+    println!("{} clients x {} msgs, {} worker threads", clients, msgs, threads);
+    let total = (clients * msgs) as f64;
+    let exclusive = runtime.block_on(run(clients, msgs, true));
+    println!("exclusive lock: {:>10.2?} ({:.0} msgs/s)", exclusive, total / exclusive.as_secs_f64());
+    let shared = runtime.block_on(run(clients, msgs, false));
+    println!("handle_event:   {:>10.2?} ({:.0} msgs/s)", shared, total / shared.as_secs_f64());
+    println!("throughput gain: {:.1}x", exclusive.as_secs_f64() / shared.as_secs_f64());
+}
+
+async fn run(clients: usize, msgs: usize, exclusive: bool) -> Duration {
+    let mut svc = ChatService::new(clients);
+    let mut chans = Vec::with_capacity(clients);
+    for i in 0..clients {
+        let conn_id = format!("conn-{}", i);
+        // drain the outbox like a connection's writer does
+        let mut outbox = svc.connect(conn_id.clone());
+        tokio::spawn(async move { while outbox.rx.recv().await.is_some() {} });
+        let (uid, _) = svc.login(conn_id.clone(), format!("u{}", i), None).expect("login");
+        let chan_id = svc
+            .create_named_chan(uid.clone(), format!("chan{}", i))
+            .expect("create chan");
+        chans.push((conn_id, uid, chan_id));
+    }
+    let svc = Arc::new(RwLock::new(svc));
+    let posted: Vec<String> = chans.iter().map(|(_, _, chan_id)| chan_id.clone()).collect();
+
+    let start = Instant::now();
+    let mut tasks = Vec::with_capacity(clients);
+    for (conn_id, uid, chan_id) in chans {
+        let svc = svc.clone();
+        tasks.push(tokio::spawn(async move {
+            for k in 0..msgs {
+                let msg = format!("load test message {} from {}", k, uid);
+                let mut events = vec![Event::SendMsg {
+                    user_id: uid.clone(),
+                    chan_id: chan_id.clone(),
+                    msg,
+                    parent_id: None,
+                }];
+                if k % 4 == 0 {
+                    events.push(Event::Typing {
+                        user_id: uid.clone(),
+                        chan_id: chan_id.clone(),
+                    });
+                }
+                if k % 8 == 0 {
+                    events.push(Event::MarkRead {
+                        user_id: uid.clone(),
+                        chan_id: chan_id.clone(),
+                        msg_id: None,
+                    });
+                }
+                for event in events {
+                    if exclusive {
+                        let svc = svc.clone();
+                        tokio::task::spawn_blocking(move || apply_exclusive(&mut svc.blocking_write(), event))
+                            .await
+                            .expect("exclusive event");
+                    } else {
+                        handle_event(conn_id.clone(), svc.clone(), event).await;
+                    }
+                }
+            }
+        }));
+    }
+    for task in tasks {
+        task.await.expect("client task");
+    }
+    let elapsed = start.elapsed();
+
+    // errors are only reported to the conn, make sure every post landed
+    let svc = svc.read().await;
+    for chan_id in posted {
+        let last = svc.storage.messages_before(&chan_id, None, 1).expect("history");
+        let last = last.first().map(|msg| msg.content.as_str()).unwrap_or_default();
+        assert!(last.starts_with(&format!("load test message {} ", msgs - 1)), "lost posts in {}", chan_id);
+    }
+    elapsed
+}
+
+fn apply_exclusive(svc: &mut ChatService, event: Event) {
+    match event {
+        Event::SendMsg { user_id, chan_id, msg, parent_id } => {
+            svc.post_msg(user_id, chan_id, msg, parent_id).expect("post")
+        }
+        Event::Typing { user_id, chan_id } => svc.typing(user_id, chan_id).expect("typing"),
+        Event::MarkRead { user_id, chan_id, msg_id } => svc.mark_read(user_id, chan_id, msg_id).expect("mark read"),
+        event => panic!("not part of the load: {:?}", event),
+    }
+}
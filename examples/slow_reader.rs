@@ -0,0 +1,194 @@
+//! Backpressure check against a running server with a deliberately slow reader
+//! and one that never reads:
+//!
+//!     echo secret > admin.key
+//!     txt-chat --admins watcher --admin-key-file admin.key --max-lag 500
+//!     cargo run --release --example slow_reader -- [addr] [admin key]
+//!
+//! `watcher` reads everything and polls `stats`. A sender floods `#lobby` until
+//! both slow readers drop messages, then the lagging reader catches up and must
+//! get a `$$lagged` notice followed by history, while the stuck one keeps not
+//! reading until the server disconnects it.
+
+use std::time::Duration;
+
+use anyhow::{Result, anyhow, bail};
+use futures::{SinkExt, StreamExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::sync::{mpsc, watch};
+use tokio::time::timeout;
+use tokio_util::codec::{FramedRead, FramedWrite, LinesCodec};
+
+const CHUNK: usize = 100; // messages posted between two stats polls
+const MAX_MSGS: usize = 200_000;
+
+struct Client {
+    uid: String,
+    read: FramedRead<OwnedReadHalf, LinesCodec>,
+    write: FramedWrite<OwnedWriteHalf, LinesCodec>,
+}
+
+impl Client {
+    /// A small receive buffer so the kernel does not hide a slow reader for long.
+    async fn connect(addr: &str, name: &str, key: Option<&str>, recv_buffer: Option<u32>) -> Result<Self> {
+        let socket = TcpSocket::new_v4()?;
+        if let Some(size) = recv_buffer {
+            socket.set_recv_buffer_size(size)?;
+        }
+        let stream: TcpStream = socket.connect(addr.parse()?).await?;
+        let (read, write) = stream.into_split();
+        let mut read = FramedRead::new(read, LinesCodec::new());
+        let mut write = FramedWrite::new(write, LinesCodec::new());
+
+        // skip the welcome, the server answers the register with our uid
+        read.next().await.ok_or_else(|| anyhow!("{}: no welcome", name))??;
+        match key {
+            Some(key) => write.send(format!("reg${}${}", name, key)).await?,
+            None => write.send(format!("reg${}", name)).await?,
+        }
+        let uid = read.next().await.ok_or_else(|| anyhow!("{}: no uid", name))??;
+        if uid.starts_with("$$error") {
+            bail!("{}: register failed: {}", name, uid);
+        }
+        Ok(Self { uid, read, write })
+    }
+
+    /// Read until nothing arrives for `idle`, or the server closes the connection.
+    async fn drain(&mut self, idle: Duration) -> (Vec<String>, bool) {
+        let mut lines = Vec::new();
+        loop {
+            match timeout(idle, self.read.next()).await {
+                Ok(Some(Ok(line))) => lines.push(line),
+                Ok(_) => return (lines, true),
+                Err(_) => return (lines, false),
+            }
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let addr = std::env::args().nth(1).unwrap_or("127.0.0.1:9090".to_string());
+    let key = std::env::args().nth(2).unwrap_or("secret".to_string());
+
+    let watcher = Client::connect(&addr, "watcher", Some(&key), None).await?;
+    let mut sender = Client::connect(&addr, "sender", None, None).await?;
+    let mut lagging = Client::connect(&addr, "lagging", None, Some(4096)).await?;
+    let mut stuck = Client::connect(&addr, "stuck", None, Some(4096)).await?;
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    // the watcher keeps up with everything and hands stats lines and its count over
+    let (stats_tx, mut stats_rx) = mpsc::unbounded_channel();
+    let (received_tx, mut received_rx) = watch::channel(0);
+    let Client { uid: watcher_uid, read: mut watcher_read, write: mut watcher_write } = watcher;
+    let watcher_task = tokio::spawn(async move {
+        let (mut received, mut lagged) = (0, 0);
+        while let Some(Ok(line)) = watcher_read.next().await {
+            if line.starts_with("$$stats") {
+                let _ = stats_tx.send(line);
+            } else if line.starts_with("$$lagged") {
+                lagged += 1;
+            } else if line.contains("#lobby sender: flood") {
+                received += 1;
+                received_tx.send_replace(received);
+            }
+        }
+        (received, lagged)
+    });
+    let mut sender_read = sender.read;
+    tokio::spawn(async move { while let Some(Ok(_)) = sender_read.next().await {} });
+
+    // the server reads each connection on its own, so wait for the posts to go
+    // through before asking, or the readers fall further behind than a chunk
+    let mut stats = async |watcher_write: &mut FramedWrite<OwnedWriteHalf, LinesCodec>,
+                           posted: usize|
+           -> Result<String> {
+        timeout(Duration::from_secs(5), received_rx.wait_for(|n| *n >= posted)).await??;
+        watcher_write.send(format!("stats${}", watcher_uid)).await?;
+        timeout(Duration::from_secs(5), stats_rx.recv())
+            .await?
+            .ok_or_else(|| anyhow!("watcher gone"))
+    };
+    let stat = |line: &str, key: &str| -> usize {
+        line.split_whitespace()
+            .find_map(|kv| kv.strip_prefix(key)?.strip_prefix('=')?.parse().ok())
+            .unwrap_or(0)
+    };
+
+    // 1. flood until both slow readers have dropped messages
+    let mut posted = 0;
+    loop {
+        for _ in 0..CHUNK {
+            sender.write.send(format!("send_msg${}$#lobby$flood {}", sender.uid, posted)).await?;
+            posted += 1;
+        }
+        let line = stats(&mut watcher_write, posted).await?;
+        if stat(&line, "lagging") >= 2 {
+            println!("after {} msgs: {}", posted, line);
+            break;
+        }
+        if posted >= MAX_MSGS {
+            bail!("no reader fell behind after {} msgs: {}", posted, line);
+        }
+    }
+
+    // 2. the lagging reader catches up: notice, then the missed messages as history
+    let (lines, closed) = lagging.drain(Duration::from_secs(2)).await;
+    let notice = lines.iter().find(|line| line.starts_with("$$lagged"));
+    let history = lines.iter().filter(|line| line.starts_with("$$history")).count();
+    println!(
+        "lagging reader: {} lines, notice: {:?}, {} history lines, still connected: {}",
+        lines.len(),
+        notice,
+        history,
+        !closed
+    );
+    if notice.is_none() || closed {
+        bail!("lagging reader was not told it fell behind");
+    }
+
+    // 3. keep flooding until the stuck reader misses more than max lag,
+    //    the lagging one now reads between chunks and keeps up
+    let mut caught_up = 0;
+    loop {
+        for _ in 0..CHUNK {
+            sender.write.send(format!("send_msg${}$#lobby$flood {}", sender.uid, posted)).await?;
+            posted += 1;
+        }
+        caught_up += lagging.drain(Duration::from_millis(50)).await.0.len();
+        let line = stats(&mut watcher_write, posted).await?;
+        if stat(&line, "slow_disconnects") >= 1 {
+            println!("after {} msgs: {}", posted, line);
+            break;
+        }
+        if posted >= MAX_MSGS {
+            bail!("stuck reader was never disconnected after {} msgs: {}", posted, line);
+        }
+    }
+    let (lines, closed) = stuck.drain(Duration::from_secs(5)).await;
+    println!(
+        "stuck reader: {} lines, last: {:?}, disconnected: {}",
+        lines.len(),
+        lines.last(),
+        closed
+    );
+    if !closed {
+        bail!("stuck reader is still connected");
+    }
+
+    // the lagging reader kept up again and the watcher never lagged
+    let (lines, closed) = lagging.drain(Duration::from_secs(2)).await;
+    println!(
+        "lagging reader afterwards: {} lines, still connected: {}",
+        caught_up + lines.len(),
+        !closed
+    );
+    drop(watcher_write);
+    let (received, lagged) = timeout(Duration::from_secs(5), watcher_task).await??;
+    println!("watcher: {} of {} msgs, {} lag notices", received, posted, lagged);
+    if received != posted || lagged > 0 {
+        bail!("a reader that keeps up lost messages");
+    }
+    Ok(())
+}
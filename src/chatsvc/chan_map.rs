@@ -0,0 +1,88 @@
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use super::Channel;
+
+/// Chan id -> chan, each behind its own lock. Holding the service exclusively
+/// reaches a chan without locking; holding it shared locks only the chan asked
+/// for, so posts to different chans run in parallel.
+///
+/// Never lock a second chan while holding a guard.
+#[derive(Debug, Default)]
+pub struct ChanMap {
+    chans: HashMap<String, Mutex<Channel>>,
+}
+
+impl ChanMap {
+    pub fn with_capacity(cap: usize) -> Self {
+        Self {
+            chans: HashMap::with_capacity(cap),
+        }
+    }
+
+    pub fn get<Q>(&self, chan_id: &Q) -> Option<MutexGuard<'_, Channel>>
+    where
+        String: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.chans
+            .get(chan_id)
+            .map(|chan| chan.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    pub fn get_mut<Q>(&mut self, chan_id: &Q) -> Option<&mut Channel>
+    where
+        String: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.chans
+            .get_mut(chan_id)
+            .map(|chan| chan.get_mut().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    pub fn insert(&mut self, chan_id: String, chan: Channel) {
+        self.chans.insert(chan_id, Mutex::new(chan));
+    }
+
+    pub fn remove<Q>(&mut self, chan_id: &Q) -> Option<Channel>
+    where
+        String: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.chans
+            .remove(chan_id)
+            .map(|chan| chan.into_inner().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    pub fn contains_key<Q>(&self, chan_id: &Q) -> bool
+    where
+        String: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.chans.contains_key(chan_id)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.chans.keys()
+    }
+
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut Channel> {
+        self.chans
+            .values_mut()
+            .map(|chan| chan.get_mut().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    pub fn len(&self) -> usize {
+        self.chans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chans.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.chans.clear();
+    }
+}
@@ -0,0 +1,395 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use futures::{Sink, SinkExt};
+use tokio::sync::{RwLock, mpsc};
+use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+use super::{ChatService, ERROR_RESP, KICK_WRITE_TIMEOUT, LAGGED_RESP, Message};
+use crate::errors::ChatErrors;
+
+/// Server side of a connection: where messages for it are queued.
+#[derive(Debug)]
+pub struct Conn {
+    pub tx: mpsc::Sender<Message>,
+    pub lag: Arc<Lag>,
+}
+
+/// What the connection's writer drains, see `write_outbox`.
+#[derive(Debug)]
+pub struct Outbox {
+    pub rx: mpsc::Receiver<Message>,
+    pub lag: Arc<Lag>,
+}
+
+/// Messages dropped because the reader fell behind and its outbox was full,
+/// kept until the reader is told about them.
+#[derive(Debug, Default)]
+pub struct Lag {
+    missed: AtomicUsize,
+    dropped: Mutex<Missed>,
+    pub kick: CancellationToken, // cancelled once the reader is too far behind
+}
+
+/// What a lagging reader missed since the last notice.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Missed {
+    pub msgs: BTreeMap<String, Vec<String>>, // chan id -> ids of the dropped user messages, oldest first
+    pub lines: usize,                        // dropped server lines, never stored so not resent
+}
+
+impl Lag {
+    /// Record a dropped message, returning how many are missed since the last notice.
+    pub fn miss(&self, msg: &Message) -> usize {
+        let mut dropped = self.dropped.lock().unwrap_or_else(PoisonError::into_inner);
+        if msg.is_cmd {
+            dropped.lines += 1;
+        } else {
+            dropped.msgs.entry(msg.chan_id.clone()).or_default().push(msg.id.clone());
+        }
+        self.missed.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    pub fn missed(&self) -> usize {
+        self.missed.load(Ordering::Relaxed)
+    }
+
+    /// Take what was missed, resetting it.
+    pub fn take(&self) -> Missed {
+        let mut dropped = self.dropped.lock().unwrap_or_else(PoisonError::into_inner);
+        self.missed.store(0, Ordering::Relaxed);
+        std::mem::take(&mut *dropped)
+    }
+
+    /// `$$lagged: <total> <chan id>:<missed> ... [server:<missed>]`
+    pub fn notice(missed: &Missed) -> String {
+        let mut counts: Vec<String> = missed
+            .msgs
+            .iter()
+            .map(|(chan_id, ids)| format!("{}:{}", chan_id, ids.len()))
+            .collect();
+        if missed.lines > 0 {
+            counts.push(format!("server:{}", missed.lines));
+        }
+        let total = missed.msgs.values().map(Vec::len).sum::<usize>() + missed.lines;
+        format!("{}: {} {}", LAGGED_RESP, total, counts.join(" "))
+    }
+}
+
+/// Drain the outbox into the conn's sink until the outbox closes, the sink
+/// fails or the reader is kicked for lagging; a kicked reader is told why.
+///
+/// Once the reader is through the backlog, the messages dropped meanwhile
+/// follow the `$$lagged` notice as history lines, each exactly once. They
+/// arrive after newer messages, the reader orders them by id; server lines
+/// are not stored and only counted.
+pub async fn write_outbox<S>(svc: &RwLock<ChatService>, outbox: &mut Outbox, sink: &mut S)
+where
+    S: Sink<String> + Unpin,
+    S::Error: fmt::Display,
+{
+    // only the chans this user is in are routed here, and the sender goes away on disconnect
+    let kick = outbox.lag.kick.clone();
+    'conn: loop {
+        // a kicked reader gets no more messages, just the reason
+        let msg = tokio::select! {
+            biased;
+            _ = kick.cancelled() => break,
+            msg = outbox.rx.recv() => msg,
+        };
+        let Some(msg) = msg else {
+            break;
+        };
+
+        let mut lines = vec![msg.to_string()];
+        if outbox.rx.is_empty() && outbox.lag.missed() > 0 {
+            let missed = outbox.lag.take();
+            let svc = svc.read().await;
+            let resync = svc.resync(&missed);
+            svc.conn_metrics.lag_notices.fetch_add(1, Ordering::Relaxed);
+            svc.conn_metrics.resynced.fetch_add(resync.len() as u64, Ordering::Relaxed);
+            lines.push(Lag::notice(&missed));
+            lines.extend(resync);
+        }
+
+        for line in lines {
+            tokio::select! {
+                biased;
+                _ = kick.cancelled() => break 'conn,
+                res = sink.send(line) => {
+                    if let Err(e) = res {
+                        warn!("Failed to send response: {}", e);
+                        break 'conn;
+                    }
+                }
+            }
+        }
+    }
+
+    if kick.is_cancelled() {
+        let err = ChatErrors::SlowConsumer(outbox.lag.missed());
+        let _ = timeout(KICK_WRITE_TIMEOUT, sink.send(format!("{}: {}", ERROR_RESP, err))).await;
+    }
+}
+
+/// Server wide counters of how connections keep up, see the admin `stats` command.
+#[derive(Debug, Default)]
+pub struct ConnMetrics {
+    pub dropped: AtomicU64,          // messages dropped for full outboxes
+    pub lag_notices: AtomicU64,      // `$$lagged` notices sent
+    pub resynced: AtomicU64,         // history lines sent to lagged readers
+    pub slow_disconnects: AtomicU64, // readers dropped for missing more than `max_lag`
+    pub rejected: AtomicU64,         // conns refused by `ConnLimits`
+    pub too_long: AtomicU64,         // conns dropped for a line over the max length
+    pub timed_out: AtomicU64,        // conns dropped for not registering or going idle
+}
+
+impl ConnMetrics {
+    /// `dropped=<n> lag_notices=<n> resynced=<n> slow_disconnects=<n> rejected=<n> too_long=<n> timed_out=<n>`
+    pub fn summary(&self) -> String {
+        format!(
+            "dropped={} lag_notices={} resynced={} slow_disconnects={} rejected={} too_long={} timed_out={}",
+            self.dropped.load(Ordering::Relaxed),
+            self.lag_notices.load(Ordering::Relaxed),
+            self.resynced.load(Ordering::Relaxed),
+            self.slow_disconnects.load(Ordering::Relaxed),
+            self.rejected.load(Ordering::Relaxed),
+            self.too_long.load(Ordering::Relaxed),
+            self.timed_out.load(Ordering::Relaxed)
+        )
+    }
+}
+
+/// Caps on open conns, overall and per client ip, checked on accept.
+#[derive(Debug)]
+pub struct ConnLimits {
+    max_conns: usize,
+    max_conns_per_ip: usize,
+    open: Mutex<OpenConns>,
+}
+
+#[derive(Debug, Default)]
+struct OpenConns {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+impl ConnLimits {
+    pub fn new(max_conns: usize, max_conns_per_ip: usize) -> Self {
+        Self {
+            max_conns,
+            max_conns_per_ip,
+            open: Mutex::default(),
+        }
+    }
+
+    /// Count a new conn from `ip`, released when the permit is dropped.
+    pub fn admit(self: &Arc<Self>, ip: IpAddr) -> Result<ConnPermit, ChatErrors> {
+        let mut open = self.open.lock().unwrap_or_else(PoisonError::into_inner);
+        if open.total >= self.max_conns {
+            return Err(ChatErrors::TooManyConnections(format!("server is full, {} open", open.total)));
+        }
+        // only counted once admitted, so refused ips leave no entry behind
+        let from_ip = open.per_ip.get(&ip).copied().unwrap_or_default();
+        if from_ip >= self.max_conns_per_ip {
+            return Err(ChatErrors::TooManyConnections(format!("{} already open from {}", from_ip, ip)));
+        }
+        open.per_ip.insert(ip, from_ip + 1);
+        open.total += 1;
+        Ok(ConnPermit {
+            limits: self.clone(),
+            ip,
+        })
+    }
+}
+
+/// An admitted conn, see `ConnLimits::admit`.
+#[derive(Debug)]
+pub struct ConnPermit {
+    limits: Arc<ConnLimits>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnPermit {
+    fn drop(&mut self) {
+        let mut open = self.limits.open.lock().unwrap_or_else(PoisonError::into_inner);
+        open.total -= 1;
+        if let Some(from_ip) = open.per_ip.get_mut(&self.ip) {
+            *from_ip -= 1;
+            if *from_ip == 0 {
+                open.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::chatsvc::{HISTORY_RESP, SHUTDOWN_RESP};
+
+    /// Alice posts to the default chan, bob reads it through an outbox of `conn_buffer`.
+    fn lagging_reader(conn_buffer: usize, max_lag: usize) -> (ChatService, String, Outbox) {
+        let mut svc = ChatService::new(8);
+        let lobby = svc.add_default_chan("lobby".to_string(), false).unwrap();
+        svc.connect("c1".to_string());
+        svc.login("c1".to_string(), "alice".to_string(), None).unwrap();
+
+        svc.conn_buffer = conn_buffer;
+        let mut outbox = svc.connect("c2".to_string());
+        svc.login("c2".to_string(), "bob".to_string(), None).unwrap();
+        // login lines overflow the small outbox too
+        while outbox.rx.try_recv().is_ok() {}
+        outbox.lag.take();
+        svc.max_lag = max_lag;
+        (svc, lobby, outbox)
+    }
+
+    fn post(svc: &ChatService, n: usize) -> Vec<String> {
+        let alice = svc.session("c1").unwrap().clone();
+        let lobby = svc.default_chans[0].clone();
+        (0..n)
+            .map(|i| {
+                svc.post_msg(alice.clone(), lobby.clone(), format!("m{}", i), None).unwrap();
+                svc.storage.messages_before(&lobby, None, 1).unwrap()[0].id.clone()
+            })
+            .collect()
+    }
+
+    /// Run the writer until the outbox closes, returning what was written.
+    async fn write(svc: ChatService, mut outbox: Outbox) -> Vec<String> {
+        let (mut sink, lines) = futures::channel::mpsc::unbounded();
+        write_outbox(&RwLock::new(svc), &mut outbox, &mut sink).await;
+        drop(sink);
+        lines.collect().await
+    }
+
+    #[tokio::test]
+    async fn resyncs_exactly_the_dropped_messages() {
+        let (mut svc, lobby, outbox) = lagging_reader(2, 100);
+        let ids = post(&svc, 5);
+        svc.send_to_conn("c2", "not stored".to_string());
+        svc.disconnect("c2");
+
+        let lines = write(svc, outbox).await;
+        assert_eq!(lines.len(), 6, "{:?}", lines);
+        assert!(lines[0].starts_with(&format!("[{}]", ids[0])));
+        assert!(lines[1].starts_with(&format!("[{}]", ids[1])));
+        assert_eq!(lines[2], format!("{}: 4 {}:3 server:1", LAGGED_RESP, lobby));
+        for (line, id) in lines[3..].iter().zip(&ids[2..]) {
+            assert!(line.starts_with(HISTORY_RESP) && line.contains(&format!("[{}]", id)), "{}", line);
+        }
+    }
+
+    #[tokio::test]
+    async fn kicks_a_reader_past_max_lag() {
+        let (mut svc, _, outbox) = lagging_reader(1, 2);
+        let metrics = svc.conn_metrics.clone();
+        let dropped_on_login = metrics.dropped.load(Ordering::Relaxed);
+        post(&svc, 4);
+        assert!(outbox.lag.kick.is_cancelled());
+        svc.disconnect("c2");
+
+        let lines = write(svc, outbox).await;
+        let err = ChatErrors::SlowConsumer(3);
+        assert_eq!(lines.last(), Some(&format!("{}: {}", ERROR_RESP, err)));
+        assert!(!lines.iter().any(|line| line.starts_with(LAGGED_RESP) || line.starts_with(HISTORY_RESP)));
+        assert_eq!(metrics.slow_disconnects.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.dropped.load(Ordering::Relaxed) - dropped_on_login, 3);
+    }
+
+    #[tokio::test]
+    async fn no_notice_without_drops() {
+        let (mut svc, _, outbox) = lagging_reader(8, 100);
+        post(&svc, 3);
+        svc.disconnect("c2");
+        let lines = write(svc, outbox).await;
+        assert_eq!(lines.len(), 3, "{:?}", lines);
+        assert!(lines.iter().all(|line| !line.starts_with(LAGGED_RESP)));
+    }
+
+    #[tokio::test]
+    async fn shutdown_notice_follows_the_queued_lines() {
+        let (mut svc, _, outbox) = lagging_reader(8, 100);
+        let mut unregistered = svc.connect("c3".to_string());
+        post(&svc, 2);
+        svc.announce_shutdown().await;
+        svc.disconnect("c2");
+
+        let notice = format!("{}: server is shutting down", SHUTDOWN_RESP);
+        let lines = write(svc, outbox).await;
+        assert_eq!(lines.len(), 3, "{:?}", lines);
+        assert_eq!(lines[2], notice);
+        assert_eq!(unregistered.rx.try_recv().unwrap().to_string(), notice);
+    }
+
+    #[tokio::test]
+    async fn shutdown_notice_waits_for_a_full_outbox() {
+        let (mut svc, _, mut outbox) = lagging_reader(2, 100);
+        post(&svc, 2);
+        let announced = tokio::spawn(svc.announce_shutdown());
+        // the notice still holds the outbox open
+        svc.disconnect("c2");
+
+        let (mut sink, lines) = futures::channel::mpsc::unbounded();
+        write_outbox(&RwLock::new(svc), &mut outbox, &mut sink).await;
+        announced.await.unwrap();
+        drop(sink);
+        let lines: Vec<String> = lines.collect().await;
+        assert_eq!(lines.len(), 3, "{:?}", lines);
+        assert_eq!(lines[2], format!("{}: server is shutting down", SHUTDOWN_RESP));
+        assert_eq!(outbox.lag.missed(), 0);
+    }
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, last])
+    }
+
+    fn tracked(limits: &ConnLimits) -> (usize, usize) {
+        let open = limits.open.lock().unwrap();
+        (open.total, open.per_ip.len())
+    }
+
+    #[test]
+    fn admit_caps_per_ip_and_releases_on_drop() {
+        let limits = Arc::new(ConnLimits::new(10, 2));
+        let first = limits.admit(ip(1)).unwrap();
+        let _second = limits.admit(ip(1)).unwrap();
+        assert!(matches!(limits.admit(ip(1)), Err(ChatErrors::TooManyConnections(_))));
+        let _other = limits.admit(ip(2)).unwrap();
+
+        drop(first);
+        assert!(limits.admit(ip(1)).is_ok());
+    }
+
+    #[test]
+    fn admit_caps_total() {
+        let limits = Arc::new(ConnLimits::new(2, 10));
+        let _permits = [limits.admit(ip(1)).unwrap(), limits.admit(ip(2)).unwrap()];
+        assert!(matches!(limits.admit(ip(3)), Err(ChatErrors::TooManyConnections(_))));
+        assert_eq!(tracked(&limits), (2, 2));
+    }
+
+    #[test]
+    fn refused_ips_are_not_tracked() {
+        let limits = Arc::new(ConnLimits::new(10, 0));
+        for last in 0..=255 {
+            assert!(limits.admit(ip(last)).is_err());
+        }
+        assert_eq!(tracked(&limits), (0, 0));
+    }
+
+    #[test]
+    fn dropped_permits_forget_the_ip() {
+        let limits = Arc::new(ConnLimits::new(10, 1));
+        drop(limits.admit(ip(1)).unwrap());
+        assert_eq!(tracked(&limits), (0, 0));
+    }
+}
@@ -1,65 +1,638 @@
 use nanoid::nanoid;
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tracing::{info, warn};
 
 use chrono::Utc;
-use tokio::sync::broadcast;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc::{self, error::TrySendError};
 
-pub const JOIN_RESP: &'static str = "$$joined";
-pub const LEAVE_RESP: &'static str = "$$leaved";
-pub const CREATE_CHAN_RESP: &'static str = "$$create_chan";
+use crate::errors::ChatErrors;
+use crate::export::{self, ExportFormat};
+use crate::search::{MAX_SEARCH_RESULTS, SEARCH_OVERFETCH, SearchIndex, SearchQuery, tokenize};
+use crate::storage::{MemoryStorage, SNAPSHOT_VERSION, Snapshot, Storage};
 
-#[derive(Debug, Clone)]
+mod chan_map;
+mod conn;
+mod shards;
+#[cfg(test)]
+pub(crate) mod testing;
+
+pub use chan_map::ChanMap;
+pub use conn::{Conn, ConnLimits, ConnMetrics, ConnPermit, Lag, Missed, Outbox, write_outbox};
+pub use shards::{SHARDS, Shards};
+
+pub const JOIN_RESP: &str = "$$joined";
+pub const LEAVE_RESP: &str = "$$leaved";
+pub const CREATE_CHAN_RESP: &str = "$$create_chan";
+pub const DELETE_CHAN_RESP: &str = "$$deleted_chan";
+pub const ARCHIVE_CHAN_RESP: &str = "$$archived";
+pub const EDIT_MSG_RESP: &str = "$$edited";
+pub const DELETE_MSG_RESP: &str = "$$deleted_msg";
+pub const REPLIES_RESP: &str = "$$replies";
+pub const THREAD_RESP: &str = "$$thread";
+pub const THREAD_MSG_RESP: &str = "$$thread_msg";
+pub const REACTIONS_RESP: &str = "$$reactions";
+pub const MAX_REACTION_LEN: usize = 32;
+pub const MENTION_RESP: &str = "$$mention";
+pub const TYPING_RESP: &str = "$$typing";
+pub const TYPING_INTERVAL: Duration = Duration::from_secs(2); // min gap between one user's typing events
+pub const UNREAD_RESP: &str = "$$unread";
+pub const REPLAY_RESP: &str = "$$replay";
+pub const HISTORY_RESP: &str = "$$history";
+pub const HISTORY_END_RESP: &str = "$$history_end";
+pub const SEARCH_RESP: &str = "$$search";
+pub const SEARCH_END_RESP: &str = "$$search_end";
+pub const EXPORT_RESP: &str = "$$export";
+pub const EXPORT_END_RESP: &str = "$$export_end";
+pub const MAX_EXPORT_MSGS: usize = 500; // over the wire, the CLI exports everything
+pub const OFFLINE_RESP: &str = "$$offline";
+pub const OFFLINE_OVERFLOW_RESP: &str = "$$offline_overflow";
+pub const DEFAULT_OFFLINE_QUEUE_CAP: usize = 100;
+pub const CONN_BUFFER: usize = 1000; // messages queued per connection before it counts as lagging
+pub const DEFAULT_MAX_LAG: usize = 1000; // messages a reader may miss before it is disconnected
+pub const LAGGED_RESP: &str = "$$lagged";
+pub const KICK_WRITE_TIMEOUT: Duration = Duration::from_secs(1); // to tell a kicked reader why
+pub const STATS_RESP: &str = "$$stats";
+pub const SHUTDOWN_RESP: &str = "$$shutdown";
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10); // to drain conns before exiting anyway
+pub const LINGER_TIMEOUT: Duration = Duration::from_secs(2); // reading from a closed conn so its last error is not lost to a reset
+pub const DEFAULT_MAX_LINE_LEN: usize = 8 * 1024; // bytes per protocol line
+pub const DEFAULT_MAX_MSG_LEN: usize = 4 * 1024; // bytes per message content
+pub const DEFAULT_MAX_CONNS: usize = 10_000;
+pub const DEFAULT_MAX_CONNS_PER_IP: usize = 100;
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10); // to register after connecting
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60 * 60); // without a line from the client
+pub const PING_INTERVAL: Duration = Duration::from_secs(30); // how often clients ping, so a passive reader is not idle
+pub const MAX_HISTORY_PAGE: usize = 100;
+pub const DEFAULT_HISTORY_SIZE: usize = 100;
+pub const MEMORY_STORED_MSGS: usize = 10_000; // per chan kept in memory without a storage backend, or in the wal's mirror
+pub const CHAN_MODE_RESP: &str = "$$chan_mode";
+pub const AUTO_JOIN_RESP: &str = "$$auto_joined";
+pub const SNAPSHOT_RESP: &str = "$$snapshot";
+pub const PRUNE_INTERVAL: Duration = Duration::from_secs(60); // how often retention policies are applied
+pub const DEFAULT_SNAPSHOT_PATH: &str = "txt-chat.snapshot";
+pub const ERROR_RESP: &str = "$$error";
+pub const TOKEN_RESP: &str = "$$token";
+
+/// Owner of server-created chans, such as the default ones.
+pub const SERVER_UID: &str = "$server";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UserInfo {
     pub id: String,
     pub name: String,
+    pub token_hash: String, // of the token issued when the name was claimed, see `hash_token`
+    #[serde(default)]
+    pub offered_chans: HashSet<String>, // default chans already joined once, left ones stay left
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
+    pub id: String, // time sortable, see `gen_msg_id`
     pub chan_id: String,
+    pub chan_name: String,
     pub sender: String,
+    pub sender_id: String,
     pub content: String,
     pub send_time: chrono::DateTime<Utc>,
+    pub is_cmd: bool, // server response rather than a user message
+    pub parent_id: Option<String>, // root of the thread this message replies to
+    pub reply_count: usize,
+    pub reactions: BTreeMap<String, HashSet<String>>, // emoji or :shortcode: -> user ids
+    pub edited: bool,
+    pub deleted: bool,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Channel {
     pub id: String,
     pub name: String,
+    pub owner: String,
+    pub archived: bool,
+    pub read_only: bool, // only owner and moderators can post
+    pub moderators: HashSet<String>,
+    pub slow_mode: Option<Duration>, // min interval between one user's messages
+    pub max_msgs_per_sec: Option<u32>,
+    #[serde(default)]
+    pub retention: Retention,
+    #[serde(skip)]
+    pub last_post: HashMap<String, Instant>,
+    #[serde(skip)]
+    pub recent_posts: VecDeque<Instant>, // posts within the last second
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub messages: BTreeMap<String, Message>, // last `history_size` user messages by id
+    #[serde(skip)]
     pub online_users: HashMap<String, ()>,
+    #[serde(skip)]
+    pub read_markers: HashMap<String, String>, // uid -> last read msg id
+    #[serde(skip)]
+    pub typing_at: HashMap<String, Instant>, // uid -> last typing event
+}
+
+/// Messages waiting for a user to log in again, newest `offline_queue_cap` kept.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OfflineQueue {
+    pub msgs: VecDeque<String>,
+    pub dropped: usize, // older messages pushed out by the cap
+}
+
+impl OfflineQueue {
+    /// Queue a line, pushing out the oldest beyond `keep`.
+    pub fn push(&mut self, line: String, keep: usize) {
+        self.msgs.push_back(line);
+        while self.msgs.len() > keep {
+            self.msgs.pop_front();
+            self.dropped += 1;
+        }
+    }
 }
 
+/// Events that only post to or read from a chan run with the service locked
+/// shared (see `Event::is_shared`), so each chan has its own lock in `ChanMap`,
+/// read markers and typing events live in their chan, and the service wide
+/// maps they change are split into `Shards`.
 pub struct ChatService {
-    pub tx: broadcast::Sender<Message>,
-    pub users: HashMap<String, UserInfo>,
-    pub channels: HashMap<String, Channel>,
+    pub conns: HashMap<String, Conn>, // conn id -> outbox its writer drains
+    pub conn_buffer: usize,           // outbox size of new conns
+    pub max_lag: usize,
+    pub max_msg_len: usize,
+    pub conn_metrics: Arc<ConnMetrics>,
+    pub sessions: HashMap<String, String>, // conn id -> uid logged in on it
+    pub online: HashMap<String, String>,   // uid -> conn id of its session
+    pub users: HashMap<String, UserInfo>,  // uid -> user, the uid never changes once the name is claimed
+    pub user_names: HashMap<String, String>, // lowercase username -> uid
+    pub channels: ChanMap,
     pub user_chans: HashMap<String, HashSet<String>>,
+    pub chan_users: HashMap<String, HashSet<String>>, // chan id -> member uids, inverse of `user_chans`
+    pub chan_names: HashMap<String, String>, // lowercase chan name -> chan id
+    pub default_chans: Vec<String>,          // chans every new user joins on register
+    pub admins: HashSet<String>,             // lowercase usernames that moderate every chan
+    pub admin_key_hash: Option<String>,      // what admins log in with instead of a token, see `hash_token`
+    pub history_size: usize,                 // messages kept per chan
+    pub storage: Box<dyn Storage>,
+    pub snapshot_path: String, // where the admin `snapshot` command writes to
+    pub search_index: Shards<SearchIndex>, // by chan id
+    pub offline_queues: Shards<HashMap<String, OfflineQueue>>, // by uid, uid -> queued messages
+    pub offline_queue_cap: usize,
+    pub queue_all_offline: bool, // queue all chan traffic, not only DMs and mentions
 }
 
 impl ChatService {
-    pub fn new(cap: usize, tx: broadcast::Sender<Message>) -> Self {
+    pub fn new(cap: usize) -> Self {
         Self {
-            tx,
+            conns: HashMap::with_capacity(cap),
+            conn_buffer: CONN_BUFFER,
+            max_lag: DEFAULT_MAX_LAG,
+            max_msg_len: DEFAULT_MAX_MSG_LEN,
+            conn_metrics: Arc::new(ConnMetrics::default()),
+            sessions: HashMap::with_capacity(cap),
+            online: HashMap::with_capacity(cap),
             users: HashMap::with_capacity(cap),
-            channels: HashMap::with_capacity(cap),
+            user_names: HashMap::with_capacity(cap),
+            channels: ChanMap::with_capacity(cap),
             user_chans: HashMap::with_capacity(cap),
+            chan_users: HashMap::with_capacity(cap),
+            chan_names: HashMap::with_capacity(cap),
+            default_chans: Vec::new(),
+            admins: HashSet::new(),
+            admin_key_hash: None,
+            history_size: DEFAULT_HISTORY_SIZE,
+            storage: Box::new(MemoryStorage::bounded(MEMORY_STORED_MSGS)),
+            snapshot_path: DEFAULT_SNAPSHOT_PATH.to_string(),
+            search_index: Shards::new(SHARDS, || SearchIndex::bounded(MEMORY_STORED_MSGS)),
+            offline_queues: Shards::default(),
+            offline_queue_cap: DEFAULT_OFFLINE_QUEUE_CAP,
+            queue_all_offline: false,
+        }
+    }
+
+    /// Switch to `storage` and rebuild users, chans, memberships and history from it.
+    pub fn load_storage(&mut self, storage: Box<dyn Storage>) -> Result<(), ChatErrors> {
+        let state = storage.load()?;
+        // backends keep every message, so the index does too
+        let mut index = Shards::new(SHARDS, SearchIndex::new);
+
+        for user in state.users {
+            self.user_names.insert(user.name.to_lowercase(), user.id.clone());
+            self.users.insert(user.id.clone(), user);
+        }
+        for chan in state.channels {
+            self.insert_chan(chan);
+        }
+        for (uid, chan_id) in state.members {
+            self.add_member(&uid, &chan_id);
+        }
+        for msg in state.messages {
+            index.get_mut(&msg.chan_id).add(&msg);
+            if let Some(chan) = self.channels.get_mut(&msg.chan_id) {
+                chan.push_message(msg, self.history_size);
+            }
+        }
+        self.search_index = index;
+        self.put_read_markers(state.read_markers);
+        for (uid, queue) in state.offline_queues {
+            self.offline_queues.get_mut(&uid).insert(uid, queue);
+        }
+
+        info!(
+            "loaded {} users, {} chans from storage",
+            self.users.len(),
+            self.channels.len()
+        );
+        self.storage = storage;
+        Ok(())
+    }
+
+    /// Hand loaded read markers, uid -> chan id -> msg id, to their chans.
+    fn put_read_markers(&mut self, markers: HashMap<String, HashMap<String, String>>) {
+        for (uid, markers) in markers {
+            for (chan_id, msg_id) in markers {
+                if let Some(chan) = self.channels.get_mut(&chan_id) {
+                    chan.read_markers.insert(uid.clone(), msg_id);
+                }
+            }
+        }
+    }
+
+    /// Put back a chan loaded from storage or a snapshot.
+    fn insert_chan(&mut self, chan: Channel) {
+        // personal chans share the owner's id and stay out of the `#name` namespace
+        if chan.id != chan.owner {
+            self.chan_names.insert(chan.name.to_lowercase(), chan.id.clone());
+        }
+        self.channels.insert(chan.id.clone(), chan);
+    }
+
+    /// Everything but runtime state. History comes from storage, so messages
+    /// already pushed out of the chans' bounded history are kept too.
+    pub fn snapshot(&self) -> Result<Snapshot, ChatErrors> {
+        let mut messages: BTreeMap<String, Message> = self
+            .storage
+            .load()?
+            .messages
+            .into_iter()
+            .map(|msg| (msg.id.clone(), msg))
+            .collect();
+        let mut channels = Vec::with_capacity(self.channels.len());
+        let mut read_markers: HashMap<String, HashMap<String, String>> = HashMap::new();
+        for chan in self.channels.keys().filter_map(|chan_id| self.channels.get(chan_id)) {
+            messages.extend(chan.messages.iter().map(|(id, msg)| (id.clone(), msg.clone())));
+            for (uid, msg_id) in chan.read_markers.iter() {
+                read_markers.entry(uid.clone()).or_default().insert(chan.id.clone(), msg_id.clone());
+            }
+            channels.push(chan.settings());
+        }
+
+        Ok(Snapshot {
+            version: SNAPSHOT_VERSION,
+            created_at: Utc::now(),
+            users: self.users.values().cloned().collect(),
+            channels,
+            members: self
+                .user_chans
+                .iter()
+                .flat_map(|(uid, chans)| chans.iter().map(|chan_id| (uid.clone(), chan_id.clone())))
+                .collect(),
+            messages: messages.into_values().collect(),
+            read_markers,
+        })
+    }
+
+    /// Replace users, chans, memberships, read markers and history with the
+    /// snapshot's. Storage is cleared and rewritten from it first, so a failed
+    /// write leaves memory as it was and fails startup; restoring again redoes
+    /// the whole store. Queued offline lines are dropped.
+    pub fn restore(&mut self, snapshot: Snapshot) -> Result<(), ChatErrors> {
+        self.persist(|s| {
+            s.clear()?;
+            for user in snapshot.users.iter() {
+                s.put_user(user)?;
+            }
+            for chan in snapshot.channels.iter() {
+                s.put_channel(chan)?;
+            }
+            for msg in snapshot.messages.iter() {
+                s.put_message(msg)?;
+            }
+            for (uid, chan_id) in snapshot.members.iter() {
+                s.put_member(uid, chan_id)?;
+            }
+            for (uid, markers) in snapshot.read_markers.iter() {
+                for (chan_id, msg_id) in markers {
+                    s.put_read_marker(uid, chan_id, msg_id)?;
+                }
+            }
+            Ok(())
+        })?;
+
+        self.offline_queues.iter_mut().for_each(HashMap::clear);
+        self.users.clear();
+        self.user_names.clear();
+        self.channels.clear();
+        self.user_chans.clear();
+        self.chan_users.clear();
+        self.chan_names.clear();
+        self.search_index.iter_mut().for_each(SearchIndex::clear);
+
+        for user in snapshot.users {
+            self.user_names.insert(user.name.to_lowercase(), user.id.clone());
+            self.users.insert(user.id.clone(), user);
+        }
+        for chan in snapshot.channels {
+            self.insert_chan(chan.settings());
+        }
+        for msg in snapshot.messages {
+            self.search_index.get_mut(&msg.chan_id).add(&msg);
+            if let Some(chan) = self.channels.get_mut(&msg.chan_id) {
+                chan.push_message(msg, self.history_size);
+            }
+        }
+        for (uid, chan_id) in snapshot.members {
+            self.add_member(&uid, &chan_id);
+        }
+        self.put_read_markers(snapshot.read_markers);
+
+        info!(
+            "restored {} users, {} chans from snapshot taken at {}",
+            self.users.len(),
+            self.channels.len(),
+            snapshot.created_at
+        );
+        Ok(())
+    }
+
+    /// Admin only: connection and backpressure counters.
+    pub fn send_stats(&self, uid: String) -> Result<(), ChatErrors> {
+        if !self.is_admin(&uid) {
+            return Err(ChatErrors::PermissionDenied("only admins can see stats".to_string()));
+        }
+
+        let lagging = self.conns.values().filter(|conn| conn.lag.missed() > 0).count();
+        self.notify_user(
+            &uid,
+            format!(
+                "{}: conns={} lagging={} {}",
+                STATS_RESP,
+                self.conns.len(),
+                lagging,
+                self.conn_metrics.summary()
+            ),
+        );
+        Ok(())
+    }
+
+    /// Admin only: write a snapshot to `snapshot_path`.
+    pub fn save_snapshot(&self, uid: String) -> Result<(), ChatErrors> {
+        if !self.is_admin(&uid) {
+            return Err(ChatErrors::PermissionDenied("only admins can take snapshots".to_string()));
+        }
+
+        let snapshot = self.snapshot()?;
+        snapshot.save(&self.snapshot_path)?;
+        self.notify_user(
+            &uid,
+            format!(
+                "{}: {} {} users {} chans",
+                SNAPSHOT_RESP,
+                self.snapshot_path,
+                snapshot.users.len(),
+                snapshot.channels.len()
+            ),
+        );
+        Ok(())
+    }
+
+    /// Write a change through to storage. Callers write before they change
+    /// memory, so a failed write fails the command and changes nothing a
+    /// restart would not bring back.
+    fn persist(&self, op: impl FnOnce(&dyn Storage) -> Result<(), ChatErrors>) -> Result<(), ChatErrors> {
+        op(self.storage.as_ref()).inspect_err(|e| warn!("storage write failed: {}", e))
+    }
+
+    /// Apply `change` to the chan's settings, stored before the chan in memory.
+    fn change_chan(&self, chan_id: &String, change: impl Fn(&mut Channel)) -> Result<(), ChatErrors> {
+        let mut settings = self
+            .channels
+            .get(chan_id)
+            .ok_or_else(|| ChatErrors::ChannelNotFound(chan_id.clone()))?
+            .settings();
+        change(&mut settings);
+        self.persist(|s| s.put_channel(&settings))?;
+        if let Some(mut chan) = self.channels.get(chan_id) {
+            change(&mut chan);
+        }
+        Ok(())
+    }
+
+    /// Log the connection in as `name`, claiming the name on first use. Returns
+    /// the user's uid, which stays the same on every later login, so chans and
+    /// read markers carry over between connections, and the token issued when
+    /// the name is claimed. Later logins must present that token, admins the
+    /// admin key instead.
+    pub fn login(
+        &mut self,
+        conn_id: String,
+        name: String,
+        token: Option<String>,
+    ) -> Result<(String, Option<String>), ChatErrors> {
+        if self.sessions.contains_key(&conn_id) {
+            return Err(ChatErrors::InvalidCommand("already registered".to_string()));
+        }
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(ChatErrors::InvalidArgument(format!("invalid username: {}", name)));
+        }
+
+        let known = self.user_names.get(&name.to_lowercase()).cloned();
+        let token_hash = token.as_deref().map(hash_token);
+        let issued = if self.is_admin_name(&name) {
+            // whoever claimed the name first, only the admin key logs it in
+            if self.admin_key_hash.is_none() || self.admin_key_hash != token_hash {
+                return Err(ChatErrors::PermissionDenied(format!(
+                    "user: {} is an admin, log in with the admin key",
+                    name
+                )));
+            }
+            None
+        } else if let Some(uid) = known.as_ref() {
+            if self.users.get(uid).is_none_or(|user| Some(&user.token_hash) != token_hash.as_ref()) {
+                return Err(ChatErrors::PermissionDenied(format!(
+                    "user: {} is taken, log in with its token",
+                    name
+                )));
+            }
+            None
+        } else {
+            Some(gen_token())
+        };
+        let uid = known.clone().unwrap_or_else(gen_id);
+        if self.online.contains_key(&uid) {
+            return Err(ChatErrors::AlreadyLoggedIn(name));
+        }
+        if known.is_none() {
+            let user = UserInfo {
+                id: uid.clone(),
+                name: name.clone(),
+                token_hash: issued.as_deref().map(hash_token).unwrap_or_default(),
+                offered_chans: HashSet::new(),
+            };
+            info!("create user: {}", user);
+            // the personal chan first, so a stored user always has one
+            self.create_chan(uid.clone(), name.clone(), Some(uid.clone()))?;
+            self.persist(|s| s.put_user(&user))?;
+            self.user_names.insert(name.to_lowercase(), uid.clone());
+            self.users.insert(uid.clone(), user);
+            info!("user: {} created chan: {}", uid, uid);
+        }
+        self.sessions.insert(conn_id.clone(), uid.clone());
+        self.online.insert(uid.clone(), conn_id);
+
+        let name = self.users.get(&uid).map_or(name, |user| user.name.clone());
+        // the first line a client gets is its uid, which is also its personal chan
+        self.send_msg(true, name.clone(), uid.clone(), uid.clone());
+        if let Some(token) = issued.as_ref() {
+            self.notify_user(&uid, format!("{}: {}", TOKEN_RESP, token));
+        }
+
+        // default chans added since the last login are joined now, once: the
+        // ones joined before keep their read markers, the ones left stay left
+        let user = self.users.get(&uid);
+        let missing: Vec<String> = self
+            .default_chans
+            .iter()
+            .filter(|chan_id| user.is_none_or(|user| !user.offered_chans.contains(*chan_id)))
+            .filter(|chan_id| self.channels.contains_key(*chan_id))
+            .cloned()
+            .collect();
+        for chan_id in missing.iter() {
+            if self.is_user_sub(&uid, chan_id) {
+                continue;
+            }
+            self.persist(|s| s.put_member(&uid, chan_id))?;
+            if let Some(chan) = self.channels.get_mut(chan_id) {
+                chan.join(uid.clone());
+                let label = chan.label();
+
+                self.add_member(&uid, chan_id);
+                self.mark_read_latest(&uid, chan_id)?;
+                self.notify_user(&uid, format!("{}: {}", AUTO_JOIN_RESP, label));
+                self.replay_history(&uid, chan_id);
+            }
+        }
+        if !missing.is_empty()
+            && let Some(user) = self.users.get(&uid)
+        {
+            let mut user = user.clone();
+            user.offered_chans.extend(missing);
+            self.persist(|s| s.put_user(&user))?;
+            self.users.insert(uid.clone(), user);
+        }
+
+        self.send_unread(&uid);
+        self.deliver_offline(&uid)?;
+        info!("user: {} logged in as: {}", uid, name);
+        Ok((uid, issued))
+    }
+
+    /// The uid logged in on the connection, `None` before it registers.
+    pub fn session(&self, conn_id: &str) -> Option<&String> {
+        self.sessions.get(conn_id)
+    }
+
+    /// Register a connection, its writer drains the returned outbox.
+    pub fn connect(&mut self, conn_id: String) -> Outbox {
+        let (tx, rx) = mpsc::channel(self.conn_buffer);
+        let lag = Arc::new(Lag::default());
+        self.conns.insert(conn_id, Conn { tx, lag: lag.clone() });
+        Outbox { rx, lag }
+    }
+
+    /// The connection is gone, its user can log in again from another one.
+    pub fn disconnect(&mut self, conn_id: &str) {
+        self.conns.remove(conn_id);
+        let Some(uid) = self.sessions.remove(conn_id) else {
+            return;
+        };
+        self.online.remove(&uid);
+        for chan_id in self.user_chans.get(&uid).into_iter().flatten() {
+            if let Some(chan) = self.channels.get_mut(chan_id) {
+                chan.typing_at.remove(&uid);
+            }
+        }
+        info!("user: {} went offline", uid);
+    }
+
+    /// Send a line to one connection, whether or not it is logged in.
+    pub fn send_to_conn(&self, conn_id: &str, line: String) {
+        if let Some(conn) = self.conns.get(conn_id) {
+            self.deliver(conn_id, conn, Message::new(SERVER_UID.to_string(), conn_id.to_string(), line));
         }
     }
 
-    pub fn add_user(&mut self, name: String, uid: String) {
-        let user = UserInfo {
-            id: uid.clone(),
-            name: name.clone(),
+    /// Tell every connection the server is going away, before they are closed.
+    /// Unlike other lines the notice is never dropped: the returned future waits
+    /// for room in full outboxes, without holding the service, so bound it by
+    /// the shutdown deadline.
+    pub fn announce_shutdown(&self) -> impl Future<Output = ()> + Send + 'static {
+        let notices: Vec<_> = self
+            .conns
+            .iter()
+            .map(|(conn_id, conn)| {
+                let line = format!("{}: server is shutting down", SHUTDOWN_RESP);
+                let notice = Message::new(SERVER_UID.to_string(), conn_id.clone(), line);
+                let tx = conn.tx.clone();
+                // a closed outbox means the conn is already gone
+                async move {
+                    let _ = tx.send(notice).await;
+                }
+            })
+            .collect();
+        info!("announcing shutdown to {} conns", notices.len());
+        async move {
+            futures::future::join_all(notices).await;
+        }
+    }
+
+    pub fn flush_storage(&self) -> Result<(), ChatErrors> {
+        self.storage.flush()
+    }
+
+    fn check_msg_len(&self, msg: &str) -> Result<(), ChatErrors> {
+        if msg.len() > self.max_msg_len {
+            return Err(ChatErrors::MessageTooLong(self.max_msg_len));
+        }
+        Ok(())
+    }
+
+    /// Create a server owned chan that every user joins on register.
+    pub fn add_default_chan(&mut self, name: String, read_only: bool) -> Result<String, ChatErrors> {
+        // loaded from storage on a restart, keep its id so memberships still point at it
+        let chan_id = match self.chan_names.get(&name.trim_start_matches('#').to_lowercase()) {
+            Some(chan_id) => chan_id.clone(),
+            None => self.create_named_chan(SERVER_UID.to_string(), name)?,
         };
+        self.persist(|s| s.remove_member(SERVER_UID, &chan_id))?;
+        self.remove_member(SERVER_UID, &chan_id);
+        self.change_chan(&chan_id, |chan| chan.read_only = read_only)?;
+        self.default_chans.push(chan_id.clone());
 
-        info!("create user: {:?}", user);
+        info!("created default chan: {}", chan_id);
+        Ok(chan_id)
+    }
 
-        self.users.insert(uid.clone(), user.clone());
-        let chan_id = self.create_chan(uid.clone(), name.clone(), Some(uid.clone()));
-        info!("user: {} created chan: {}", uid.clone(), chan_id.clone());
+    /// Admin names only log in with the admin key, see `login`, so the name
+    /// stands for the key's holder.
+    pub fn is_admin(&self, uid: &String) -> bool {
+        self.users.get(uid).is_some_and(|user| self.is_admin_name(&user.name))
+    }
 
-        self.send_msg(true, name, chan_id.clone(), chan_id);
+    fn is_admin_name(&self, name: &str) -> bool {
+        self.admins.contains(&name.to_lowercase())
     }
 
     pub fn is_user_sub(&self, uid: &String, chan_id: &String) -> bool {
@@ -70,168 +643,2354 @@ impl ChatService {
         }
     }
 
+    /// Create a channel whose name is registered in the case-insensitive
+    /// `#name` namespace, so it can be addressed by name as well as by id.
+    pub fn create_named_chan(&mut self, uid: String, name: String) -> Result<String, ChatErrors> {
+        let name = name.trim_start_matches('#').to_string();
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(ChatErrors::InvalidChannelName(name));
+        }
+
+        let key = name.to_lowercase();
+        if self.chan_names.contains_key(&key) {
+            return Err(ChatErrors::ChannelNameTaken(name));
+        }
+
+        let chan_id = self.create_chan(uid, name, None)?;
+        self.chan_names.insert(key, chan_id.clone());
+        Ok(chan_id)
+    }
+
+    /// Turn `#name` into the channel id it refers to; anything else is taken as an id.
+    pub fn resolve_chan(&self, chan: String) -> Result<String, ChatErrors> {
+        match chan.strip_prefix('#') {
+            Some(name) => self
+                .chan_names
+                .get(&name.to_lowercase())
+                .cloned()
+                .ok_or(ChatErrors::ChannelNotFound(chan)),
+            None => Ok(chan),
+        }
+    }
+
     pub fn create_chan(
         &mut self,
         uid: String,
         name: String,
         pre_chan_id: Option<String>,
-    ) -> String {
-        let mut chan = Channel::new(name);
+    ) -> Result<String, ChatErrors> {
+        let mut chan = Channel::new(name, uid.clone());
         let mut not_send = false;
-        if pre_chan_id.is_some() {
+        if let Some(pre_chan_id) = pre_chan_id {
             not_send = true;
-            chan.id = pre_chan_id.unwrap();
+            chan.id = pre_chan_id;
         }
 
         let chan_id = chan.id.clone();
+        self.persist(|s| s.put_channel(&chan))?;
+        self.persist(|s| s.put_member(&uid, &chan_id))?;
         self.channels.insert(chan_id.clone(), chan);
+        self.add_member(&uid, &chan_id);
 
-        let mut set = HashSet::new();
-        set.insert(chan_id.clone());
+        if not_send {
+            return Ok(chan_id);
+        }
 
-        self.user_chans
-            .entry(uid.clone())
-            .and_modify(|chans| {
-                chans.insert(chan_id.clone());
-            })
-            .or_insert(set);
+        if let Some(user) = self.users.get(&uid) {
+            let label = self.channels.get(&chan_id).map(|chan| chan.label()).unwrap_or_default();
+            self.send_msg(true, user.name.clone(), chan_id.clone(), format!("{}: {}", CREATE_CHAN_RESP, label));
+        }
 
-        if not_send {
-            return chan_id;
+        Ok(chan_id)
+    }
+
+    pub fn join_chan(&mut self, uid: String, chan_id: String) -> Result<(), ChatErrors> {
+        // personal chans share their owner's uid and carry the owner's mentions
+        if self.users.contains_key(&chan_id) {
+            return Err(ChatErrors::PermissionDenied("personal chan can not be joined".to_string()));
         }
+        if !self.channels.contains_key(&chan_id) {
+            return Err(ChatErrors::ChannelNotFound(chan_id));
+        }
+
+        self.persist(|s| s.put_member(&uid, &chan_id))?;
+        let chan = self
+            .channels
+            .get_mut(&chan_id)
+            .ok_or_else(|| ChatErrors::ChannelNotFound(chan_id.clone()))?;
+        chan.join(uid.clone());
+        let label = chan.label();
+        self.add_member(&uid, &chan_id);
+        self.mark_read_latest(&uid, &chan_id)?;
 
         if let Some(user) = self.users.get(&uid) {
-            self.send_msg(true, user.name.clone(), chan_id.clone(), format!("{}: {}", CREATE_CHAN_RESP, chan_id));
+            self.send_msg(
+                true,
+                user.name.clone(),
+                chan_id.clone(),
+                format!("{}: {}", JOIN_RESP, label),
+            );
+        }
+        self.replay_history(&uid, &chan_id);
+        Ok(())
+    }
+
+    /// Leave a chan, the leaver is told through their personal channel as the
+    /// chan's own messages no longer reach them.
+    pub fn leave_chan(&mut self, uid: String, chan_id: String) -> Result<(), ChatErrors> {
+        if chan_id == uid {
+            return Err(ChatErrors::PermissionDenied(
+                "personal chan can not be left".to_string(),
+            ));
+        }
+        if !self.channels.contains_key(&chan_id) {
+            return Err(ChatErrors::ChannelNotFound(chan_id));
         }
 
-        chan_id
+        self.persist(|s| s.remove_member(&uid, &chan_id))?;
+        if let Some(chan) = self.channels.get_mut(&chan_id) {
+            chan.leave(uid.clone());
+            chan.read_markers.remove(&uid);
+        }
+        self.remove_member(&uid, &chan_id);
+        self.notify_user(&uid, format!("{}: {}", LEAVE_RESP, chan_id));
+
+        info!("user: {} leave chan: {}", uid, chan_id);
+        Ok(())
     }
 
-    pub fn join_chan(&mut self, uid: String, chan_id: String) {
-        match self.channels.get_mut(&chan_id) {
-            Some(chan) => {
-                chan.join(uid.clone());
+    /// Remove a channel for good and tell every member through their personal channel,
+    /// so clients can drop it from `joined_chans`.
+    pub fn delete_chan(&mut self, uid: String, chan_id: String) -> Result<(), ChatErrors> {
+        let chan = self
+            .channels
+            .get_mut(&chan_id)
+            .ok_or_else(|| ChatErrors::ChannelNotFound(chan_id.clone()))?;
+        if chan.owner != uid {
+            return Err(ChatErrors::PermissionDenied(format!(
+                "only the owner can delete chan: {}",
+                chan_id
+            )));
+        }
+        if chan_id == uid {
+            return Err(ChatErrors::PermissionDenied(
+                "personal chan can not be deleted".to_string(),
+            ));
+        }
 
-                let mut set = HashSet::new();
-                set.insert(chan_id.clone());
-
-                self.user_chans
-                    .entry(uid.clone())
-                    .and_modify(|chans| {
-                        chans.insert(chan_id.clone());
-                    })
-                    .or_insert(set);
-
-                if let Some(user) = self.users.get(&uid) {
-                    self.send_msg(
-                        true,
-                        user.name.clone(),
-                        chan_id.clone(),
-                        format!("{}: {}", JOIN_RESP, chan_id),
-                    );
-                }
+        self.persist(|s| s.remove_channel(&chan_id))?;
+        let members = self.chan_members(&chan_id);
+        if let Some(chan) = self.channels.remove(&chan_id) {
+            self.chan_names.remove(&chan.name.to_lowercase());
+        }
+        for uid in self.chan_users.remove(&chan_id).unwrap_or_default() {
+            if let Some(chans) = self.user_chans.get_mut(&uid) {
+                chans.remove(&chan_id);
             }
-            None => info!("chan {} not found", chan_id),
         }
+        self.search_index.get_mut(&chan_id).remove_chan(&chan_id);
+
+        for member in members {
+            self.notify_user(&member, format!("{}: {}", DELETE_CHAN_RESP, chan_id));
+        }
+
+        info!("user: {} deleted chan: {}", uid, chan_id);
+        Ok(())
     }
 
-    pub fn leave_chan(&mut self, uid: String, chan_id: String) {
-        match self.channels.get_mut(&chan_id) {
-            Some(chan) => {
-                chan.leave(uid.clone());
+    /// Archived channels stay readable but reject new messages.
+    pub fn archive_chan(&mut self, uid: String, chan_id: String) -> Result<(), ChatErrors> {
+        let chan = self
+            .channels
+            .get_mut(&chan_id)
+            .ok_or_else(|| ChatErrors::ChannelNotFound(chan_id.clone()))?;
+        if chan.owner != uid {
+            return Err(ChatErrors::PermissionDenied(format!(
+                "only the owner can archive chan: {}",
+                chan_id
+            )));
+        }
+        if chan_id == uid {
+            return Err(ChatErrors::PermissionDenied(
+                "personal chan can not be archived".to_string(),
+            ));
+        }
+
+        self.change_chan(&chan_id, |chan| chan.archived = true)?;
+        self.send_msg(
+            true,
+            uid.clone(),
+            chan_id.clone(),
+            format!("{}: {}", ARCHIVE_CHAN_RESP, chan_id),
+        );
 
-                match self.user_chans.get_mut(&uid) {
-                    Some(chans) => {
-                        chans.remove(&chan_id);
-                    }
-                    None => {}
-                }
+        info!("user: {} archived chan: {}", uid, chan_id);
+        Ok(())
+    }
 
-                if let Some(user) = self.users.get(&uid) {
-                    self.send_msg(
-                        true, 
-                        user.name.clone(),
-                        chan_id.clone(),
-                        format!("{}: {}", LEAVE_RESP, chan_id),
-                    );
-                }
+    /// Users subscribed to the channel, including its owner.
+    pub fn chan_members(&self, chan_id: &String) -> Vec<String> {
+        self.chan_users
+            .get(chan_id)
+            .map(|uids| uids.iter().cloned().collect())
+            .unwrap_or_default()
+    }
 
-                info!("user: {} leave chan: {}", uid, chan_id);
-            }
-            None => info!("chan {} not found", chan_id),
+    fn add_member(&mut self, uid: &str, chan_id: &str) {
+        self.user_chans
+            .entry(uid.to_string())
+            .or_default()
+            .insert(chan_id.to_string());
+        self.chan_users
+            .entry(chan_id.to_string())
+            .or_default()
+            .insert(uid.to_string());
+    }
+
+    fn remove_member(&mut self, uid: &str, chan_id: &str) {
+        if let Some(chans) = self.user_chans.get_mut(uid) {
+            chans.remove(chan_id);
+        }
+        if let Some(uids) = self.chan_users.get_mut(chan_id) {
+            uids.remove(uid);
         }
     }
 
-    pub fn send_msg(&self, is_cmd: bool, username: String, chan_id: String, msg: String) {
-        match self.channels.get(&chan_id) {
-            Some(_) => {
-                let msg = if is_cmd {
-                    msg
-                } else {
-                    format!("{}: {}", username, msg)
-                };
-
-                match self
-                    .tx
-                    .send(Message::new(username.clone(), chan_id.clone(), msg))
-                {
-                    Ok(v) => {
-                        info!("success send {} message", v);
-                    }
-                    Err(e) => warn!("failed to send msg to channel: {}, {}", chan_id, e),
+    /// Send a server response to a single user through the personal channel
+    /// created for them on registration.
+    pub fn notify_user(&self, uid: &str, msg: String) {
+        self.send_msg(true, uid.to_string(), uid.to_string(), msg);
+    }
+
+    /// Owner only: let another user, given by name, change the chan's modes.
+    pub fn add_moderator(&mut self, uid: String, chan_id: String, target: String) -> Result<(), ChatErrors> {
+        let target_uid = self
+            .user_names
+            .get(&target.to_lowercase())
+            .cloned()
+            .ok_or_else(|| ChatErrors::InvalidArgument(format!("user: {} not found", target)))?;
+        let chan = self
+            .channels
+            .get_mut(&chan_id)
+            .ok_or_else(|| ChatErrors::ChannelNotFound(chan_id.clone()))?;
+        if chan.owner != uid {
+            return Err(ChatErrors::PermissionDenied(format!(
+                "only the owner can add moderators to chan: {}",
+                chan_id
+            )));
+        }
+
+        self.change_chan(&chan_id, |chan| {
+            chan.moderators.insert(target_uid.clone());
+        })?;
+        self.send_chan_mode(&chan_id, format!("moderator {}", target));
+        Ok(())
+    }
+
+    /// Minimum interval between two messages of the same user, 0 turns it off.
+    pub fn set_slow_mode(&mut self, uid: String, chan_id: String, secs: u64) -> Result<(), ChatErrors> {
+        self.check_moderator(&uid, &chan_id)?;
+        self.change_chan(&chan_id, |chan| {
+            chan.slow_mode = (secs > 0).then(|| Duration::from_secs(secs));
+            chan.last_post.clear();
+        })?;
+
+        self.send_chan_mode(&chan_id, format!("slow_mode {}s", secs));
+        Ok(())
+    }
+
+    /// Chan wide cap of messages per second, 0 turns it off.
+    pub fn set_rate_limit(&mut self, uid: String, chan_id: String, per_sec: u32) -> Result<(), ChatErrors> {
+        self.check_moderator(&uid, &chan_id)?;
+        self.change_chan(&chan_id, |chan| {
+            chan.max_msgs_per_sec = (per_sec > 0).then_some(per_sec);
+            chan.recent_posts.clear();
+        })?;
+
+        self.send_chan_mode(&chan_id, format!("rate_limit {}/s", per_sec));
+        Ok(())
+    }
+
+    /// Announcement style chan: only owner and moderators can post.
+    pub fn set_read_only(&mut self, uid: String, chan_id: String, read_only: bool) -> Result<(), ChatErrors> {
+        self.check_moderator(&uid, &chan_id)?;
+        self.change_chan(&chan_id, |chan| chan.read_only = read_only)?;
+
+        self.send_chan_mode(&chan_id, format!("read_only {}", read_only));
+        Ok(())
+    }
+
+    /// How long the chan's stored history is kept, applied by `prune_history`.
+    pub fn set_retention(&mut self, uid: String, chan_id: String, retention: Retention) -> Result<(), ChatErrors> {
+        self.check_moderator(&uid, &chan_id)?;
+        self.change_chan(&chan_id, |chan| chan.retention = retention)?;
+
+        self.send_chan_mode(&chan_id, format!("retention {}", retention));
+        Ok(())
+    }
+
+    /// Drop stored and in memory messages that fall outside their chan's
+    /// retention, and typing events too old to hold back the next one.
+    pub fn prune_history(&mut self) {
+        let now = Instant::now();
+        for chan in self.channels.values_mut() {
+            chan.typing_at.retain(|_, at| now.duration_since(*at) < TYPING_INTERVAL);
+        }
+
+        let policies: Vec<(String, Retention)> = self
+            .channels
+            .values_mut()
+            .filter(|chan| chan.retention != Retention::Forever)
+            .map(|chan| (chan.id.clone(), chan.retention))
+            .collect();
+
+        for (chan_id, retention) in policies {
+            let removed = match self.storage.prune_messages(&chan_id, retention) {
+                Ok(removed) => removed,
+                Err(e) => {
+                    warn!("failed to prune chan: {}, {}", chan_id, e);
+                    continue;
+                }
+            };
+            if removed.is_empty() {
+                continue;
+            }
+
+            if let Some(chan) = self.channels.get_mut(&chan_id) {
+                for msg_id in removed.iter() {
+                    chan.messages.remove(msg_id);
                 }
-                info!("user: {} send msg to: {}", username, chan_id);
             }
-            None => {
-                info!("chan {} not found", chan_id);
+            let index = self.search_index.get_mut(&chan_id);
+            for msg_id in removed.iter() {
+                index.remove(msg_id);
             }
+            info!("pruned {} messages from chan: {} ({})", removed.len(), chan_id, retention);
         }
     }
-}
 
-impl Channel {
-    pub fn new(name: String) -> Self {
-        Self {
-            id: gen_id(),
-            name,
-            online_users: HashMap::new(),
+    fn check_moderator(&self, uid: &String, chan_id: &String) -> Result<(), ChatErrors> {
+        let is_admin = self.is_admin(uid);
+        let chan = self
+            .channels
+            .get(chan_id)
+            .ok_or_else(|| ChatErrors::ChannelNotFound(chan_id.clone()))?;
+        if !is_admin && !chan.is_moderator(uid) {
+            return Err(ChatErrors::PermissionDenied(format!(
+                "only owner and moderators can change chan: {}",
+                chan_id
+            )));
         }
+
+        Ok(())
     }
 
-    pub fn join(&mut self, user_id: String) {
-        self.online_users.insert(user_id, ());
+    fn send_chan_mode(&self, chan_id: &String, mode: String) {
+        self.send_msg(
+            true,
+            SERVER_UID.to_string(),
+            chan_id.clone(),
+            format!("{}: {} {}", CHAN_MODE_RESP, chan_id, mode),
+        );
     }
 
-    pub fn leave(&mut self, user_id: String) {
-        self.online_users.remove(&user_id);
+    /// Entry point for messages posted by users, as opposed to server responses.
+    /// Only needs the service shared: the chan stays locked until the message is
+    /// fanned out, so members get a chan's messages in history order.
+    pub fn post_msg(
+        &self,
+        uid: String,
+        chan_id: String,
+        msg: String,
+        parent_id: Option<String>,
+    ) -> Result<(), ChatErrors> {
+        self.check_msg_len(&msg)?;
+        if !self.channels.contains_key(&chan_id) {
+            return Err(ChatErrors::ChannelNotFound(chan_id));
+        }
+        // replies to a reply land in the root thread, looked up before the chan is locked
+        let parent_id = match parent_id {
+            Some(parent_id) => match self.find_msg(&chan_id, &parent_id)? {
+                Some(parent) if !parent.deleted => Some(parent.parent_id.unwrap_or(parent_id)),
+                _ => return Err(ChatErrors::MessageNotFound(parent_id)),
+            },
+            None => None,
+        };
+
+        let is_admin = self.is_admin(&uid);
+        let mut chan = self
+            .channels
+            .get(&chan_id)
+            .ok_or_else(|| ChatErrors::ChannelNotFound(chan_id.clone()))?;
+        if chan.archived {
+            return Err(ChatErrors::ChannelArchived(chan_id));
+        }
+        // anyone can write to a user's personal chan, other chans take posts from members only
+        if chan.id != chan.owner && !self.is_user_sub(&uid, &chan_id) {
+            return Err(ChatErrors::PermissionDenied(format!("you have not joined chan: {}", chan_id)));
+        }
+        if chan.read_only && !is_admin && !chan.is_moderator(&uid) {
+            return Err(ChatErrors::PermissionDenied(format!(
+                "chan: {} is read only",
+                chan_id
+            )));
+        }
+        let now = Instant::now();
+        chan.check_rate(&uid, now)?;
+
+        let username = self.users.get(&uid).map_or(uid.clone(), |u| u.name.clone());
+        let mut msg = Message::from_user(uid, username, chan_id.clone(), msg);
+        msg.parent_id = parent_id.clone();
+        msg.chan_name = chan.name.clone();
+
+        // everything is stored before memory changes, the post itself last: it
+        // is only acked once stored
+        self.queue_offline(&msg)?;
+        self.persist(|s| s.put_read_marker(&msg.sender_id, &chan_id, &msg.id))?;
+        self.persist(|s| s.put_message(&msg))?;
+        chan.record_post(&msg.sender_id, now);
+        // the root counts the reply once it is stored, the reply stands even if
+        // the count can not be
+        let reply_count = parent_id.as_ref().and_then(|parent_id| {
+            let counted = self.change_msg(&mut chan, parent_id, |parent| {
+                parent.reply_count += 1;
+                Ok(parent.reply_count)
+            });
+            counted
+                .inspect_err(|e| warn!("failed to count reply: {} to msg: {}, {}", msg.id, parent_id, e))
+                .ok()
+        });
+        chan.read_markers.insert(msg.sender_id.clone(), msg.id.clone());
+        chan.push_message(msg.clone(), self.history_size);
+        self.search_index.get(&chan_id).add(&msg);
+
+        self.notify_mentions(&msg);
+        self.broadcast(msg);
+        if let (Some(parent_id), Some(count)) = (parent_id, reply_count) {
+            self.send_msg(
+                true,
+                SERVER_UID.to_string(),
+                chan_id,
+                format!("{}: {} {}", REPLIES_RESP, parent_id, count),
+            );
+        }
+        Ok(())
     }
-}
 
-impl Message {
-    pub fn new(username: String, chan_id: String, c: String) -> Self {
-        Self {
-            chan_id,
-            sender: username,
-            content: c,
-            send_time: Utc::now(),
+    /// Tell every `@username` in the message through their personal channel,
+    /// whether or not they are in the message's channel.
+    fn notify_mentions(&self, msg: &Message) {
+        for uid in self.mentioned(msg) {
+            info!("user: {} mentioned in chan: {}", uid, msg.chan_id);
+            self.notify_user(uid, format!("{}: {} {}", MENTION_RESP, msg.chan_id, msg));
         }
     }
 
-    pub fn to_string(&self) -> String {
-        format!("{}", self.content)
+    /// Uids of the users the message mentions, other than its sender.
+    fn mentioned(&self, msg: &Message) -> Vec<&String> {
+        parse_mentions(&msg.content)
+            .iter()
+            .filter_map(|name| self.user_names.get(&name.to_lowercase()))
+            .filter(|uid| **uid != msg.sender_id)
+            .collect()
     }
-}
 
-impl UserInfo {
-    pub fn to_string(&self) -> String {
-        format!("{}: {}", self.id, self.name)
+    /// Queue the message for recipients that are offline: the owner of a personal
+    /// chan it was sent to, users it mentions, and with `queue_all_offline` every
+    /// other chan member.
+    fn queue_offline(&self, msg: &Message) -> Result<(), ChatErrors> {
+        let mut recipients: Vec<(String, String)> = Vec::new(); // (uid, queued line)
+        // personal chans share their owner's uid
+        if self.users.contains_key(&msg.chan_id) {
+            recipients.push((msg.chan_id.clone(), format!("{}: {} {}", OFFLINE_RESP, msg.chan_id, msg)));
+        }
+        for uid in self.mentioned(msg) {
+            recipients.push((uid.clone(), format!("{}: {} @ {}", OFFLINE_RESP, msg.chan_id, msg)));
+        }
+        if self.queue_all_offline {
+            for uid in self.chan_members(&msg.chan_id) {
+                recipients.push((uid, format!("{}: {} {}", OFFLINE_RESP, msg.chan_id, msg)));
+            }
+        }
+
+        // the first reason to queue wins, `@` for a mention
+        let mut queued = HashSet::new();
+        recipients.retain(|(uid, _)| *uid != msg.sender_id && !self.online.contains_key(uid) && queued.insert(uid.clone()));
+
+        if recipients.is_empty() {
+            return Ok(());
+        }
+        for (uid, line) in recipients {
+            let mut queues = self.offline_queues.get(&uid);
+            self.persist(|s| s.push_offline(&uid, &line, self.offline_queue_cap))?;
+            queues.entry(uid).or_default().push(line, self.offline_queue_cap);
+        }
+        Ok(())
     }
-}
 
-fn gen_id() -> String {
-    let alphabet: [char; 16] = [
-        '1', '2', '3', '4', '5', '6', '7', '8', '9', '0', 'a', 'b', 'c', 'd', 'e', 'f',
-    ];
+    /// Hand a returning user everything queued for them while they were away,
+    /// oldest first, after telling them how much did not fit.
+    fn deliver_offline(&self, uid: &str) -> Result<(), ChatErrors> {
+        let mut queues = self.offline_queues.get(uid);
+        if !queues.contains_key(uid) {
+            return Ok(());
+        }
+        self.persist(|s| s.remove_offline(uid))?;
+        let Some(queue) = queues.remove(uid) else {
+            return Ok(());
+        };
+        drop(queues);
 
-    nanoid!(10, &alphabet) //=> "4f90d13a42"
+        if queue.dropped > 0 {
+            self.notify_user(uid, format!("{}: {}", OFFLINE_OVERFLOW_RESP, queue.dropped));
+        }
+        for line in queue.msgs {
+            self.notify_user(uid, line);
+        }
+        info!("delivered offline messages to user: {}", uid);
+        Ok(())
+    }
+
+    /// Send the chan's stored history, oldest first, to a user that just joined it.
+    pub fn replay_history(&self, uid: &str, chan_id: &String) {
+        let Some(chan) = self.channels.get(chan_id) else {
+            return;
+        };
+
+        for msg in chan.messages.values().filter(|msg| !msg.deleted) {
+            self.notify_user(uid, format!("{}: {}", REPLAY_RESP, msg));
+        }
+    }
+
+    /// Move the user's read marker to `msg_id`, or to the newest message when it is `None`.
+    pub fn mark_read(&self, uid: String, chan_id: String, msg_id: Option<String>) -> Result<(), ChatErrors> {
+        if !self.channels.contains_key(&chan_id) {
+            return Err(ChatErrors::ChannelNotFound(chan_id));
+        }
+        if !self.is_user_sub(&uid, &chan_id) {
+            return Err(ChatErrors::PermissionDenied(format!("you have not joined chan: {}", chan_id)));
+        }
+
+        match msg_id {
+            Some(msg_id) => {
+                if self.find_msg(&chan_id, &msg_id)?.is_none() {
+                    return Err(ChatErrors::MessageNotFound(msg_id));
+                }
+                self.persist(|s| s.put_read_marker(&uid, &chan_id, &msg_id))?;
+                if let Some(mut chan) = self.channels.get(&chan_id) {
+                    chan.read_markers.insert(uid.clone(), msg_id);
+                }
+            }
+            None => self.mark_read_latest(&uid, &chan_id)?,
+        }
+
+        let count = self.unread_count(&uid, &chan_id);
+        self.send_unread_count(&uid, &chan_id, count);
+        Ok(())
+    }
+
+    fn mark_read_latest(&self, uid: &str, chan_id: &String) -> Result<(), ChatErrors> {
+        let Some(mut chan) = self.channels.get(chan_id) else {
+            return Ok(());
+        };
+        if let Some(last) = chan.messages.keys().next_back().cloned() {
+            self.persist(|s| s.put_read_marker(uid, chan_id, &last))?;
+            chan.read_markers.insert(uid.to_string(), last);
+        }
+        Ok(())
+    }
+
+    /// Messages from other users after the user's read marker, counted in
+    /// storage once messages after it were pushed out of the history ring.
+    pub fn unread_count(&self, uid: &String, chan_id: &String) -> usize {
+        let Some(chan) = self.channels.get(chan_id) else {
+            return 0;
+        };
+
+        let marker = chan.read_markers.get(uid);
+        let in_ring = chan.messages.len() < self.history_size
+            || chan.messages.keys().next().is_some_and(|oldest| marker.is_some_and(|marker| oldest <= marker));
+        if !in_ring {
+            match self.storage.count_unread(chan_id, marker.map(String::as_str), uid) {
+                Ok(count) => return count,
+                Err(e) => warn!("failed to count unread msgs of chan: {}, {}", chan_id, e),
+            }
+        }
+        let unread = match marker {
+            Some(marker) => chan
+                .messages
+                .range::<String, _>((std::ops::Bound::Excluded(marker), std::ops::Bound::Unbounded)),
+            None => chan.messages.range::<String, _>(..),
+        };
+        unread
+            .filter(|(_, msg)| !msg.deleted && msg.sender_id != *uid)
+            .count()
+    }
+
+    /// Unread counts for every chan the user joined, one line per chan.
+    pub fn send_unread(&self, uid: &String) {
+        let Some(chans) = self.user_chans.get(uid) else {
+            return;
+        };
+
+        for chan_id in chans.iter().filter(|chan_id| *chan_id != uid) {
+            self.send_unread_count(uid, chan_id, self.unread_count(uid, chan_id));
+        }
+    }
+
+    fn send_unread_count(&self, uid: &str, chan_id: &String, count: usize) {
+        if let Some(chan) = self.channels.get(chan_id) {
+            self.notify_user(uid, format!("{}: {} {}", UNREAD_RESP, chan.label(), count));
+        }
+    }
+
+    /// Fan out an ephemeral "is typing" event to the other members, never
+    /// stored, dropping events that come faster than `TYPING_INTERVAL`.
+    pub fn typing(&self, uid: String, chan_id: String) -> Result<(), ChatErrors> {
+        if !self.channels.contains_key(&chan_id) {
+            return Err(ChatErrors::ChannelNotFound(chan_id));
+        }
+        if !self.is_user_sub(&uid, &chan_id) {
+            return Err(ChatErrors::PermissionDenied(format!("you have not joined chan: {}", chan_id)));
+        }
+
+        let now = Instant::now();
+        if let Some(mut chan) = self.channels.get(&chan_id) {
+            // stale entries are dropped by `prune_history` and on disconnect
+            if chan.typing_at.get(&uid).is_some_and(|at| now.duration_since(*at) < TYPING_INTERVAL) {
+                return Ok(());
+            }
+            chan.typing_at.insert(uid.clone(), now);
+        }
+
+        let username = self.users.get(&uid).map_or(uid.clone(), |u| u.name.clone());
+        let line = format!("{}: {} {}", TYPING_RESP, chan_id, username);
+        self.broadcast_except(Message::new(uid.clone(), chan_id, line), Some(&uid));
+        Ok(())
+    }
+
+    /// Add (`add = true`) or remove the user's reaction and broadcast the new counts.
+    pub fn react(
+        &self,
+        uid: String,
+        chan_id: String,
+        msg_id: String,
+        reaction: String,
+        add: bool,
+    ) -> Result<(), ChatErrors> {
+        if reaction.is_empty()
+            || reaction.chars().count() > MAX_REACTION_LEN
+            || reaction.contains(char::is_whitespace)
+        {
+            return Err(ChatErrors::InvalidReaction(reaction));
+        }
+        if !self.is_user_sub(&uid, &chan_id) {
+            return Err(ChatErrors::PermissionDenied(format!("you have not joined chan: {}", chan_id)));
+        }
+
+        let mut chan = self
+            .channels
+            .get(&chan_id)
+            .ok_or_else(|| ChatErrors::ChannelNotFound(chan_id.clone()))?;
+        if chan.archived {
+            return Err(ChatErrors::ChannelArchived(chan_id));
+        }
+
+        let summary = self.change_msg(&mut chan, &msg_id, |msg| {
+            if msg.deleted {
+                return Err(ChatErrors::MessageNotFound(msg_id.clone()));
+            }
+            if add {
+                msg.reactions.entry(reaction).or_default().insert(uid.clone());
+            } else if let Some(users) = msg.reactions.get_mut(&reaction) {
+                users.remove(&uid);
+                if users.is_empty() {
+                    msg.reactions.remove(&reaction);
+                }
+            }
+            Ok(msg.reaction_summary())
+        })?;
+
+        let resp = format!("{}: {} {}", REACTIONS_RESP, msg_id, summary);
+        self.send_msg(true, uid, chan_id, resp);
+        Ok(())
+    }
+
+    /// Page back through the chan's stored history, beyond what is kept in memory.
+    /// Ends with `$$history_end: <chan_id> <oldest msg id> <count>`, the oldest id
+    /// being the `before` of the next page.
+    pub fn send_history(&self, uid: &str, chan_id: String, before: Option<String>, limit: usize) -> Result<(), ChatErrors> {
+        if !self.channels.contains_key(&chan_id) {
+            return Err(ChatErrors::ChannelNotFound(chan_id));
+        }
+        if !self.is_user_sub(&uid.to_string(), &chan_id) {
+            return Err(ChatErrors::PermissionDenied(format!("you have not joined chan: {}", chan_id)));
+        }
+
+        let page = self
+            .storage
+            .messages_before(&chan_id, before.as_deref(), limit.min(MAX_HISTORY_PAGE))?;
+        for msg in page.iter() {
+            self.notify_user(uid, history_line(&chan_id, msg));
+        }
+
+        let oldest = page.first().map_or("-", |msg| msg.id.as_str());
+        self.notify_user(uid, format!("{}: {} {} {}", HISTORY_END_RESP, chan_id, oldest, page.len()));
+        Ok(())
+    }
+
+    /// History lines with the stored messages a lagging reader missed, each
+    /// one it was not sent. Written by the connection's writer right after the
+    /// `$$lagged` notice, not through the full outbox.
+    pub fn resync(&self, missed: &Missed) -> Vec<String> {
+        let mut lines = Vec::new();
+        for (chan_id, ids) in missed.msgs.iter() {
+            for id in ids {
+                match self.storage.get_message(id) {
+                    Ok(Some(msg)) => lines.push(history_line(chan_id, &msg)),
+                    Ok(None) => info!("missed msg: {} no longer stored", id),
+                    Err(e) => warn!("failed to resync msg: {}, {}", id, e),
+                }
+            }
+        }
+        lines
+    }
+
+    /// Every stored message of the chan, oldest first. Walks the whole history,
+    /// for the offline CLI export.
+    pub fn chan_history(&self, chan_id: &str) -> Result<Vec<Message>, ChatErrors> {
+        let mut pages = Vec::new();
+        let mut before: Option<String> = None;
+        loop {
+            let page = self
+                .storage
+                .messages_before(chan_id, before.as_deref(), MAX_HISTORY_PAGE)?;
+            let Some(oldest) = page.first() else {
+                break;
+            };
+            before = Some(oldest.id.clone());
+            let done = page.len() < MAX_HISTORY_PAGE;
+            pages.push(page);
+            if done {
+                break;
+            }
+        }
+
+        Ok(pages.into_iter().rev().flatten().collect())
+    }
+
+    /// Send the chan's transcript to the requester, one `$$export` line per output
+    /// line, ending with `$$export_end: <chan_id> <format> <msgs> <total msgs>`.
+    /// Only the last `MAX_EXPORT_MSGS` messages are sent.
+    pub fn export_chan(&self, uid: String, chan_id: String, format: ExportFormat) -> Result<(), ChatErrors> {
+        let chan_name = self
+            .channels
+            .get(&chan_id)
+            .map(|chan| chan.name.clone())
+            .ok_or_else(|| ChatErrors::ChannelNotFound(chan_id.clone()))?;
+        if !self.is_user_sub(&uid, &chan_id) {
+            return Err(ChatErrors::PermissionDenied(format!("you have not joined chan: {}", chan_id)));
+        }
+
+        let sent = self.storage.messages_before(&chan_id, None, MAX_EXPORT_MSGS)?;
+        let total = self.storage.count_messages(&chan_id)?;
+        for line in export::render(&chan_name, &sent, format) {
+            self.notify_user(&uid, format!("{}: {}", EXPORT_RESP, line));
+        }
+
+        self.notify_user(
+            &uid,
+            format!("{}: {} {} {} {}", EXPORT_END_RESP, chan_id, format, sent.len(), total),
+        );
+        Ok(())
+    }
+
+    /// Keyword search over the history of the chans the requester belongs to,
+    /// newest first, ending with `$$search_end: <count>`.
+    pub fn search(&self, uid: String, query: SearchQuery) -> Result<(), ChatErrors> {
+        if tokenize(&query.terms).is_empty() {
+            return Err(ChatErrors::InvalidArgument("search need at least one keyword".to_string()));
+        }
+
+        let mut chans = self.user_chans.get(&uid).cloned().unwrap_or_default();
+        if let Some(chan) = query.chan.clone() {
+            let chan_id = self.resolve_chan(chan)?;
+            if !chans.contains(&chan_id) {
+                return Err(ChatErrors::PermissionDenied(format!("you have not joined chan: {}", chan_id)));
+            }
+            chans = HashSet::from([chan_id]);
+        }
+
+        // the newest of each shard's newest, ids sort by time
+        let wanted = MAX_SEARCH_RESULTS + SEARCH_OVERFETCH;
+        let mut msg_ids: Vec<String> = Vec::new();
+        for index in self.search_index.iter() {
+            msg_ids.extend(index.search(&query, &chans).take(wanted).cloned());
+        }
+        msg_ids.sort_unstable_by(|a, b| b.cmp(a));
+        msg_ids.truncate(wanted);
+
+        let mut count = 0;
+        for msg_id in msg_ids {
+            // a tombstone can push a still indexed message out of a bounded store
+            let Some(msg) = self.storage.get_message(&msg_id)? else {
+                continue;
+            };
+            self.notify_user(
+                &uid,
+                format!("{}: {} {}", SEARCH_RESP, msg.send_time.format("%Y-%m-%d %H:%M:%S"), msg),
+            );
+            count += 1;
+            if count == MAX_SEARCH_RESULTS {
+                break;
+            }
+        }
+
+        self.notify_user(&uid, format!("{}: {}", SEARCH_END_RESP, count));
+        Ok(())
+    }
+
+    /// Send the thread root and its replies, oldest first, to the requester only.
+    pub fn get_thread(&self, uid: String, chan_id: String, parent_id: String) -> Result<(), ChatErrors> {
+        if !self.channels.contains_key(&chan_id) {
+            return Err(ChatErrors::ChannelNotFound(chan_id));
+        }
+        if !self.is_user_sub(&uid, &chan_id) {
+            return Err(ChatErrors::PermissionDenied(format!("you have not joined chan: {}", chan_id)));
+        }
+        let parent = self
+            .find_msg(&chan_id, &parent_id)?
+            .ok_or_else(|| ChatErrors::MessageNotFound(parent_id.clone()))?;
+
+        // replies pushed out of the history ring are only in storage
+        let mut replies: BTreeMap<String, Message> = self
+            .storage
+            .thread_replies(&chan_id, &parent_id)?
+            .into_iter()
+            .map(|msg| (msg.id.clone(), msg))
+            .collect();
+        if let Some(chan) = self.channels.get(&chan_id) {
+            for reply in chan
+                .messages
+                .values()
+                .filter(|msg| msg.parent_id.as_ref() == Some(&parent_id))
+            {
+                replies.insert(reply.id.clone(), reply.clone());
+            }
+        }
+
+        self.notify_user(&uid, format!("{}: {} {}", THREAD_RESP, parent_id, parent));
+        for reply in replies.values() {
+            self.notify_user(&uid, format!("{}: {}", THREAD_MSG_RESP, reply));
+        }
+        Ok(())
+    }
+
+    /// Only the author or a moderator can edit a message.
+    pub fn edit_msg(&self, uid: String, chan_id: String, msg_id: String, content: String) -> Result<(), ChatErrors> {
+        self.check_msg_len(&content)?;
+        let edited = self.change_authored_msg(&uid, &chan_id, &msg_id, |msg| {
+            msg.content = content;
+            msg.edited = true;
+            msg.clone()
+        })?;
+        self.search_index.get(&chan_id).add(&edited);
+
+        let resp = format!("{}: {}", EDIT_MSG_RESP, edited);
+        self.send_msg(true, uid, chan_id, resp);
+        Ok(())
+    }
+
+    /// Deleted messages are kept as tombstones so ids stay resolvable.
+    pub fn delete_msg(&self, uid: String, chan_id: String, msg_id: String) -> Result<(), ChatErrors> {
+        self.change_authored_msg(&uid, &chan_id, &msg_id, |msg| {
+            msg.content.clear();
+            msg.deleted = true;
+        })?;
+        self.search_index.get(&chan_id).remove(&msg_id);
+
+        self.send_msg(true, uid, chan_id.clone(), format!("{}: {} {}", DELETE_MSG_RESP, chan_id, msg_id));
+        Ok(())
+    }
+
+    /// Apply `change` to a message the user wrote, or any message of a chan they moderate.
+    fn change_authored_msg<T>(
+        &self,
+        uid: &String,
+        chan_id: &String,
+        msg_id: &String,
+        change: impl FnOnce(&mut Message) -> T,
+    ) -> Result<T, ChatErrors> {
+        let is_admin = self.is_admin(uid);
+        let mut chan = self
+            .channels
+            .get(chan_id)
+            .ok_or_else(|| ChatErrors::ChannelNotFound(chan_id.clone()))?;
+        if chan.archived {
+            return Err(ChatErrors::ChannelArchived(chan_id.clone()));
+        }
+
+        let is_moderator = is_admin || chan.is_moderator(uid);
+        self.change_msg(&mut chan, msg_id, |msg| {
+            if msg.deleted {
+                return Err(ChatErrors::MessageNotFound(msg_id.clone()));
+            }
+            if msg.sender_id != *uid && !is_moderator {
+                return Err(ChatErrors::PermissionDenied(format!(
+                    "only the author or moderators can change msg: {}",
+                    msg_id
+                )));
+            }
+            Ok(change(msg))
+        })
+    }
+
+    /// A message of the chan, from its history ring or, once pushed out of
+    /// it, from storage. Locks the chan, so never call it holding its guard.
+    fn find_msg(&self, chan_id: &String, msg_id: &String) -> Result<Option<Message>, ChatErrors> {
+        let in_ring = self
+            .channels
+            .get(chan_id)
+            .and_then(|chan| chan.messages.get(msg_id).cloned());
+        if in_ring.is_some() {
+            return Ok(in_ring);
+        }
+        Ok(self.storage.get_message(msg_id)?.filter(|msg| msg.chan_id == *chan_id))
+    }
+
+    /// Apply `change` to a message of the locked chan, wherever `find_msg` would
+    /// find it. The changed copy is stored before it replaces the one in the
+    /// history ring, nothing changes when `change` or the write fails.
+    fn change_msg<T>(
+        &self,
+        chan: &mut Channel,
+        msg_id: &String,
+        change: impl FnOnce(&mut Message) -> Result<T, ChatErrors>,
+    ) -> Result<T, ChatErrors> {
+        let mut msg = match chan.messages.get(msg_id) {
+            Some(msg) => msg.clone(),
+            None => self
+                .storage
+                .get_message(msg_id)?
+                .filter(|msg| msg.chan_id == chan.id)
+                .ok_or_else(|| ChatErrors::MessageNotFound(msg_id.clone()))?,
+        };
+        let res = change(&mut msg)?;
+        self.persist(|s| s.put_message(&msg))?;
+        if let Some(in_ring) = chan.messages.get_mut(msg_id) {
+            *in_ring = msg;
+        }
+        Ok(res)
+    }
+
+    /// Hand the message to the outbox of every connected chan member.
+    fn broadcast(&self, msg: Message) {
+        self.broadcast_except(msg, None)
+    }
+
+    /// `broadcast`, skipping the `except` member.
+    fn broadcast_except(&self, msg: Message, except: Option<&String>) {
+        let Some(uids) = self.chan_users.get(&msg.chan_id) else {
+            return;
+        };
+
+        let mut sent = 0;
+        for (conn_id, conn) in uids
+            .iter()
+            .filter(|uid| Some(*uid) != except)
+            .filter_map(|uid| self.online.get(uid))
+            .filter_map(|conn_id| Some((conn_id, self.conns.get(conn_id)?)))
+        {
+            if self.deliver(conn_id, conn, msg.clone()) {
+                sent += 1;
+            }
+        }
+        info!("user: {} send msg to: {}, {} receivers", msg.sender, msg.chan_id, sent);
+    }
+
+    /// Queue the message in the conn's outbox, whether it got there.
+    fn deliver(&self, conn_id: &str, conn: &Conn, msg: Message) -> bool {
+        match conn.tx.try_send(msg) {
+            Ok(()) => true,
+            Err(TrySendError::Full(msg)) => {
+                self.lagged(conn_id, conn, &msg);
+                false
+            }
+            Err(TrySendError::Closed(_)) => false, // writer gone, cleaned up on disconnect
+        }
+    }
+
+    /// The reader's outbox is full: drop the message and count it, so the
+    /// writer can tell the reader once it catches up, or kick the connection
+    /// once it missed more than `max_lag`.
+    fn lagged(&self, conn_id: &str, conn: &Conn, msg: &Message) {
+        self.conn_metrics.dropped.fetch_add(1, Ordering::Relaxed);
+        let missed = conn.lag.miss(msg);
+        if missed > self.max_lag && !conn.lag.kick.is_cancelled() {
+            warn!("conn: {} missed {} messages, disconnecting slow reader", conn_id, missed);
+            self.conn_metrics.slow_disconnects.fetch_add(1, Ordering::Relaxed);
+            conn.lag.kick.cancel();
+        }
+    }
+
+    pub fn send_msg(&self, is_cmd: bool, username: String, chan_id: String, msg: String) {
+        // no chan lock, callers may hold the chan's guard
+        if !self.channels.contains_key(&chan_id) {
+            info!("chan {} not found", chan_id);
+            return;
+        }
+
+        let msg = if is_cmd {
+            msg
+        } else {
+            format!("{}: {}", username, msg)
+        };
+        self.broadcast(Message::new(username, chan_id, msg));
+    }
+}
+
+/// `$$history: <chan_id> <send time> <msg>`
+fn history_line(chan_id: &str, msg: &Message) -> String {
+    format!(
+        "{}: {} {} {}",
+        HISTORY_RESP,
+        chan_id,
+        msg.send_time.format("%Y-%m-%d %H:%M:%S"),
+        msg
+    )
+}
+
+/// How much of a chan's history is stored.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Retention {
+    #[default]
+    Forever,
+    Days(u32),
+    LastN(usize),
+}
+
+impl Retention {
+    /// Id of the oldest message a `Days` policy keeps at `now`, message ids
+    /// being unix millis in the high bits (see `gen_msg_id`).
+    pub fn cutoff_id(&self, now: chrono::DateTime<Utc>) -> Option<String> {
+        match self {
+            Retention::Days(days) => {
+                let cutoff = now - chrono::Duration::days(*days as i64);
+                Some(format!("{:016x}", (cutoff.timestamp_millis().max(0) as u64) << 16))
+            }
+            _ => None,
+        }
+    }
+}
+
+// forever, days=30 or last=1000
+impl fmt::Display for Retention {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Retention::Forever => write!(f, "forever"),
+            Retention::Days(days) => write!(f, "days={}", days),
+            Retention::LastN(n) => write!(f, "last={}", n),
+        }
+    }
+}
+
+impl std::str::FromStr for Retention {
+    type Err = ChatErrors;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ChatErrors::InvalidArgument(format!("invalid retention: {}, expect forever, days=N or last=N", s));
+        match s.split_once('=') {
+            None if s == "forever" => Ok(Retention::Forever),
+            Some(("days", n)) => n.parse().ok().filter(|n| *n > 0).map(Retention::Days).ok_or_else(invalid),
+            Some(("last", n)) => n.parse().ok().filter(|n| *n > 0).map(Retention::LastN).ok_or_else(invalid),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl Channel {
+    pub fn new(name: String, owner: String) -> Self {
+        Self {
+            id: gen_id(),
+            name,
+            owner,
+            archived: false,
+            read_only: false,
+            moderators: HashSet::new(),
+            slow_mode: None,
+            max_msgs_per_sec: None,
+            retention: Retention::Forever,
+            last_post: HashMap::new(),
+            recent_posts: VecDeque::new(),
+            messages: BTreeMap::new(),
+            online_users: HashMap::new(),
+            read_markers: HashMap::new(),
+            typing_at: HashMap::new(),
+        }
+    }
+
+    /// Copy of the persisted settings, without history and runtime state.
+    pub fn settings(&self) -> Channel {
+        Channel {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            owner: self.owner.clone(),
+            archived: self.archived,
+            read_only: self.read_only,
+            moderators: self.moderators.clone(),
+            slow_mode: self.slow_mode,
+            max_msgs_per_sec: self.max_msgs_per_sec,
+            retention: self.retention,
+            ..Channel::new(String::new(), String::new())
+        }
+    }
+
+    /// `<id> #<name>`, the form channels are announced to clients in.
+    pub fn label(&self) -> String {
+        format!("{} #{}", self.id, self.name)
+    }
+
+    /// Store a message, dropping the oldest ones beyond `cap`.
+    pub fn push_message(&mut self, msg: Message, cap: usize) {
+        self.messages.insert(msg.id.clone(), msg);
+        while self.messages.len() > cap {
+            self.messages.pop_first();
+        }
+    }
+
+    pub fn is_moderator(&self, uid: &String) -> bool {
+        self.owner == *uid || self.moderators.contains(uid)
+    }
+
+    /// Enforce slow mode and the chan wide rate limit. The post only counts
+    /// once `record_post` is called for it.
+    pub fn check_rate(&mut self, uid: &String, now: Instant) -> Result<(), ChatErrors> {
+        if let (Some(interval), Some(last)) = (self.slow_mode, self.last_post.get(uid)) {
+            let elapsed = now.duration_since(*last);
+            if elapsed < interval {
+                return Err(ChatErrors::RateLimited(interval - elapsed));
+            }
+        }
+
+        if let Some(max) = self.max_msgs_per_sec {
+            let window = Duration::from_secs(1);
+            while let Some(first) = self.recent_posts.front() {
+                if now.duration_since(*first) < window {
+                    break;
+                }
+                self.recent_posts.pop_front();
+            }
+
+            if self.recent_posts.len() >= max as usize
+                && let Some(first) = self.recent_posts.front()
+            {
+                return Err(ChatErrors::RateLimited(window - now.duration_since(*first)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Count a post that passed `check_rate` and was stored.
+    pub fn record_post(&mut self, uid: &str, now: Instant) {
+        if self.max_msgs_per_sec.is_some() {
+            self.recent_posts.push_back(now);
+        }
+        if self.slow_mode.is_some() {
+            self.last_post.insert(uid.to_string(), now);
+        }
+    }
+
+    pub fn join(&mut self, user_id: String) {
+        self.online_users.insert(user_id, ());
+    }
+
+    pub fn leave(&mut self, user_id: String) {
+        self.online_users.remove(&user_id);
+    }
+}
+
+impl Message {
+    pub fn new(username: String, chan_id: String, c: String) -> Self {
+        Self {
+            id: gen_msg_id(),
+            chan_id,
+            chan_name: String::new(),
+            sender: username.clone(),
+            sender_id: username,
+            content: c,
+            send_time: Utc::now(),
+            is_cmd: true,
+            parent_id: None,
+            reply_count: 0,
+            reactions: BTreeMap::new(),
+            edited: false,
+            deleted: false,
+        }
+    }
+
+    pub fn from_user(uid: String, username: String, chan_id: String, c: String) -> Self {
+        Self {
+            sender: username,
+            sender_id: uid,
+            is_cmd: false,
+            ..Self::new(String::new(), chan_id, c)
+        }
+    }
+}
+
+impl Message {
+    /// `👍 2, :tada: 1`, or `none` once the last reaction is gone.
+    pub fn reaction_summary(&self) -> String {
+        if self.reactions.is_empty() {
+            return "none".to_string();
+        }
+
+        self.reactions
+            .iter()
+            .map(|(reaction, users)| format!("{} {}", reaction, users.len()))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_cmd {
+            return write!(f, "{}", self.content);
+        }
+
+        if self.deleted {
+            return write!(f, "[{}] (deleted)", self.id);
+        }
+
+        write!(f, "[{}] ", self.id)?;
+        if !self.chan_name.is_empty() {
+            write!(f, "#{} ", self.chan_name)?;
+        }
+        if let Some(parent_id) = &self.parent_id {
+            write!(f, "(reply to {}) ", parent_id)?;
+        }
+        write!(f, "{}: {}", self.sender, self.content)?;
+        if self.edited {
+            write!(f, " (edited)")?;
+        }
+        if self.reply_count > 0 {
+            write!(f, " ({} replies)", self.reply_count)?;
+        }
+        if !self.reactions.is_empty() {
+            write!(f, " [{}]", self.reaction_summary())?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for UserInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.id, self.name)
+    }
+}
+
+fn gen_id() -> String {
+    let alphabet: [char; 16] = [
+        '1', '2', '3', '4', '5', '6', '7', '8', '9', '0', 'a', 'b', 'c', 'd', 'e', 'f',
+    ];
+
+    nanoid!(10, &alphabet) //=> "4f90d13a42"
+}
+
+/// Secret a user logs in with once their name is claimed, only its hash is kept.
+fn gen_token() -> String {
+    nanoid!(32)
+}
+
+/// Hex sha256 of the token. Tokens are random, not picked by users, so there
+/// is nothing to salt or stretch.
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// `hi @alice, @bob!` => ["alice", "bob"]
+fn parse_mentions(content: &str) -> Vec<String> {
+    let mut names: Vec<String> = content
+        .split_whitespace()
+        .filter_map(|word| word.strip_prefix('@'))
+        .map(|name| {
+            name.trim_end_matches(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-'))
+                .to_string()
+        })
+        .filter(|name| !name.is_empty())
+        .collect();
+
+    names.sort();
+    names.dedup();
+    names
+}
+
+static LAST_MSG_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Unix millis in the high bits and a sequence in the low 16 bits, so ids
+/// sort by send time and stay unique within the same millisecond.
+fn gen_msg_id() -> String {
+    let now = (Utc::now().timestamp_millis() as u64) << 16;
+    let prev = LAST_MSG_ID
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| Some(now.max(last + 1)))
+        .unwrap_or_default();
+
+    format!("{:016x}", now.max(prev + 1)) //=> "019a0c5e2b7c0000"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing::{connect, drain, login, service};
+
+    fn last_msg_id(svc: &ChatService, chan_id: &String) -> String {
+        svc.channels.get(chan_id).unwrap().messages.keys().last().cloned().unwrap()
+    }
+
+    #[test]
+    fn archived_chans_reject_posts() {
+        let mut svc = service();
+        let (alice, mut a) = login(&mut svc, "c1", "alice");
+        let chan_id = svc.create_chan(alice.clone(), "room".to_string(), None).unwrap();
+        svc.post_msg(alice.clone(), chan_id.clone(), "before".to_string(), None).unwrap();
+        svc.archive_chan(alice.clone(), chan_id.clone()).unwrap();
+
+        assert!(matches!(
+            svc.post_msg(alice.clone(), chan_id.clone(), "after".to_string(), None),
+            Err(ChatErrors::ChannelArchived(_))
+        ));
+        let lines = drain(&mut a);
+        assert_eq!(lines.last().unwrap(), &format!("{}: {}", ARCHIVE_CHAN_RESP, chan_id));
+        assert!(!lines.iter().any(|line| line.ends_with("after")), "{:?}", lines);
+        assert!(svc.is_user_sub(&alice, &chan_id));
+    }
+
+    #[test]
+    fn deleting_a_chan_tells_its_members() {
+        let mut svc = service();
+        let (alice, mut a) = login(&mut svc, "c1", "alice");
+        let (bob, mut b) = login(&mut svc, "c2", "bob");
+        let (_carol, mut c) = login(&mut svc, "c3", "carol");
+        let chan_id = svc.create_chan(alice.clone(), "room".to_string(), None).unwrap();
+        svc.join_chan(bob.clone(), chan_id.clone()).unwrap();
+        drain(&mut a);
+        drain(&mut b);
+        drain(&mut c);
+
+        svc.delete_chan(alice.clone(), chan_id.clone()).unwrap();
+        let deleted = format!("{}: {}", DELETE_CHAN_RESP, chan_id);
+        assert_eq!(drain(&mut a), vec![deleted.clone()]);
+        assert_eq!(drain(&mut b), vec![deleted]);
+        assert!(drain(&mut c).is_empty());
+        assert!(!svc.channels.contains_key(&chan_id));
+        assert!(!svc.is_user_sub(&bob, &chan_id));
+    }
+
+    #[test]
+    fn personal_chans_stay() {
+        let mut svc = service();
+        let (alice, _a) = login(&mut svc, "c1", "alice");
+        for res in [
+            svc.archive_chan(alice.clone(), alice.clone()),
+            svc.delete_chan(alice.clone(), alice.clone()),
+            svc.leave_chan(alice.clone(), alice.clone()),
+        ] {
+            assert!(matches!(res, Err(ChatErrors::PermissionDenied(_))));
+        }
+        assert!(!svc.channels.get(&alice).unwrap().archived);
+        assert!(svc.is_user_sub(&alice, &alice));
+    }
+
+    #[test]
+    fn the_leaver_is_told() {
+        let mut svc = service();
+        let (alice, mut a) = login(&mut svc, "c1", "alice");
+        let (bob, mut b) = login(&mut svc, "c2", "bob");
+        let chan_id = svc.create_chan(alice.clone(), "room".to_string(), None).unwrap();
+        svc.join_chan(bob.clone(), chan_id.clone()).unwrap();
+        drain(&mut a);
+        drain(&mut b);
+
+        svc.leave_chan(bob.clone(), chan_id.clone()).unwrap();
+        assert_eq!(drain(&mut b), vec![format!("{}: {}", LEAVE_RESP, chan_id)]);
+        // the others keep the chan, their clients drop it on this response
+        assert!(drain(&mut a).is_empty());
+        assert!(!svc.is_user_sub(&bob, &chan_id));
+    }
+
+    #[test]
+    fn chan_names_are_unique_ignoring_case() {
+        let mut svc = service();
+        let (alice, _) = login(&mut svc, "c1", "alice");
+        let room = svc.create_named_chan(alice.clone(), "#Room".to_string()).unwrap();
+        assert!(matches!(
+            svc.create_named_chan(alice.clone(), "room".to_string()),
+            Err(ChatErrors::ChannelNameTaken(_))
+        ));
+        for name in ["", "#", "two words"] {
+            assert!(matches!(
+                svc.create_named_chan(alice.clone(), name.to_string()),
+                Err(ChatErrors::InvalidChannelName(_))
+            ));
+        }
+
+        assert_eq!(svc.resolve_chan("#ROOM".to_string()).unwrap(), room);
+        assert_eq!(svc.resolve_chan(room.clone()).unwrap(), room);
+        assert!(matches!(svc.resolve_chan("#hall".to_string()), Err(ChatErrors::ChannelNotFound(_))));
+
+        // the name is free again once the chan is gone
+        svc.delete_chan(alice.clone(), room.clone()).unwrap();
+        assert!(svc.resolve_chan("#room".to_string()).is_err());
+        let again = svc.create_named_chan(alice, "room".to_string()).unwrap();
+        assert_ne!(again, room);
+    }
+
+    #[test]
+    fn new_users_join_the_default_chans() {
+        let mut svc = service();
+        let lobby = svc.add_default_chan("lobby".to_string(), false).unwrap();
+        let news = svc.add_default_chan("#news".to_string(), true).unwrap();
+        // the server does not stay a member of its own chans
+        assert!(svc.chan_members(&lobby).is_empty());
+
+        let (alice, mut a) = login(&mut svc, "c1", "alice");
+        assert!(svc.is_user_sub(&alice, &lobby) && svc.is_user_sub(&alice, &news));
+        let lines = drain(&mut a);
+        let joined: Vec<&String> = lines.iter().filter(|line| line.starts_with(AUTO_JOIN_RESP)).collect();
+        assert_eq!(joined.len(), 2, "{:?}", lines);
+        assert!(matches!(
+            svc.post_msg(alice.clone(), news.clone(), "hi".to_string(), None),
+            Err(ChatErrors::PermissionDenied(_))
+        ));
+
+        // adding it again, as on a restart, keeps the chan
+        assert_eq!(svc.add_default_chan("Lobby".to_string(), false).unwrap(), lobby);
+    }
+
+    #[test]
+    fn slow_mode_spaces_one_users_posts() {
+        let mut chan = Channel::new("room".to_string(), "a".to_string());
+        chan.slow_mode = Some(Duration::from_secs(10));
+        let (alice, bob) = ("a".to_string(), "b".to_string());
+        let start = Instant::now();
+
+        let mut post = |uid: &String, at: Duration| {
+            chan.check_rate(uid, start + at)?;
+            chan.record_post(uid, start + at);
+            Ok::<_, ChatErrors>(())
+        };
+
+        post(&alice, Duration::ZERO).unwrap();
+        match post(&alice, Duration::from_secs(4)) {
+            Err(ChatErrors::RateLimited(wait)) => assert_eq!(wait, Duration::from_secs(6)),
+            res => panic!("unexpected: {:?}", res),
+        }
+        // other users have their own window
+        post(&bob, Duration::from_secs(4)).unwrap();
+        // a refused post does not restart the window
+        post(&alice, Duration::from_secs(10)).unwrap();
+    }
+
+    #[test]
+    fn rate_limit_caps_the_whole_chan() {
+        let mut chan = Channel::new("room".to_string(), "a".to_string());
+        chan.max_msgs_per_sec = Some(2);
+        let start = Instant::now();
+
+        let mut post = |uid: &str, at_ms: u64| {
+            let at = start + Duration::from_millis(at_ms);
+            chan.check_rate(&uid.to_string(), at)?;
+            chan.record_post(uid, at);
+            Ok::<_, ChatErrors>(())
+        };
+
+        post("a", 0).unwrap();
+        post("b", 300).unwrap();
+        match post("c", 400) {
+            Err(ChatErrors::RateLimited(wait)) => assert_eq!(wait, Duration::from_millis(600)),
+            res => panic!("unexpected: {:?}", res),
+        }
+        // the first post left the window
+        post("c", 1000).unwrap();
+        assert!(post("a", 1100).is_err());
+    }
+
+    #[test]
+    fn only_moderators_change_chan_modes() {
+        let mut svc = service();
+        let (alice, _a) = login(&mut svc, "c1", "alice");
+        let (bob, _b) = login(&mut svc, "c2", "bob");
+        let chan_id = svc.create_named_chan(alice.clone(), "room".to_string()).unwrap();
+        svc.join_chan(bob.clone(), chan_id.clone()).unwrap();
+
+        assert!(matches!(
+            svc.set_slow_mode(bob.clone(), chan_id.clone(), 5),
+            Err(ChatErrors::PermissionDenied(_))
+        ));
+        assert!(svc.add_moderator(bob.clone(), chan_id.clone(), "bob".to_string()).is_err());
+        assert!(matches!(
+            svc.add_moderator(alice.clone(), chan_id.clone(), "carol".to_string()),
+            Err(ChatErrors::InvalidArgument(_))
+        ));
+        svc.add_moderator(alice.clone(), chan_id.clone(), "Bob".to_string()).unwrap();
+        svc.set_slow_mode(bob.clone(), chan_id.clone(), 60).unwrap();
+
+        svc.post_msg(bob.clone(), chan_id.clone(), "one".to_string(), None).unwrap();
+        assert!(matches!(
+            svc.post_msg(bob, chan_id, "two".to_string(), None),
+            Err(ChatErrors::RateLimited(_))
+        ));
+    }
+
+    #[test]
+    fn only_moderators_post_to_read_only_chans() {
+        let mut svc = service();
+        svc.admins.insert("root".to_string());
+        svc.admin_key_hash = Some(hash_token("key"));
+        let (alice, _a) = login(&mut svc, "c1", "alice");
+        let (bob, _b) = login(&mut svc, "c2", "bob");
+        let (carol, _c) = login(&mut svc, "c3", "carol");
+        connect(&mut svc, "c4");
+        let (root, _) = svc.login("c4".to_string(), "root".to_string(), Some("key".to_string())).unwrap();
+        let (dave, _d) = login(&mut svc, "c5", "dave");
+        let chan_id = svc.create_named_chan(alice.clone(), "news".to_string()).unwrap();
+        for uid in [&bob, &carol, &root] {
+            svc.join_chan(uid.clone(), chan_id.clone()).unwrap();
+        }
+        svc.add_moderator(alice.clone(), chan_id.clone(), "bob".to_string()).unwrap();
+
+        assert!(svc.set_read_only(carol.clone(), chan_id.clone(), true).is_err());
+        svc.set_read_only(bob.clone(), chan_id.clone(), true).unwrap();
+        assert!(matches!(
+            svc.post_msg(carol.clone(), chan_id.clone(), "chatter".to_string(), None),
+            Err(ChatErrors::PermissionDenied(_))
+        ));
+        for uid in [&alice, &bob, &root] {
+            svc.post_msg(uid.clone(), chan_id.clone(), "notice".to_string(), None).unwrap();
+        }
+
+        svc.set_read_only(alice, chan_id.clone(), false).unwrap();
+        svc.post_msg(carol, chan_id.clone(), "chatter".to_string(), None).unwrap();
+        // never joined
+        assert!(matches!(
+            svc.post_msg(dave, chan_id, "chatter".to_string(), None),
+            Err(ChatErrors::PermissionDenied(_))
+        ));
+    }
+
+    #[test]
+    fn msg_ids_sort_by_send_order() {
+        let ids: Vec<String> = (0..1000).map(|_| gen_msg_id()).collect();
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(ids.iter().all(|id| id.len() == 16));
+    }
+
+    #[test]
+    fn only_authors_and_moderators_change_a_msg() {
+        let mut svc = service();
+        let (alice, _a) = login(&mut svc, "c1", "alice");
+        let (bob, mut b) = login(&mut svc, "c2", "bob");
+        let (carol, _c) = login(&mut svc, "c3", "carol");
+        let room = svc.create_named_chan(alice.clone(), "room".to_string()).unwrap();
+        svc.join_chan(bob.clone(), room.clone()).unwrap();
+        svc.join_chan(carol.clone(), room.clone()).unwrap();
+        svc.post_msg(bob.clone(), room.clone(), "typo".to_string(), None).unwrap();
+        let msg_id = last_msg_id(&svc, &room);
+        drain(&mut b);
+
+        assert!(matches!(
+            svc.edit_msg(carol.clone(), room.clone(), msg_id.clone(), "mine".to_string()),
+            Err(ChatErrors::PermissionDenied(_))
+        ));
+        svc.edit_msg(bob.clone(), room.clone(), msg_id.clone(), "fixed".to_string()).unwrap();
+        let lines = drain(&mut b);
+        assert_eq!(lines, vec![format!("{}: [{}] #room bob: fixed (edited)", EDIT_MSG_RESP, msg_id)]);
+
+        // the owner moderates the chan
+        svc.delete_msg(alice.clone(), room.clone(), msg_id.clone()).unwrap();
+        assert_eq!(drain(&mut b), vec![format!("{}: {} {}", DELETE_MSG_RESP, room, msg_id)]);
+        let msg = svc.channels.get(&room).unwrap().messages[&msg_id].clone();
+        assert_eq!(msg.to_string(), format!("[{}] (deleted)", msg_id));
+        assert!(matches!(
+            svc.edit_msg(bob, room, msg_id, "back".to_string()),
+            Err(ChatErrors::MessageNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn typing_reaches_the_other_members_only() {
+        let mut svc = service();
+        let (alice, mut a) = login(&mut svc, "c1", "alice");
+        let (bob, mut b) = login(&mut svc, "c2", "bob");
+        let room = svc.create_named_chan(alice.clone(), "room".to_string()).unwrap();
+        svc.join_chan(bob.clone(), room.clone()).unwrap();
+        drain(&mut a);
+        drain(&mut b);
+
+        svc.typing(alice.clone(), room.clone()).unwrap();
+        // within `TYPING_INTERVAL` of the first, dropped
+        svc.typing(alice.clone(), room.clone()).unwrap();
+        assert_eq!(drain(&mut b), vec![format!("{}: {} alice", TYPING_RESP, room)]);
+        assert!(drain(&mut a).is_empty());
+        assert!(svc.channels.get(&room).unwrap().messages.is_empty());
+        assert_eq!(svc.storage.count_messages(&room).unwrap(), 0);
+    }
+
+    #[test]
+    fn failed_writes_change_nothing() {
+        let (mut svc, fail) = testing::failing_service();
+        let (alice, mut a) = login(&mut svc, "c1", "alice");
+        let (bob, _b) = login(&mut svc, "c2", "bob");
+        let room = svc.create_named_chan(alice.clone(), "room".to_string()).unwrap();
+        svc.post_msg(alice.clone(), room.clone(), "kept".to_string(), None).unwrap();
+        let msg_id = last_msg_id(&svc, &room);
+        drain(&mut a);
+
+        fail.store(true, Ordering::Relaxed);
+        assert!(matches!(svc.join_chan(bob.clone(), room.clone()), Err(ChatErrors::Storage(_))));
+        assert!(!svc.is_user_sub(&bob, &room));
+        assert!(svc.post_msg(alice.clone(), room.clone(), "lost".to_string(), None).is_err());
+        assert!(svc.post_msg(alice.clone(), room.clone(), "lost".to_string(), Some(msg_id.clone())).is_err());
+        assert!(svc.edit_msg(alice.clone(), room.clone(), msg_id.clone(), "changed".to_string()).is_err());
+        assert!(svc.set_read_only(alice.clone(), room.clone(), true).is_err());
+        assert!(svc.leave_chan(alice.clone(), room.clone()).is_err());
+
+        let chan = svc.channels.get(&room).unwrap();
+        assert_eq!(chan.messages.len(), 1);
+        assert_eq!((chan.messages[&msg_id].content.as_str(), chan.messages[&msg_id].reply_count), ("kept", 0));
+        assert!(!chan.read_only);
+        drop(chan);
+        assert!(svc.is_user_sub(&alice, &room));
+        assert!(drain(&mut a).is_empty());
+    }
+
+    #[test]
+    fn failed_posts_do_not_count_against_the_rate() {
+        let (mut svc, fail) = testing::failing_service();
+        let (alice, _a) = login(&mut svc, "c1", "alice");
+        let room = svc.create_named_chan(alice.clone(), "room".to_string()).unwrap();
+        svc.set_rate_limit(alice.clone(), room.clone(), 1).unwrap();
+        svc.set_slow_mode(alice.clone(), room.clone(), 60).unwrap();
+
+        fail.store(true, Ordering::Relaxed);
+        assert!(matches!(
+            svc.post_msg(alice.clone(), room.clone(), "lost".to_string(), None),
+            Err(ChatErrors::Storage(_))
+        ));
+        fail.store(false, Ordering::Relaxed);
+        svc.post_msg(alice.clone(), room.clone(), "kept".to_string(), None).unwrap();
+        assert!(matches!(
+            svc.post_msg(alice, room, "too soon".to_string(), None),
+            Err(ChatErrors::RateLimited(_))
+        ));
+    }
+
+    #[test]
+    fn msgs_over_the_max_len_are_refused() {
+        let mut svc = service();
+        svc.max_msg_len = 8;
+        let (alice, _a) = login(&mut svc, "c1", "alice");
+        let room = svc.create_named_chan(alice.clone(), "room".to_string()).unwrap();
+
+        assert!(matches!(
+            svc.post_msg(alice.clone(), room.clone(), "123456789".to_string(), None),
+            Err(ChatErrors::MessageTooLong(8))
+        ));
+        svc.post_msg(alice.clone(), room.clone(), "12345678".to_string(), None).unwrap();
+        let msg_id = last_msg_id(&svc, &room);
+        assert!(matches!(
+            svc.edit_msg(alice, room, msg_id, "123456789".to_string()),
+            Err(ChatErrors::MessageTooLong(8))
+        ));
+    }
+
+    #[test]
+    fn archived_chans_keep_their_msgs() {
+        let mut svc = service();
+        let (alice, _a) = login(&mut svc, "c1", "alice");
+        let room = svc.create_named_chan(alice.clone(), "room".to_string()).unwrap();
+        svc.post_msg(alice.clone(), room.clone(), "final".to_string(), None).unwrap();
+        let msg_id = last_msg_id(&svc, &room);
+        svc.archive_chan(alice.clone(), room.clone()).unwrap();
+
+        assert!(matches!(
+            svc.edit_msg(alice.clone(), room.clone(), msg_id.clone(), "changed".to_string()),
+            Err(ChatErrors::ChannelArchived(_))
+        ));
+        assert!(matches!(
+            svc.delete_msg(alice.clone(), room.clone(), msg_id.clone()),
+            Err(ChatErrors::ChannelArchived(_))
+        ));
+        assert!(matches!(
+            svc.react(alice, room.clone(), msg_id.clone(), "+1".to_string(), true),
+            Err(ChatErrors::ChannelArchived(_))
+        ));
+        assert_eq!(svc.channels.get(&room).unwrap().messages[&msg_id].content, "final");
+    }
+
+    #[test]
+    fn reactions_count_each_user_once() {
+        let mut svc = service();
+        let (alice, mut a) = login(&mut svc, "c1", "alice");
+        let (bob, _b) = login(&mut svc, "c2", "bob");
+        let (carol, _c) = login(&mut svc, "c3", "carol");
+        let room = svc.create_named_chan(alice.clone(), "room".to_string()).unwrap();
+        svc.join_chan(bob.clone(), room.clone()).unwrap();
+        svc.post_msg(alice.clone(), room.clone(), "ship it".to_string(), None).unwrap();
+        let msg_id = last_msg_id(&svc, &room);
+        drain(&mut a);
+
+        let react = |uid: &String, reaction: &str, add| svc.react(uid.clone(), room.clone(), msg_id.clone(), reaction.to_string(), add);
+        react(&bob, "+1", true).unwrap();
+        react(&bob, "+1", true).unwrap();
+        react(&alice, "+1", true).unwrap();
+        react(&alice, "🎉", true).unwrap();
+        react(&alice, "🎉", false).unwrap();
+        assert!(matches!(react(&alice, "two words", true), Err(ChatErrors::InvalidReaction(_))));
+        assert!(matches!(react(&carol, "+1", true), Err(ChatErrors::PermissionDenied(_))));
+
+        let lines = drain(&mut a);
+        assert_eq!(lines.len(), 5, "{:?}", lines);
+        assert_eq!(lines[1], format!("{}: {} +1 1", REACTIONS_RESP, msg_id));
+        assert_eq!(lines[3], format!("{}: {} +1 2, 🎉 1", REACTIONS_RESP, msg_id));
+        assert_eq!(lines[4], format!("{}: {} +1 2", REACTIONS_RESP, msg_id));
+    }
+
+    #[test]
+    fn parses_mentions_out_of_punctuation() {
+        assert_eq!(parse_mentions("hi @alice, @bob! and @alice"), ["alice", "bob"]);
+        assert_eq!(parse_mentions("mail a@b.c or @ alone"), Vec::<String>::new());
+        assert_eq!(parse_mentions("@dev-ops_1."), ["dev-ops_1"]);
+    }
+
+    #[test]
+    fn mentions_reach_users_outside_the_chan() {
+        let mut svc = service();
+        let (alice, mut a) = login(&mut svc, "c1", "alice");
+        let (bob, mut b) = login(&mut svc, "c2", "bob");
+        let room = svc.create_named_chan(bob.clone(), "room".to_string()).unwrap();
+        drain(&mut a);
+        drain(&mut b);
+
+        svc.post_msg(bob.clone(), room.clone(), "ping @Alice and @bob and @nobody".to_string(), None).unwrap();
+        let lines = drain(&mut a);
+        assert_eq!(lines.len(), 1, "{:?}", lines);
+        assert!(lines[0].starts_with(&format!("{}: {} [", MENTION_RESP, room)), "{:?}", lines);
+        assert!(!svc.is_user_sub(&alice, &room));
+        // no mention line for the sender's own name
+        assert!(!drain(&mut b).iter().any(|line| line.starts_with(MENTION_RESP)));
+    }
+
+    #[test]
+    fn personal_chans_can_not_be_joined() {
+        let mut svc = service();
+        let (alice, mut a) = login(&mut svc, "c1", "alice");
+        let (bob, mut b) = login(&mut svc, "c2", "bob");
+        assert!(matches!(svc.join_chan(bob.clone(), alice.clone()), Err(ChatErrors::PermissionDenied(_))));
+        assert!(!svc.is_user_sub(&bob, &alice));
+        assert!(matches!(svc.join_chan(bob.clone(), "nope".to_string()), Err(ChatErrors::ChannelNotFound(_))));
+        drain(&mut a);
+        drain(&mut b);
+
+        let room = svc.create_named_chan(bob.clone(), "room".to_string()).unwrap();
+        svc.post_msg(bob, room, "@alice look".to_string(), None).unwrap();
+        assert_eq!(drain(&mut a).len(), 1);
+        assert!(!drain(&mut b).iter().any(|line| line.starts_with(MENTION_RESP)));
+    }
+
+    #[test]
+    fn login_keeps_the_uid_of_a_name() {
+        let mut svc = service();
+        let (alice, mut a) = login(&mut svc, "c1", "alice");
+        assert_eq!(drain(&mut a)[0], alice);
+        svc.disconnect("c1");
+
+        let (again, mut a) = login(&mut svc, "c2", "Alice");
+        assert_eq!(again, alice);
+        assert_eq!(drain(&mut a)[0], alice);
+        assert_eq!(svc.session("c2"), Some(&alice));
+        assert_eq!(svc.session("c1"), None);
+    }
+
+    #[test]
+    fn one_session_per_user() {
+        let mut svc = service();
+        let mut a = connect(&mut svc, "c1");
+        let (_, token) = svc.login("c1".to_string(), "alice".to_string(), None).unwrap();
+        let token = token.unwrap();
+        assert_eq!(drain(&mut a)[1], format!("{}: {}", TOKEN_RESP, token));
+        assert!(matches!(
+            svc.login("c2".to_string(), "alice".to_string(), Some(token)),
+            Err(ChatErrors::AlreadyLoggedIn(_))
+        ));
+        assert!(matches!(
+            svc.login("c1".to_string(), "bob".to_string(), None),
+            Err(ChatErrors::InvalidCommand(_))
+        ));
+        assert!(svc.login("c2".to_string(), "a b".to_string(), None).is_err());
+    }
+
+    #[test]
+    fn a_claimed_name_needs_its_token() {
+        let mut svc = service();
+        let (alice, _a) = login(&mut svc, "c1", "alice");
+        svc.disconnect("c1");
+
+        connect(&mut svc, "c2");
+        for token in [None, Some("guess".to_string())] {
+            assert!(matches!(
+                svc.login("c2".to_string(), "ALICE".to_string(), token),
+                Err(ChatErrors::PermissionDenied(_))
+            ));
+        }
+        assert_eq!(svc.session("c2"), None);
+        assert!(!svc.online.contains_key(&alice));
+    }
+
+    #[test]
+    fn admin_names_need_the_admin_key() {
+        let mut svc = service();
+        svc.admins.insert("root".to_string());
+        connect(&mut svc, "c1");
+        // no key configured, nobody can be an admin
+        assert!(svc.login("c1".to_string(), "root".to_string(), None).is_err());
+
+        svc.admin_key_hash = Some(hash_token("key"));
+        for (name, token) in [("root", None), ("Root", Some("guess"))] {
+            assert!(matches!(
+                svc.login("c1".to_string(), name.to_string(), token.map(str::to_string)),
+                Err(ChatErrors::PermissionDenied(_))
+            ));
+        }
+        assert!(svc.user_names.is_empty());
+
+        let (root, token) = svc.login("c1".to_string(), "root".to_string(), Some("key".to_string())).unwrap();
+        assert_eq!(token, None);
+        assert!(svc.is_admin(&root));
+        svc.send_stats(root).unwrap();
+    }
+
+    #[test]
+    fn unread_counts_survive_a_new_login() {
+        let mut svc = service();
+        let lobby = svc.add_default_chan("lobby".to_string(), false).unwrap();
+        let (alice, _) = login(&mut svc, "c1", "alice");
+        let (bob, _) = login(&mut svc, "c2", "bob");
+        svc.post_msg(bob.clone(), lobby.clone(), "seen".to_string(), None).unwrap();
+        svc.mark_read(alice.clone(), lobby.clone(), None).unwrap();
+        svc.disconnect("c1");
+
+        svc.post_msg(bob.clone(), lobby.clone(), "one".to_string(), None).unwrap();
+        svc.post_msg(bob, lobby.clone(), "two".to_string(), None).unwrap();
+        let (_, mut a) = login(&mut svc, "c3", "alice");
+
+        assert_eq!(svc.unread_count(&alice, &lobby), 2);
+        let label = svc.channels.get(&lobby).unwrap().label();
+        let lines = drain(&mut a);
+        assert!(lines.contains(&format!("{}: {} 2", UNREAD_RESP, label)), "{:?}", lines);
+        // joined on the first login only
+        assert!(!lines.iter().any(|line| line.starts_with(AUTO_JOIN_RESP)), "{:?}", lines);
+    }
+
+    #[test]
+    fn unread_counts_reach_past_the_history_ring() {
+        let mut svc = service();
+        svc.history_size = 2;
+        let (alice, _a) = login(&mut svc, "c1", "alice");
+        let (bob, _b) = login(&mut svc, "c2", "bob");
+        let room = svc.create_named_chan(alice.clone(), "room".to_string()).unwrap();
+        svc.join_chan(bob.clone(), room.clone()).unwrap();
+        let mut ids = Vec::new();
+        for i in 0..5 {
+            svc.post_msg(bob.clone(), room.clone(), i.to_string(), None).unwrap();
+            ids.push(last_msg_id(&svc, &room));
+        }
+        assert_eq!(svc.unread_count(&alice, &room), 5);
+
+        // the marker can sit on a message pushed out of the ring
+        svc.mark_read(alice.clone(), room.clone(), Some(ids[1].clone())).unwrap();
+        assert_eq!(svc.unread_count(&alice, &room), 3);
+        svc.mark_read(alice.clone(), room.clone(), Some(ids[3].clone())).unwrap();
+        assert_eq!(svc.unread_count(&alice, &room), 1);
+    }
+
+    #[test]
+    fn new_default_chans_are_joined_on_the_next_login() {
+        let mut svc = service();
+        let (alice, _) = login(&mut svc, "c1", "alice");
+        svc.disconnect("c1");
+        let news = svc.add_default_chan("news".to_string(), true).unwrap();
+        assert!(!svc.is_user_sub(&alice, &news));
+
+        let (_, mut a) = login(&mut svc, "c2", "alice");
+        assert!(svc.is_user_sub(&alice, &news));
+        assert!(drain(&mut a).iter().any(|line| line.starts_with(AUTO_JOIN_RESP)));
+    }
+
+    #[test]
+    fn left_default_chans_stay_left() {
+        let mut svc = service();
+        let news = svc.add_default_chan("news".to_string(), true).unwrap();
+        let (alice, _) = login(&mut svc, "c1", "alice");
+        svc.leave_chan(alice.clone(), news.clone()).unwrap();
+        svc.disconnect("c1");
+
+        let (_, mut a) = login(&mut svc, "c2", "alice");
+        assert!(!svc.is_user_sub(&alice, &news));
+        assert!(!drain(&mut a).iter().any(|line| line.starts_with(AUTO_JOIN_RESP)));
+        assert!(svc.storage.load().unwrap().users.iter().any(|user| user.offered_chans.contains(&news)));
+    }
+
+    #[test]
+    fn joins_replay_the_bounded_history() {
+        let mut svc = service();
+        svc.history_size = 3;
+        let (alice, _) = login(&mut svc, "c1", "alice");
+        let (bob, mut b) = login(&mut svc, "c2", "bob");
+        let room = svc.create_named_chan(alice.clone(), "room".to_string()).unwrap();
+        for i in 0..5 {
+            svc.post_msg(alice.clone(), room.clone(), format!("msg {}", i), None).unwrap();
+        }
+        svc.delete_msg(alice.clone(), room.clone(), last_msg_id(&svc, &room)).unwrap();
+        assert_eq!(svc.channels.get(&room).unwrap().messages.len(), 3);
+        drain(&mut b);
+
+        svc.join_chan(bob.clone(), room.clone()).unwrap();
+        let lines = drain(&mut b);
+        let replayed: Vec<&String> = lines.iter().filter(|line| line.starts_with(REPLAY_RESP)).collect();
+        assert_eq!(replayed.len(), 2, "{:?}", replayed);
+        assert!(replayed[0].ends_with("alice: msg 2") && replayed[1].ends_with("alice: msg 3"), "{:?}", replayed);
+        // joining does not count the replayed messages as unread
+        assert_eq!(svc.unread_count(&bob, &room), 0);
+    }
+
+    #[test]
+    fn users_and_chans_survive_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("chat.db");
+        let mut svc = service();
+        svc.load_storage(Box::new(crate::storage::SqliteStorage::open(&path).unwrap())).unwrap();
+        let (alice, _) = login(&mut svc, "c1", "alice");
+        let chan_id = svc.create_named_chan(alice.clone(), "room".to_string()).unwrap();
+        svc.post_msg(alice.clone(), chan_id.clone(), "hi".to_string(), None).unwrap();
+        drop(svc);
+
+        let mut svc = service();
+        svc.load_storage(Box::new(crate::storage::SqliteStorage::open(&path).unwrap())).unwrap();
+        let (uid, _) = login(&mut svc, "c2", "alice");
+        assert_eq!(uid, alice);
+        assert!(svc.is_user_sub(&uid, &chan_id));
+        assert_eq!(svc.channels.get(&chan_id).unwrap().owner, uid);
+        assert_eq!(svc.channels.get(&chan_id).unwrap().messages.len(), 1);
+    }
+
+    #[test]
+    fn msgs_pushed_out_of_the_history_stay_reachable() {
+        let mut svc = service();
+        svc.history_size = 0;
+        let (alice, mut a) = login(&mut svc, "c1", "alice");
+        let (bob, _) = login(&mut svc, "c2", "bob");
+        let room = svc.create_named_chan(alice.clone(), "room".to_string()).unwrap();
+        svc.join_chan(bob.clone(), room.clone()).unwrap();
+        let stored = |svc: &ChatService, msg_id: &String| svc.storage.get_message(msg_id).unwrap().unwrap();
+
+        svc.post_msg(alice.clone(), room.clone(), "root".to_string(), None).unwrap();
+        let root = svc.storage.load().unwrap().messages.last().unwrap().id.clone();
+        assert!(svc.channels.get(&room).unwrap().messages.is_empty());
+        svc.post_msg(bob.clone(), room.clone(), "reply".to_string(), Some(root.clone())).unwrap();
+        let reply = svc.storage.load().unwrap().messages.last().unwrap().id.clone();
+        svc.post_msg(alice.clone(), room.clone(), "nested".to_string(), Some(reply)).unwrap();
+        assert_eq!(stored(&svc, &root).reply_count, 2);
+
+        assert!(matches!(
+            svc.edit_msg(bob.clone(), room.clone(), root.clone(), "mine".to_string()),
+            Err(ChatErrors::PermissionDenied(_))
+        ));
+        svc.edit_msg(alice.clone(), room.clone(), root.clone(), "fixed".to_string()).unwrap();
+        svc.react(bob.clone(), room.clone(), root.clone(), ":+1:".to_string(), true).unwrap();
+        assert_eq!(stored(&svc, &root).to_string(), format!("[{}] #room alice: fixed (edited) (2 replies) [:+1: 1]", root));
+
+        drain(&mut a);
+        svc.get_thread(alice.clone(), room.clone(), root.clone()).unwrap();
+        let lines = drain(&mut a);
+        assert_eq!(lines.len(), 3, "{:?}", lines);
+        assert!(lines[0].starts_with(THREAD_RESP) && lines[2].ends_with("alice: nested"), "{:?}", lines);
+
+        svc.delete_msg(alice.clone(), room.clone(), root.clone()).unwrap();
+        assert!(stored(&svc, &root).deleted);
+        assert!(matches!(
+            svc.post_msg(bob, room, "late".to_string(), Some(root)),
+            Err(ChatErrors::MessageNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn read_markers_survive_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("chat.wal");
+        let mut svc = service();
+        svc.load_storage(Box::new(crate::storage::WalStorage::open(&path).unwrap())).unwrap();
+        let lobby = svc.add_default_chan("lobby".to_string(), false).unwrap();
+        let (alice, _) = login(&mut svc, "c1", "alice");
+        let (bob, _) = login(&mut svc, "c2", "bob");
+        svc.post_msg(alice.clone(), lobby.clone(), "seen".to_string(), None).unwrap();
+        svc.mark_read(bob.clone(), lobby.clone(), None).unwrap();
+        svc.post_msg(alice.clone(), lobby.clone(), "unseen".to_string(), None).unwrap();
+        drop(svc);
+
+        let mut svc = service();
+        svc.load_storage(Box::new(crate::storage::WalStorage::open(&path).unwrap())).unwrap();
+        assert_eq!(svc.unread_count(&bob, &lobby), 1);
+        assert_eq!(svc.unread_count(&alice, &lobby), 0);
+    }
+
+    #[test]
+    fn snapshots_round_trip_through_a_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("chat.snapshot");
+        let mut svc = service();
+        let (alice, _) = login(&mut svc, "c1", "alice");
+        let (bob, _) = login(&mut svc, "c2", "bob");
+        let room = svc.create_named_chan(alice.clone(), "room".to_string()).unwrap();
+        svc.join_chan(bob.clone(), room.clone()).unwrap();
+        svc.add_moderator(alice.clone(), room.clone(), "bob".to_string()).unwrap();
+        svc.post_msg(alice.clone(), room.clone(), "read".to_string(), None).unwrap();
+        let msg_id = last_msg_id(&svc, &room);
+        svc.react(bob.clone(), room.clone(), msg_id.clone(), "+1".to_string(), true).unwrap();
+        svc.mark_read(bob.clone(), room.clone(), None).unwrap();
+        svc.post_msg(alice.clone(), room.clone(), "unread".to_string(), None).unwrap();
+        svc.snapshot().unwrap().save(&path).unwrap();
+
+        let mut restored = service();
+        restored.restore(Snapshot::load(&path).unwrap()).unwrap();
+        assert_eq!(restored.resolve_chan("#room".to_string()).unwrap(), room);
+        assert!(restored.is_user_sub(&bob, &room));
+        let chan = restored.channels.get_mut(&room).unwrap();
+        assert!(chan.is_moderator(&bob));
+        assert_eq!(chan.messages[&msg_id].reaction_summary(), "+1 1");
+        assert_eq!(restored.unread_count(&bob, &room), 1);
+        assert!(restored.storage.get_message(&msg_id).unwrap().is_some());
+
+        let (uid, _) = login(&mut restored, "c3", "Bob");
+        assert_eq!(uid, bob);
+    }
+
+    #[test]
+    fn restore_rewrites_the_store_with_the_full_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("chat.db");
+        let mut svc = service();
+        svc.history_size = 2;
+        svc.load_storage(Box::new(crate::storage::SqliteStorage::open(&path).unwrap())).unwrap();
+        let (alice, _) = login(&mut svc, "c1", "alice");
+        let room = svc.create_named_chan(alice.clone(), "room".to_string()).unwrap();
+        for content in ["one", "two", "three"] {
+            svc.post_msg(alice.clone(), room.clone(), content.to_string(), None).unwrap();
+        }
+        let snapshot = svc.snapshot().unwrap();
+        assert_eq!(snapshot.messages.len(), 3);
+
+        let (bob, _) = login(&mut svc, "c2", "bob");
+        svc.join_chan(bob.clone(), room.clone()).unwrap();
+        let later = svc.create_named_chan(bob.clone(), "later".to_string()).unwrap();
+        svc.post_msg(bob.clone(), room.clone(), "four".to_string(), None).unwrap();
+        svc.disconnect("c2");
+        svc.restore(snapshot).unwrap();
+        drop(svc);
+
+        let mut svc = service();
+        svc.load_storage(Box::new(crate::storage::SqliteStorage::open(&path).unwrap())).unwrap();
+        assert!(!svc.users.contains_key(&bob));
+        assert!(!svc.channels.contains_key(&later));
+        assert!(svc.is_user_sub(&alice, &room));
+        let stored = svc.storage.load().unwrap().messages;
+        let contents: Vec<&str> = stored.iter().map(|msg| msg.content.as_str()).collect();
+        assert_eq!(contents, vec!["one", "two", "three"]);
+    }
+
+    #[test]
+    fn failed_restore_keeps_the_running_state() {
+        let (mut svc, fail) = testing::failing_service();
+        let (alice, _) = login(&mut svc, "c1", "alice");
+        let room = svc.create_named_chan(alice.clone(), "room".to_string()).unwrap();
+        let snapshot = svc.snapshot().unwrap();
+        svc.post_msg(alice.clone(), room.clone(), "kept".to_string(), None).unwrap();
+
+        fail.store(true, Ordering::Relaxed);
+        assert!(matches!(svc.restore(snapshot), Err(ChatErrors::Storage(_))));
+        assert!(svc.is_user_sub(&alice, &room));
+        assert_eq!(svc.channels.get(&room).unwrap().messages.len(), 1);
+    }
+
+    #[test]
+    fn history_pages_back_past_the_replay_buffer() {
+        let mut svc = service();
+        svc.history_size = 2;
+        let (alice, mut a) = login(&mut svc, "c1", "alice");
+        let (bob, _) = login(&mut svc, "c2", "bob");
+        let room = svc.create_named_chan(alice.clone(), "room".to_string()).unwrap();
+        for i in 0..5 {
+            svc.post_msg(alice.clone(), room.clone(), format!("msg {}", i), None).unwrap();
+        }
+        drain(&mut a);
+
+        let mut page = |before: Option<String>, limit| {
+            svc.send_history(&alice, room.clone(), before, limit).unwrap();
+            let mut lines = drain(&mut a);
+            let end = lines.pop().unwrap();
+            (lines, end)
+        };
+        let (lines, end) = page(None, 2);
+        assert!(lines[0].ends_with("msg 3") && lines[1].ends_with("msg 4"), "{:?}", lines);
+        let oldest = end.split(' ').nth(2).unwrap().to_string();
+        assert_eq!(end, format!("{}: {} {} 2", HISTORY_END_RESP, room, oldest));
+
+        let (lines, end) = page(Some(oldest), 10);
+        assert_eq!(lines.len(), 3, "{:?}", lines);
+        assert!(lines.iter().all(|line| line.starts_with(HISTORY_RESP)));
+        assert!(lines[0].ends_with("msg 0"), "{:?}", lines);
+        let oldest = end.split(' ').nth(2).unwrap().to_string();
+
+        let (lines, end) = page(Some(oldest), 10);
+        assert!(lines.is_empty());
+        assert_eq!(end, format!("{}: {} - 0", HISTORY_END_RESP, room));
+
+        assert!(matches!(svc.send_history(&bob, room, None, 10), Err(ChatErrors::PermissionDenied(_))));
+    }
+
+    #[test]
+    fn parses_retention_policies() {
+        for policy in ["forever", "days=30", "last=1000"] {
+            assert_eq!(policy.parse::<Retention>().unwrap().to_string(), policy);
+        }
+        for policy in ["", "days=0", "last=-1", "weeks=2", "days"] {
+            assert!(policy.parse::<Retention>().is_err(), "{}", policy);
+        }
+    }
+
+    #[test]
+    fn days_cutoff_sits_between_old_and_new_ids() {
+        let now = Utc::now();
+        let cutoff = Retention::Days(7).cutoff_id(now).unwrap();
+        let old = format!("{:016x}", ((now - chrono::Duration::days(8)).timestamp_millis() as u64) << 16);
+        assert!(old < cutoff && cutoff < gen_msg_id());
+        assert_eq!(Retention::LastN(5).cutoff_id(now), None);
+    }
+
+    #[test]
+    fn prune_drops_expired_messages_everywhere() {
+        let mut svc = service();
+        let (alice, _) = login(&mut svc, "c1", "alice");
+        let (bob, _) = login(&mut svc, "c2", "bob");
+        let room = svc.create_named_chan(alice.clone(), "room".to_string()).unwrap();
+        let hall = svc.create_named_chan(alice.clone(), "hall".to_string()).unwrap();
+        assert!(matches!(
+            svc.set_retention(bob, room.clone(), Retention::LastN(2)),
+            Err(ChatErrors::PermissionDenied(_))
+        ));
+        svc.set_retention(alice.clone(), room.clone(), Retention::LastN(2)).unwrap();
+        svc.set_retention(alice.clone(), hall.clone(), Retention::Days(1)).unwrap();
+
+        for i in 0..4 {
+            svc.post_msg(alice.clone(), room.clone(), format!("note {}", i), None).unwrap();
+        }
+        let mut old = Message::from_user(alice.clone(), "alice".to_string(), hall.clone(), "old note".to_string());
+        old.id = format!("{:016x}", ((Utc::now() - chrono::Duration::days(2)).timestamp_millis() as u64) << 16);
+        svc.storage.put_message(&old).unwrap();
+        svc.search_index.get_mut(&hall).add(&old);
+        svc.channels.get_mut(&hall).unwrap().push_message(old.clone(), 8);
+        svc.post_msg(alice.clone(), hall.clone(), "new note".to_string(), None).unwrap();
+
+        svc.prune_history();
+        let chans = HashSet::from([room.clone(), hall.clone()]);
+        let query = SearchQuery::parse("note", &[]).unwrap();
+        let found: usize = svc.search_index.iter().map(|index| index.search(&query, &chans).count()).sum();
+        assert_eq!(found, 3);
+        let notes = |chan_id: &String| -> Vec<String> {
+            let chan = svc.channels.get(chan_id).unwrap();
+            chan.messages.values().map(|msg| msg.content.clone()).collect()
+        };
+        assert_eq!(notes(&room), ["note 2", "note 3"]);
+        assert_eq!(notes(&hall), ["new note"]);
+        assert_eq!(svc.storage.messages_before(&room, None, 10).unwrap().len(), 2);
+        assert!(svc.storage.get_message(&old.id).unwrap().is_none());
+    }
+
+    #[test]
+    fn search_stops_at_max_results() {
+        let mut svc = service();
+        let (alice, mut a) = login(&mut svc, "c1", "alice");
+        let room = svc.create_named_chan(alice.clone(), "room".to_string()).unwrap();
+        let count = MAX_SEARCH_RESULTS + SEARCH_OVERFETCH + 5;
+        for i in 0..count {
+            svc.post_msg(alice.clone(), room.clone(), format!("note {}", i), None).unwrap();
+        }
+        drain(&mut a);
+
+        svc.search(alice, SearchQuery::parse("note", &[]).unwrap()).unwrap();
+        let lines = drain(&mut a);
+        assert_eq!(lines.len(), MAX_SEARCH_RESULTS + 1);
+        assert!(lines[0].ends_with(&format!("note {}", count - 1)), "{:?}", lines[0]);
+        assert_eq!(lines[MAX_SEARCH_RESULTS], format!("{}: {}", SEARCH_END_RESP, MAX_SEARCH_RESULTS));
+    }
+
+    #[test]
+    fn export_reads_the_whole_history_from_storage() {
+        let mut svc = service();
+        svc.history_size = 2;
+        let (alice, mut a) = login(&mut svc, "c1", "alice");
+        let (bob, _) = login(&mut svc, "c2", "bob");
+        let room = svc.create_named_chan(alice.clone(), "room".to_string()).unwrap();
+        let count = MAX_EXPORT_MSGS + 5;
+        for i in 0..count {
+            svc.post_msg(alice.clone(), room.clone(), format!("msg {}", i), None).unwrap();
+        }
+        drain(&mut a);
+
+        let history = svc.chan_history(&room).unwrap();
+        assert_eq!(history.len(), count);
+        assert!(history.windows(2).all(|pair| pair[0].id < pair[1].id));
+
+        // over the wire, only the newest `MAX_EXPORT_MSGS`
+        svc.export_chan(alice, room.clone(), ExportFormat::Text).unwrap();
+        let lines = drain(&mut a);
+        assert_eq!(lines.len(), MAX_EXPORT_MSGS + 1);
+        assert!(lines[0].starts_with(EXPORT_RESP) && lines[0].ends_with("alice: msg 5"), "{:?}", lines[0]);
+        assert_eq!(
+            lines[MAX_EXPORT_MSGS],
+            format!("{}: {} txt {} {}", EXPORT_END_RESP, room, MAX_EXPORT_MSGS, count)
+        );
+        assert!(matches!(svc.export_chan(bob, room, ExportFormat::Text), Err(ChatErrors::PermissionDenied(_))));
+    }
+
+    #[test]
+    fn direct_messages_and_mentions_wait_for_the_user() {
+        let mut svc = service();
+        let lobby = svc.add_default_chan("lobby".to_string(), false).unwrap();
+        let (alice, _) = login(&mut svc, "c1", "alice");
+        let (bob, _) = login(&mut svc, "c2", "bob");
+        svc.disconnect("c1");
+
+        svc.post_msg(bob.clone(), alice.clone(), "psst".to_string(), None).unwrap();
+        svc.post_msg(bob.clone(), lobby.clone(), "hi @ALICE and @bob".to_string(), None).unwrap();
+        svc.post_msg(bob.clone(), lobby.clone(), "not for alice".to_string(), None).unwrap();
+        assert!(!svc.offline_queues.get(&bob).contains_key(&bob));
+
+        let (_, mut conn) = login(&mut svc, "c3", "Alice");
+        let offline: Vec<String> = drain(&mut conn)
+            .into_iter()
+            .filter(|line| line.starts_with(OFFLINE_RESP))
+            .collect();
+        assert_eq!(offline.len(), 2, "{:?}", offline);
+        assert!(offline[0].starts_with(&format!("{}: {} ", OFFLINE_RESP, alice)) && offline[0].ends_with("bob: psst"));
+        assert!(offline[1].starts_with(&format!("{}: {} @ ", OFFLINE_RESP, lobby)));
+        assert!(svc.offline_queues.iter().all(|queues| queues.is_empty()));
+    }
+
+    #[test]
+    fn queue_all_offline_keeps_the_newest_per_user() {
+        let mut svc = service();
+        svc.queue_all_offline = true;
+        svc.offline_queue_cap = 2;
+        let lobby = svc.add_default_chan("lobby".to_string(), false).unwrap();
+        login(&mut svc, "c1", "alice");
+        let (bob, _) = login(&mut svc, "c2", "bob");
+        svc.disconnect("c1");
+
+        for n in 0..3 {
+            svc.post_msg(bob.clone(), lobby.clone(), format!("msg {} for @alice", n), None).unwrap();
+        }
+        let (_, mut conn) = login(&mut svc, "c3", "alice");
+        let lines = drain(&mut conn);
+        let at = lines.iter().position(|line| line.starts_with(OFFLINE_OVERFLOW_RESP)).unwrap();
+        assert_eq!(lines[at], format!("{}: 1", OFFLINE_OVERFLOW_RESP));
+        assert!(lines[at + 1].ends_with("msg 1 for @alice"));
+        assert!(lines[at + 2].ends_with("msg 2 for @alice"));
+        assert_eq!(lines.len(), at + 3);
+    }
+
+    #[test]
+    fn offline_queues_survive_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("chat.wal");
+        let mut svc = service();
+        svc.load_storage(Box::new(crate::storage::WalStorage::open(&path).unwrap())).unwrap();
+        let lobby = svc.add_default_chan("lobby".to_string(), false).unwrap();
+        let (alice, _) = login(&mut svc, "c1", "alice");
+        login(&mut svc, "c2", "bob");
+        svc.disconnect("c2");
+        svc.post_msg(alice.clone(), lobby.clone(), "unseen @bob".to_string(), None).unwrap();
+        drop(svc);
+
+        let mut svc = service();
+        svc.load_storage(Box::new(crate::storage::WalStorage::open(&path).unwrap())).unwrap();
+        let (_, mut conn) = login(&mut svc, "c3", "bob");
+        let lines = drain(&mut conn);
+        assert!(lines.iter().any(|line| line.starts_with(OFFLINE_RESP) && line.ends_with("alice: unseen @bob")), "{:?}", lines);
+        assert!(svc.offline_queues.iter().all(|queues| queues.is_empty()));
+        drop(svc);
+
+        let state = crate::storage::WalStorage::open(&path).unwrap().load().unwrap();
+        assert!(state.offline_queues.is_empty());
+    }
+
+    #[test]
+    fn posts_reach_only_the_members_outboxes() {
+        let mut svc = service();
+        let (alice, mut a) = login(&mut svc, "c1", "alice");
+        let (bob, mut b) = login(&mut svc, "c2", "bob");
+        let (_, mut c) = login(&mut svc, "c3", "carol");
+        let room = svc.create_named_chan(alice.clone(), "room".to_string()).unwrap();
+        svc.join_chan(bob.clone(), room.clone()).unwrap();
+        drain(&mut a);
+        drain(&mut b);
+        drain(&mut c);
+
+        svc.post_msg(alice.clone(), room.clone(), "hi".to_string(), None).unwrap();
+        assert!(drain(&mut a)[0].ends_with("alice: hi"));
+        assert!(drain(&mut b)[0].ends_with("alice: hi"));
+        assert!(drain(&mut c).is_empty());
+        assert_eq!(svc.chan_users[&room].len(), 2);
+
+        svc.leave_chan(bob.clone(), room.clone()).unwrap();
+        svc.post_msg(alice.clone(), room.clone(), "still there?".to_string(), None).unwrap();
+        assert!(!drain(&mut b).iter().any(|line| line.ends_with("still there?")));
+
+        svc.join_chan(bob, room.clone()).unwrap();
+        svc.disconnect("c2");
+        drain(&mut b);
+        svc.post_msg(alice, room, "gone".to_string(), None).unwrap();
+        assert!(drain(&mut b).is_empty());
+    }
+
+    #[test]
+    fn posts_only_lock_their_chan() {
+        let mut svc = service();
+        let (alice, _a) = login(&mut svc, "c1", "alice");
+        let (bob, _b) = login(&mut svc, "c2", "bob");
+        let one = svc.create_named_chan(alice.clone(), "one".to_string()).unwrap();
+        let two = svc.create_named_chan(bob.clone(), "two".to_string()).unwrap();
+
+        let busy = svc.channels.get(&one).unwrap();
+        svc.post_msg(bob.clone(), two.clone(), "not blocked".to_string(), None).unwrap();
+        drop(busy);
+
+        let svc = &svc;
+        std::thread::scope(|scope| {
+            for (uid, chan_id) in [(&alice, &one), (&bob, &two)] {
+                scope.spawn(move || {
+                    for i in 0..50 {
+                        svc.post_msg(uid.clone(), chan_id.clone(), format!("msg {}", i), None).unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(svc.channels.get(&one).unwrap().messages.len(), 50);
+        assert_eq!(svc.channels.get(&two).unwrap().messages.len(), 51);
+    }
 }
@@ -0,0 +1,69 @@
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// Shards of the service wide maps, enough that posts to different chans
+/// rarely meet on one.
+pub const SHARDS: usize = 16;
+
+/// A map split into shards by key, each behind its own lock, so callers
+/// holding the service shared only wait on others touching the same shard.
+/// Like `ChanMap`, holding the service exclusively reaches them without
+/// locking.
+///
+/// Never lock a second shard while holding a guard.
+#[derive(Debug)]
+pub struct Shards<T> {
+    shards: Vec<Mutex<T>>,
+}
+
+impl<T> Shards<T> {
+    pub fn new(count: usize, mut init: impl FnMut() -> T) -> Self {
+        (0..count.max(1)).map(|_| init()).collect::<Vec<_>>().into()
+    }
+
+    /// Lock the shard `key` falls in.
+    pub fn get<K: Hash + ?Sized>(&self, key: &K) -> MutexGuard<'_, T> {
+        lock(&self.shards[self.pick(key)])
+    }
+
+    pub fn get_mut<K: Hash + ?Sized>(&mut self, key: &K) -> &mut T {
+        let i = self.pick(key);
+        self.shards[i].get_mut().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Lock each shard in turn, one at a time.
+    pub fn iter(&self) -> impl Iterator<Item = MutexGuard<'_, T>> {
+        self.shards.iter().map(lock)
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.shards
+            .iter_mut()
+            .map(|shard| shard.get_mut().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    fn pick<K: Hash + ?Sized>(&self, key: &K) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() % self.shards.len() as u64) as usize
+    }
+}
+
+impl<T: Default> Default for Shards<T> {
+    fn default() -> Self {
+        Self::new(SHARDS, T::default)
+    }
+}
+
+impl<T> From<Vec<T>> for Shards<T> {
+    fn from(shards: Vec<T>) -> Self {
+        assert!(!shards.is_empty(), "no shards");
+        Self {
+            shards: shards.into_iter().map(Mutex::new).collect(),
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
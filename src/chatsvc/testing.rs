@@ -0,0 +1,150 @@
+//! Fixture shared by the service and handler tests: users logged in on a
+//! service, and the lines each of their connections would get.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use super::{Channel, ChatService, Message, Outbox, Retention, UserInfo};
+use crate::errors::ChatErrors;
+use crate::storage::{MemoryStorage, Storage, StoredState};
+
+/// What a connection's writer reads, see `write_outbox`.
+pub struct Inbox {
+    outbox: Outbox,
+}
+
+pub fn service() -> ChatService {
+    ChatService::new(8)
+}
+
+/// A service whose storage fails every write once the returned flag is set.
+pub fn failing_service() -> (ChatService, Arc<AtomicBool>) {
+    let fail = Arc::new(AtomicBool::new(false));
+    let mut svc = service();
+    svc.load_storage(Box::new(FailingStorage {
+        inner: MemoryStorage::new(),
+        fail: fail.clone(),
+    }))
+    .unwrap();
+    (svc, fail)
+}
+
+thread_local! {
+    // issued to the names the running test claimed, by lowercase name
+    static TOKENS: RefCell<HashMap<String, String>> = RefCell::new(HashMap::new());
+}
+
+/// Log `name` in on the conn `conn_id`, returning the user's uid and inbox.
+/// Logging a name in again uses the token it was issued.
+pub fn login(svc: &mut ChatService, conn_id: &str, name: &str) -> (String, Inbox) {
+    let inbox = connect(svc, conn_id);
+    let key = name.to_lowercase();
+    let token = TOKENS.with_borrow(|tokens| tokens.get(&key).cloned());
+    let (uid, issued) = svc.login(conn_id.to_string(), name.to_string(), token).unwrap();
+    if let Some(issued) = issued {
+        TOKENS.with_borrow_mut(|tokens| tokens.insert(key, issued));
+    }
+    (uid, inbox)
+}
+
+/// A connection that is not logged in yet.
+pub fn connect(svc: &mut ChatService, conn_id: &str) -> Inbox {
+    Inbox {
+        outbox: svc.connect(conn_id.to_string()),
+    }
+}
+
+/// The lines queued for the conn since the last drain.
+pub fn drain(inbox: &mut Inbox) -> Vec<String> {
+    let mut lines = Vec::new();
+    while let Ok(msg) = inbox.outbox.rx.try_recv() {
+        lines.push(msg.to_string());
+    }
+    lines
+}
+
+struct FailingStorage {
+    inner: MemoryStorage,
+    fail: Arc<AtomicBool>,
+}
+
+impl FailingStorage {
+    fn write(&self) -> Result<&MemoryStorage, ChatErrors> {
+        if self.fail.load(Ordering::Relaxed) {
+            return Err(ChatErrors::Storage("disk full".to_string()));
+        }
+        Ok(&self.inner)
+    }
+}
+
+impl Storage for FailingStorage {
+    fn load(&self) -> Result<StoredState, ChatErrors> {
+        self.inner.load()
+    }
+
+    fn clear(&self) -> Result<(), ChatErrors> {
+        self.write()?.clear()
+    }
+
+    fn put_user(&self, user: &UserInfo) -> Result<(), ChatErrors> {
+        self.write()?.put_user(user)
+    }
+
+    fn put_channel(&self, chan: &Channel) -> Result<(), ChatErrors> {
+        self.write()?.put_channel(chan)
+    }
+
+    fn remove_channel(&self, chan_id: &str) -> Result<(), ChatErrors> {
+        self.write()?.remove_channel(chan_id)
+    }
+
+    fn put_member(&self, uid: &str, chan_id: &str) -> Result<(), ChatErrors> {
+        self.write()?.put_member(uid, chan_id)
+    }
+
+    fn remove_member(&self, uid: &str, chan_id: &str) -> Result<(), ChatErrors> {
+        self.write()?.remove_member(uid, chan_id)
+    }
+
+    fn put_read_marker(&self, uid: &str, chan_id: &str, msg_id: &str) -> Result<(), ChatErrors> {
+        self.write()?.put_read_marker(uid, chan_id, msg_id)
+    }
+
+    fn push_offline(&self, uid: &str, line: &str, keep: usize) -> Result<(), ChatErrors> {
+        self.write()?.push_offline(uid, line, keep)
+    }
+
+    fn remove_offline(&self, uid: &str) -> Result<(), ChatErrors> {
+        self.write()?.remove_offline(uid)
+    }
+
+    fn put_message(&self, msg: &Message) -> Result<(), ChatErrors> {
+        self.write()?.put_message(msg)
+    }
+
+    fn get_message(&self, msg_id: &str) -> Result<Option<Message>, ChatErrors> {
+        self.inner.get_message(msg_id)
+    }
+
+    fn thread_replies(&self, chan_id: &str, parent_id: &str) -> Result<Vec<Message>, ChatErrors> {
+        self.inner.thread_replies(chan_id, parent_id)
+    }
+
+    fn prune_messages(&self, chan_id: &str, retention: Retention) -> Result<Vec<String>, ChatErrors> {
+        self.write()?.prune_messages(chan_id, retention)
+    }
+
+    fn messages_before(&self, chan_id: &str, before: Option<&str>, limit: usize) -> Result<Vec<Message>, ChatErrors> {
+        self.inner.messages_before(chan_id, before, limit)
+    }
+
+    fn count_messages(&self, chan_id: &str) -> Result<usize, ChatErrors> {
+        self.inner.count_messages(chan_id)
+    }
+
+    fn count_unread(&self, chan_id: &str, after: Option<&str>, uid: &str) -> Result<usize, ChatErrors> {
+        self.inner.count_unread(chan_id, after, uid)
+    }
+}
@@ -1,22 +1,55 @@
 use anyhow::Result;
 use futures::{SinkExt, StreamExt};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::sync::Arc;
-use tokio::io::{AsyncBufReadExt, BufReader};
+use std::time::{Duration, Instant};
 use tokio::net::TcpStream;
 use tokio::net::tcp::OwnedWriteHalf;
 use tokio::sync::RwLock;
 use tokio_util::codec::{FramedRead, FramedWrite, LinesCodec};
 use tracing::{info, level_filters::LevelFilter, warn};
 use tracing_subscriber::{Layer as _, fmt::Layer, layer::SubscriberExt, util::SubscriberInitExt};
-use txt_chat::chatsvc::{CREATE_CHAN_RESP, JOIN_RESP, LEAVE_RESP};
+use txt_chat::chatsvc::{
+    ARCHIVE_CHAN_RESP, AUTO_JOIN_RESP, CREATE_CHAN_RESP, DELETE_CHAN_RESP, DELETE_MSG_RESP,
+    EDIT_MSG_RESP, ERROR_RESP, EXPORT_END_RESP, EXPORT_RESP, HISTORY_END_RESP, HISTORY_RESP, JOIN_RESP, LAGGED_RESP, OFFLINE_OVERFLOW_RESP, OFFLINE_RESP, SEARCH_END_RESP, SEARCH_RESP, LEAVE_RESP, MENTION_RESP, PING_INTERVAL, REACTIONS_RESP, REPLAY_RESP, REPLIES_RESP, SHUTDOWN_RESP, THREAD_MSG_RESP, THREAD_RESP, TOKEN_RESP, TYPING_RESP, UNREAD_RESP,
+};
 use txt_chat::errors::ChatErrors;
+use txt_chat::export::ExportFormat;
 
-const JOIN: &'static str = "$join";
-const SWITCH: &'static str = "$switch";
-const LEAVE: &'static str = "$leave";
-const CREATE_CHAN: &'static str = "$create_chan";
+use crate::input::{Console, Input, InputReader};
+
+mod input;
+
+const JOIN: &str = "$join";
+const SWITCH: &str = "$switch";
+const LEAVE: &str = "$leave";
+const CREATE_CHAN: &str = "$create_chan";
+const DELETE_CHAN: &str = "$delete";
+const ARCHIVE_CHAN: &str = "$archive";
+const ADD_MOD: &str = "$mod";
+const SLOW_MODE: &str = "$slow";
+const RATE_LIMIT: &str = "$rate";
+const READ_ONLY: &str = "$read_only";
+const RETENTION: &str = "$retention";
+const EDIT_MSG: &str = "$edit";
+const DELETE_MSG: &str = "$delete_msg";
+const REPLY: &str = "$reply";
+const THREAD: &str = "$thread";
+const REACT: &str = "$react";
+const UNREACT: &str = "$unreact";
+const UNREAD: &str = "$unread";
+const SNAPSHOT: &str = "$snapshot";
+const STATS: &str = "$stats";
+const HISTORY: &str = "$history";
+const SEARCH: &str = "$search";
+const EXPORT: &str = "$export";
+
+// messages fetched by `$history` without a count
+const HISTORY_PAGE: usize = 20;
+
+// how often to tell the server we are still typing
+const TYPING_INTERVAL: Duration = Duration::from_secs(3);
 
 pub struct ClientState {
     pub user_id: String,
@@ -24,6 +57,10 @@ pub struct ClientState {
     pub user_chan: String,    // automatic created chan after user register
     pub current_chan: String, // current chan id
     pub joined_chans: HashSet<String>,
+    pub chan_names: HashMap<String, String>, // lowercase chan name -> chan id
+    pub unread: HashMap<String, usize>,      // chan id -> unread messages, except current chan
+    pub history_before: HashMap<String, String>, // chan id -> oldest msg id seen, `$history` goes back from it
+    pub export: Option<(String, Vec<String>)>,   // file the pending `$export` goes to, lines received so far
 }
 
 impl ClientState {
@@ -34,6 +71,10 @@ impl ClientState {
             user_chan: "".to_string(),
             current_chan: "".to_string(),
             joined_chans: HashSet::new(),
+            chan_names: HashMap::new(),
+            unread: HashMap::new(),
+            history_before: HashMap::new(),
+            export: None,
         }
     }
 
@@ -45,6 +86,21 @@ impl ClientState {
         self.joined_chans.insert(chan_id);
     }
 
+    pub fn name_chan(&mut self, chan_id: String, name: Option<String>) {
+        if let Some(name) = name {
+            self.chan_names.insert(name.to_lowercase(), chan_id);
+        }
+    }
+
+    // `#name` is looked up in the names learned from server responses,
+    // anything else is taken as a chan id
+    pub fn resolve_chan(&self, chan: &str) -> Option<String> {
+        match chan.strip_prefix('#') {
+            Some(name) => self.chan_names.get(&name.to_lowercase()).cloned(),
+            None => Some(chan.to_string()),
+        }
+    }
+
     pub fn leave_chan(&mut self, chan_id: String) {
         self.joined_chans.remove(&chan_id);
         if self.current_chan == chan_id {
@@ -54,14 +110,19 @@ impl ClientState {
     }
 
     pub fn switch_chan(&mut self, new_chan: String) {
+        self.unread.remove(&new_chan);
         self.current_chan = new_chan.clone();
         self.append_chan(new_chan.clone());
         info!("switched to channel: {}", new_chan);
     }
 }
 
-fn encode_reg(uname: String) -> String {
-    format!("reg${}", uname)
+// reg$alice, or reg$alice${token} once the name is ours
+fn encode_reg(uname: String, token: Option<&String>) -> String {
+    match token {
+        Some(token) => format!("reg${}${}", uname, token),
+        None => format!("reg${}", uname),
+    }
 }
 
 // join$123$456
@@ -79,6 +140,102 @@ fn encode_create_chan(state: &ClientState, chan_name: String) -> String {
     format!("create_chan${}${}", state.user_id, chan_name)
 }
 
+// delete_chan$123$456
+fn encode_delete_chan(state: &ClientState, chan_id: String) -> String {
+    format!("delete_chan${}${}", state.user_id, chan_id)
+}
+
+// archive_chan$123$456
+fn encode_archive_chan(state: &ClientState, chan_id: String) -> String {
+    format!("archive_chan${}${}", state.user_id, chan_id)
+}
+
+// add_mod$123$456$bob, slow_mode$123$456$5, rate_limit$123$456$10, read_only$123$456$on
+fn encode_mod_cmd(state: &ClientState, cmd: &str, chan_id: String, arg: String) -> String {
+    format!("{}${}${}${}", cmd, state.user_id, chan_id, arg)
+}
+
+// edit_msg${uid}${chan_id}${msg_id}$Hello
+fn encode_edit_msg(state: &ClientState, msg_id: String, msg: String) -> String {
+    format!("edit_msg${}${}${}${}", state.user_id, state.current_chan, msg_id, msg)
+}
+
+// delete_msg${uid}${chan_id}${msg_id}
+fn encode_delete_msg(state: &ClientState, msg_id: String) -> String {
+    format!("delete_msg${}${}${}", state.user_id, state.current_chan, msg_id)
+}
+
+// thread${uid}${chan_id}${parent_id}
+fn encode_thread(state: &ClientState, parent_id: String) -> String {
+    format!("thread${}${}${}", state.user_id, state.current_chan, parent_id)
+}
+
+// react${uid}${chan_id}${msg_id}$:+1: / unreact${uid}${chan_id}${msg_id}$:+1:
+fn encode_react(state: &ClientState, cmd: &str, msg_id: String, reaction: String) -> String {
+    format!("{}${}${}${}${}", cmd, state.user_id, state.current_chan, msg_id, reaction)
+}
+
+// mark_read${uid}${chan_id}
+fn encode_mark_read(state: &ClientState) -> String {
+    format!("mark_read${}${}", state.user_id, state.current_chan)
+}
+
+// unread${uid}
+fn encode_unread(state: &ClientState) -> String {
+    format!("unread${}", state.user_id)
+}
+
+// history${uid}${chan_id}${before_msg_id}${limit}
+fn encode_history(state: &ClientState, limit: usize) -> String {
+    let before = state.history_before.get(&state.current_chan).cloned().unwrap_or_default();
+    format!("history${}${}${}${}", state.user_id, state.current_chan, before, limit)
+}
+
+// `$search some link in:#general from:alice after:2025-10-01` ->
+// search${uid}$some link$in=#general$from=alice$after=2025-10-01
+fn encode_search(state: &ClientState, query: &str) -> String {
+    let mut terms = Vec::new();
+    let mut filters = Vec::new();
+    for word in query.split_whitespace() {
+        match word.split_once(':') {
+            Some((key @ ("in" | "from" | "after" | "before"), value)) => filters.push(format!("{}={}", key, value)),
+            _ => terms.push(word),
+        }
+    }
+
+    let mut msg = format!("search${}${}", state.user_id, terms.join(" "));
+    for filter in filters {
+        msg.push('$');
+        msg.push_str(&filter);
+    }
+    msg
+}
+
+// export${uid}${chan_id}${format}
+fn encode_export(state: &ClientState, chan: &str, format: ExportFormat) -> String {
+    format!("export${}${}${}", state.user_id, chan, format)
+}
+
+// snapshot${uid}, admin only
+fn encode_snapshot(state: &ClientState) -> String {
+    format!("snapshot${}", state.user_id)
+}
+
+// stats${uid}, admin only
+fn encode_stats(state: &ClientState) -> String {
+    format!("stats${}", state.user_id)
+}
+
+// typing${uid}${chan_id}
+fn encode_typing(state: &ClientState) -> String {
+    format!("typing${}${}", state.user_id, state.current_chan)
+}
+
+// reply${uid}${chan_id}${parent_id}$Hello
+fn encode_reply(state: &ClientState, parent_id: String, msg: String) -> String {
+    format!("reply${}${}${}${}", state.user_id, state.current_chan, parent_id, msg)
+}
+
 // send_msg${uid}${chan_id}$Hello
 fn encode_send_msg(state: &ClientState, msg: String) -> Result<String, ChatErrors> {
     if state.current_chan.is_empty() {
@@ -93,15 +250,18 @@ fn encode_send_msg(state: &ClientState, msg: String) -> Result<String, ChatError
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let layer = Layer::new().with_filter(LevelFilter::INFO);
+    // logs and server lines print above the line being typed
+    let console = Console::new();
+    let log_console = console.clone();
+    let layer = Layer::new()
+        .with_writer(move || log_console.writer())
+        .with_filter(LevelFilter::INFO);
     tracing_subscriber::registry().with(layer).init();
 
     // Connect to the server
     let stream = TcpStream::connect("0.0.0.0:9090").await?;
     info!("Connected to 0.0.0.0:9090");
 
-    let user_id = format!("{:?}", stream.local_addr()?);
-
     // Split the stream into read and write halves
     let (read_half, write_half) = stream.into_split();
 
@@ -111,12 +271,14 @@ async fn main() -> Result<()> {
 
     let args: Vec<String> = env::args().collect();
     let user_name = &args[1];
+    // issued by the server the first time the name is used
+    let token = args.get(2);
 
     if let Some(Ok(welcome)) = framed_read.next().await {
         info!("{}", welcome);
     }
 
-    match framed_write.send(encode_reg(user_name.to_string())).await {
+    match framed_write.send(encode_reg(user_name.to_string(), token)).await {
         Ok(_) => {}
         Err(e) => {
             warn!("register user failed: {}", e);
@@ -124,9 +286,16 @@ async fn main() -> Result<()> {
         }
     }
 
-    let state = Arc::new(RwLock::new(ClientState::new(user_id)));
+    // the server answers with our uid, which is also our personal chan
+    let state = Arc::new(RwLock::new(ClientState::new(String::new())));
     if let Some(Ok(cur_chan)) = framed_read.next().await {
+        if cur_chan.starts_with(ERROR_RESP) {
+            warn!("register user failed: {}", cur_chan);
+            return Ok(());
+        }
         let mut state = state.write().await;
+        state.set_uid(cur_chan.clone());
+        state.user_chan = cur_chan.clone();
         state.current_chan = cur_chan;
         state.username = user_name.to_string();
     } else {
@@ -135,33 +304,145 @@ async fn main() -> Result<()> {
     }
     let state_clone = state.clone();
     let state_clone1 = state.clone();
+    let input_console = console.clone();
 
     // Spawn a task to handle sending lines from stdin
     let send_task = tokio::spawn(async move {
-        let stdin = BufReader::new(tokio::io::stdin());
-        let mut lines = stdin.lines();
+        let mut input = InputReader::new(input_console);
+        let mut last_typing: Option<Instant> = None;
+        // a user only reading still sends something before the server's idle timeout
+        let mut ping = tokio::time::interval_at(tokio::time::Instant::now() + PING_INTERVAL, PING_INTERVAL);
 
         loop {
-            let line_res = lines.next_line().await;
-            match line_res {
-                Ok(Some(line)) => {
+            let next = tokio::select! {
+                next = input.next() => next,
+                _ = ping.tick() => {
+                    if framed_write.send("ping".to_string()).await.is_err() {
+                        warn!("Failed to send ping");
+                        break;
+                    }
+                    continue;
+                }
+            };
+            match next {
+                Input::Typing => {
+                    if last_typing.is_some_and(|at| at.elapsed() < TYPING_INTERVAL) {
+                        continue;
+                    }
+
+                    let state = state_clone.read().await;
+                    if state.current_chan.is_empty() {
+                        continue;
+                    }
+                    if framed_write.send(encode_typing(&state)).await.is_err() {
+                        warn!("Failed to send typing");
+                        break;
+                    }
+                    last_typing = Some(Instant::now());
+                }
+                Input::Line(line) => {
+                    last_typing = None;
                     if line.trim().is_empty() {
                         continue;
                     }
 
-                    if let Ok(Some(chan_id)) = check_switch_chan(line.clone()) {
+                    if let Ok(Some(chan)) = check_switch_chan(line.clone()) {
                         let mut state = state_clone.write().await;
+                        let Some(chan_id) = state.resolve_chan(&chan) else {
+                            warn!("unknown chan: {}, please join it first", chan);
+                            continue;
+                        };
                         if !state.joined_chans.contains(&chan_id) {
                             warn!("you have not joined chan: {}, please join it first", chan_id);
                             continue;
                         }
 
                         state.switch_chan(chan_id.clone());
+                        if framed_write.send(encode_mark_read(&state)).await.is_err() {
+                            warn!("Failed to send line");
+                            break;
+                        }
                         continue;
                     }
 
                     let state = state_clone.read().await;
 
+                    if line.trim() == UNREAD {
+                        if framed_write.send(encode_unread(&state)).await.is_err() {
+                            warn!("Failed to send line");
+                            break;
+                        }
+                        continue;
+                    }
+
+                    if let Some(limit) = line.trim().strip_prefix(HISTORY) {
+                        let limit = match limit.trim() {
+                            "" => HISTORY_PAGE,
+                            n => match n.parse() {
+                                Ok(n) => n,
+                                Err(_) => {
+                                    warn!("usage: {} [count]", HISTORY);
+                                    continue;
+                                }
+                            },
+                        };
+                        if framed_write.send(encode_history(&state, limit)).await.is_err() {
+                            warn!("Failed to send line");
+                            break;
+                        }
+                        continue;
+                    }
+
+                    if let Some(query) = line.trim().strip_prefix(SEARCH) {
+                        if query.trim().is_empty() {
+                            warn!("usage: {} <keywords> [in:#chan] [from:user] [after:YYYY-MM-DD] [before:YYYY-MM-DD]", SEARCH);
+                            continue;
+                        }
+                        if framed_write.send(encode_search(&state, query)).await.is_err() {
+                            warn!("Failed to send line");
+                            break;
+                        }
+                        continue;
+                    }
+
+                    // $export <chan> <jsonl|md|txt> [file]
+                    if let Some(args) = line.trim().strip_prefix(EXPORT) {
+                        let args: Vec<&str> = args.split_whitespace().collect();
+                        let (Some(chan), Some(Ok(format))) = (args.first(), args.get(1).map(|f| f.parse::<ExportFormat>())) else {
+                            warn!("usage: {} <chan> <jsonl|md|txt> [file]", EXPORT);
+                            continue;
+                        };
+                        let path = args.get(2).map_or_else(
+                            || format!("{}.{}", chan.trim_start_matches('#'), format.extension()),
+                            |path| path.to_string(),
+                        );
+
+                        let msg = encode_export(&state, chan, format);
+                        drop(state);
+                        state_clone.write().await.export = Some((path, Vec::new()));
+                        if framed_write.send(msg).await.is_err() {
+                            warn!("Failed to send line");
+                            break;
+                        }
+                        continue;
+                    }
+
+                    if line.trim() == SNAPSHOT {
+                        if framed_write.send(encode_snapshot(&state)).await.is_err() {
+                            warn!("Failed to send line");
+                            break;
+                        }
+                        continue;
+                    }
+
+                    if line.trim() == STATS {
+                        if framed_write.send(encode_stats(&state)).await.is_err() {
+                            warn!("Failed to send line");
+                            break;
+                        }
+                        continue;
+                    }
+
                     if let Ok(Some(msg)) = check_leave_cmd_and_encode_msg(line.clone(), &state) {
                         if framed_write.send(msg).await.is_err() {
                             warn!("Failed to send line");
@@ -186,6 +467,51 @@ async fn main() -> Result<()> {
                         continue;
                     }
 
+                    match check_msg_cmd_and_encode_msg(line.clone(), &state) {
+                        Ok(Some(msg)) => {
+                            if framed_write.send(msg).await.is_err() {
+                                warn!("Failed to send line");
+                                break;
+                            }
+                            continue;
+                        }
+                        Ok(None) => {}
+                        Err(e) => {
+                            warn!("{}", e);
+                            continue;
+                        }
+                    }
+
+                    match check_mod_cmd_and_encode_msg(line.clone(), &state) {
+                        Ok(Some(msg)) => {
+                            if framed_write.send(msg).await.is_err() {
+                                warn!("Failed to send line");
+                                break;
+                            }
+                            continue;
+                        }
+                        Ok(None) => {}
+                        Err(e) => {
+                            warn!("{}", e);
+                            continue;
+                        }
+                    }
+
+                    match check_owner_cmd_and_encode_msg(line.clone(), &state) {
+                        Ok(Some(msg)) => {
+                            if framed_write.send(msg).await.is_err() {
+                                warn!("Failed to send line");
+                                break;
+                            }
+                            continue;
+                        }
+                        Ok(None) => {}
+                        Err(e) => {
+                            warn!("{}", e);
+                            continue;
+                        }
+                    }
+
                     if let Ok(msg) = encode_send_msg(&state, line) {
                         if framed_write.send(msg).await.is_err() {
                             warn!("Failed to send line");
@@ -195,7 +521,7 @@ async fn main() -> Result<()> {
                         warn!("you have not set current chan yet, please join a chan first");
                     }
                 }
-                Ok(None) | Err(_) => break,
+                Input::Eof => break,
             }
         }
 
@@ -212,11 +538,102 @@ async fn main() -> Result<()> {
     while let Some(line_result) = framed_read.next().await {
         match line_result {
             Ok(line) => {
-                println!(">> {}", line);
-
                 let mut state = state_clone1.write().await;
+                if let Ok(typing) = parse_resp(&line, TYPING_RESP) {
+                    // ephemeral, only shown for the chan we are looking at
+                    if let Some((chan_id, name)) = typing.split_once(' ')
+                        && chan_id == state.current_chan
+                        && name != state.username
+                    {
+                        console.print(&format!(".. {} is typing…", name));
+                    }
+                    continue;
+                }
+
+                // transcript lines are collected, not printed
+                if let Ok(export_line) = parse_resp(&line, EXPORT_RESP) {
+                    if let Some((_, lines)) = state.export.as_mut() {
+                        lines.push(export_line);
+                    }
+                    continue;
+                }
+
+                if let Ok(end) = parse_resp(&line, EXPORT_END_RESP) {
+                    if let Some((path, lines)) = state.export.take() {
+                        let counts: Vec<&str> = end.split(' ').skip(2).collect();
+                        match std::fs::write(&path, lines.join("\n") + "\n") {
+                            Ok(()) => console.print(&format!(">> exported {} to {}", counts.join(" of ") + " messages", path)),
+                            Err(e) => warn!("failed to write export to {}: {}", path, e),
+                        }
+                    }
+                    continue;
+                }
+
+                if let Ok(end) = parse_resp(&line, HISTORY_END_RESP) {
+                    let parts: Vec<&str> = end.split(' ').collect();
+                    if let [chan_id, oldest, count] = parts[..] {
+                        if oldest != "-" {
+                            state.history_before.insert(chan_id.to_string(), oldest.to_string());
+                        }
+                        if count == "0" {
+                            console.print(">> (no older messages)");
+                        }
+                    }
+                    continue;
+                }
+
+                // scrolling back starts right before the first replayed message
+                if let Ok(replayed) = parse_resp(&line, REPLAY_RESP)
+                    && let Some(chan_id) = parse_msg_chan(&replayed).and_then(|name| state.resolve_chan(&name))
+                    && let Some(msg_id) = replayed.strip_prefix('[').and_then(|r| r.split_once(']'))
+                {
+                    state.history_before.entry(chan_id).or_insert(msg_id.0.to_string());
+                }
+
+                let mut rendered = render_line(&line);
+                if let Some(chan_id) = parse_msg_chan(&line).and_then(|name| state.resolve_chan(&name))
+                    && chan_id != state.current_chan
+                {
+                    let count = state.unread.entry(chan_id).or_default();
+                    *count += 1;
+                    rendered = format!("({} unread) {}", count, rendered);
+                }
+
+                if let Ok(unread) = parse_resp(&line, UNREAD_RESP)
+                    && let Some((label, count)) = unread.rsplit_once(' ')
+                {
+                    let (chan_id, _) = split_chan_label(label);
+                    match count.parse::<usize>() {
+                        Ok(count) if count > 0 && chan_id != state.current_chan => {
+                            state.unread.insert(chan_id, count);
+                        }
+                        _ => {
+                            state.unread.remove(&chan_id);
+                        }
+                    }
+                }
+
+                if mentions_user(&line, &state.username) {
+                    console.print(&format!(">> \x1b[1;33m{}\x1b[0m", rendered));
+                } else {
+                    console.print(&format!(">> {}", rendered));
+                }
+
                 if let Ok(joined_chan) = parse_join_resp(&line) {
-                    state.switch_chan(joined_chan);
+                    let (chan_id, name) = split_chan_label(&joined_chan);
+                    state.name_chan(chan_id.clone(), name);
+                    state.switch_chan(chan_id);
+                }
+
+                if let Ok(auto_joined) = parse_auto_join_resp(&line) {
+                    let (chan_id, name) = split_chan_label(&auto_joined);
+                    state.name_chan(chan_id.clone(), name);
+                    // land in the first default chan instead of the personal one
+                    if state.current_chan == state.user_chan {
+                        state.switch_chan(chan_id);
+                    } else {
+                        state.append_chan(chan_id);
+                    }
                 }
 
                 if let Ok(leaved_chan) = parse_leave_resp(&line) {
@@ -224,12 +641,28 @@ async fn main() -> Result<()> {
                 }
 
                 if let Ok(created_chan) = parse_create_chan_resp(&line) {
-                    state.append_chan(created_chan.clone());
+                    let (chan_id, name) = split_chan_label(&created_chan);
+                    state.name_chan(chan_id.clone(), name);
+                    state.append_chan(chan_id);
                     info!("created chan: {} and joined it", created_chan);
                 }
+
+                if let Ok(deleted_chan) = parse_delete_chan_resp(&line) {
+                    state.chan_names.retain(|_, id| *id != deleted_chan);
+                    state.leave_chan(deleted_chan.clone());
+                    info!("chan: {} has been deleted", deleted_chan);
+                }
+
+                if let Ok(archived_chan) = parse_archive_chan_resp(&line) {
+                    info!("chan: {} has been archived, it is read only now", archived_chan);
+                }
+
+                if let Ok(err) = parse_error_resp(&line) {
+                    warn!("server error: {}", err);
+                }
             }
             Err(e) => {
-                eprintln!("Error reading line: {}", e);
+                console.print(&format!("Error reading line: {}", e));
                 break;
             }
         }
@@ -277,6 +710,9 @@ fn check_leave_cmd_and_encode_msg(
                     return Err("chan_id is empty".to_string());
                 }
 
+                let chan_id = state
+                    .resolve_chan(&chan_id)
+                    .ok_or(format!("you have not joined chan: {}", chan_id))?;
                 if !state.joined_chans.contains(&chan_id) {
                     return Err(format!("you have not joined chan: {}", chan_id));
                 }
@@ -300,7 +736,9 @@ fn check_join_cmd_and_encode_msg(
                     return Err("chan_id is empty".to_string());
                 }
 
-                if state.joined_chans.contains(&chan_id) {
+                if let Some(id) = state.resolve_chan(&chan_id)
+                    && state.joined_chans.contains(&id)
+                {
                     return Err(format!("you have already joined chan: {}", chan_id));
                 }
 
@@ -322,12 +760,98 @@ fn check_create_chan_cmd_and_encode_msg(
             return Err("create_chan need chan_name".to_string());
         }
 
-        return Ok(Some(encode_create_chan(state, parts[1].to_string())));
+        Ok(Some(encode_create_chan(state, parts[1].to_string())))
     } else {
         Ok(None)
     }
 }
 
+// $delete <chan_id> / $archive <chan_id>
+fn check_owner_cmd_and_encode_msg(
+    line: String,
+    state: &ClientState,
+) -> Result<Option<String>, String> {
+    let parts: Vec<&str> = line.split(" ").collect();
+    let encode: fn(&ClientState, String) -> String = match parts[0] {
+        DELETE_CHAN => encode_delete_chan,
+        ARCHIVE_CHAN => encode_archive_chan,
+        _ => return Ok(None),
+    };
+
+    if parts.len() < 2 || parts[1].is_empty() {
+        return Err(format!("{} need chan_id", parts[0]));
+    }
+
+    Ok(Some(encode(state, parts[1].to_string())))
+}
+
+// $mod <chan_id> <username> / $slow <chan_id> <secs> / $rate <chan_id> <msgs_per_sec>
+// $read_only <chan_id> <on|off> / $retention <chan_id> <forever|days=N|last=N>
+fn check_mod_cmd_and_encode_msg(
+    line: String,
+    state: &ClientState,
+) -> Result<Option<String>, String> {
+    let parts: Vec<&str> = line.split(" ").collect();
+    let cmd = match parts[0] {
+        ADD_MOD => "add_mod",
+        SLOW_MODE => "slow_mode",
+        RATE_LIMIT => "rate_limit",
+        READ_ONLY => "read_only",
+        RETENTION => "retention",
+        _ => return Ok(None),
+    };
+
+    if parts.len() < 3 || parts[1].is_empty() || parts[2].is_empty() {
+        return Err(format!("{} need chan_id and value", parts[0]));
+    }
+
+    Ok(Some(encode_mod_cmd(state, cmd, parts[1].to_string(), parts[2].to_string())))
+}
+
+// $edit <msg_id> <new content> / $delete_msg <msg_id>
+// $reply <msg_id> <content> / $thread <msg_id>
+// $react <msg_id> <emoji> / $unreact <msg_id> <emoji>, in the current chan
+fn check_msg_cmd_and_encode_msg(
+    line: String,
+    state: &ClientState,
+) -> Result<Option<String>, String> {
+    let parts: Vec<&str> = line.splitn(3, " ").collect();
+    match parts[0] {
+        EDIT_MSG => {
+            if parts.len() < 3 || parts[1].is_empty() {
+                return Err("edit need msg_id and content".to_string());
+            }
+            Ok(Some(encode_edit_msg(state, parts[1].to_string(), parts[2].to_string())))
+        }
+        DELETE_MSG => {
+            if parts.len() < 2 || parts[1].is_empty() {
+                return Err("delete_msg need msg_id".to_string());
+            }
+            Ok(Some(encode_delete_msg(state, parts[1].to_string())))
+        }
+        REPLY => {
+            if parts.len() < 3 || parts[1].is_empty() {
+                return Err("reply need msg_id and content".to_string());
+            }
+            Ok(Some(encode_reply(state, parts[1].to_string(), parts[2].to_string())))
+        }
+        THREAD => {
+            if parts.len() < 2 || parts[1].is_empty() {
+                return Err("thread need msg_id".to_string());
+            }
+            Ok(Some(encode_thread(state, parts[1].to_string())))
+        }
+        REACT | UNREACT => {
+            if parts.len() < 3 || parts[1].is_empty() || parts[2].is_empty() {
+                return Err(format!("{} need msg_id and emoji", parts[0]));
+            }
+            let cmd = if parts[0] == REACT { "react" } else { "unreact" };
+            Ok(Some(encode_react(state, cmd, parts[1].to_string(), parts[2].to_string())))
+        }
+        _ => Ok(None),
+    }
+}
+
 fn is_join(line: String) -> Result<(bool, String), String> {
     if line.starts_with("$") {
         let parts: Vec<&str> = line.split(" ").collect();
@@ -360,41 +884,165 @@ fn is_leave(line: String) -> Result<(bool, String), String> {
     }
 }
 
-fn parse_join_resp(line: &String) -> Result<String, String> {
-    let parts: Vec<&str> = line.split(": ").collect();
+fn parse_resp(line: &str, resp: &str) -> Result<String, String> {
+    let parts: Vec<&str> = line.splitn(2, ": ").collect();
     if parts.len() < 2 {
-        return Err("invalid join resp".to_string());
+        return Err(format!("invalid {} resp", resp));
     }
 
-    if line.starts_with("$$") && parts[0] == JOIN_RESP {
+    if line.starts_with("$$") && parts[0] == resp {
         return Ok(parts[1].to_string());
     }
 
-    Err("not join resp".to_string())
+    Err(format!("not {} resp", resp))
 }
 
-fn parse_leave_resp(line: &String) -> Result<String, String> {
-    let parts: Vec<&str> = line.split(": ").collect();
-    if parts.len() < 2 {
-        return Err("invalid leave resp".to_string());
+fn mentions_user(line: &str, username: &str) -> bool {
+    if username.is_empty() {
+        return false;
     }
 
-    if line.starts_with("$$") && parts[0] == LEAVE_RESP {
-        return Ok(parts[1].to_string());
+    let mention = format!("@{}", username.to_lowercase());
+    line.to_lowercase().split_whitespace().any(|word| {
+        word.strip_prefix(&mention)
+            .is_some_and(|rest| !rest.starts_with(|c: char| c.is_alphanumeric() || c == '_' || c == '-'))
+    })
+}
+
+// `[msg_id] #name alice: Hello` -> `#name`
+fn parse_msg_chan(line: &str) -> Option<String> {
+    if !line.starts_with('[') {
+        return None;
     }
 
-    Err("not leave resp".to_string())
+    let (_, rest) = line.split_once("] ")?;
+    let name = rest.split(' ').next()?;
+    name.starts_with('#').then(|| name.to_string())
 }
 
-fn parse_create_chan_resp(line: &String) -> Result<String, String> {
-    let parts: Vec<&str> = line.split(": ").collect();
-    if parts.len() < 2 {
-        return Err("invalid create_chan resp".to_string());
+// edits and tombstones replace the original message line
+fn render_line(line: &str) -> String {
+    if let Ok(edited) = parse_resp(line, EDIT_MSG_RESP) {
+        return format!("~ {}", edited);
     }
 
-    if line.starts_with("$$") && parts[0] == CREATE_CHAN_RESP {
-        return Ok(parts[1].to_string());
+    if let Ok(deleted) = parse_resp(line, DELETE_MSG_RESP) {
+        let msg_id = deleted.split(' ').next_back().unwrap_or_default();
+        return format!("~ [{}] (deleted)", msg_id);
+    }
+
+    if let Ok(replies) = parse_resp(line, REPLIES_RESP)
+        && let Some((parent_id, count)) = replies.split_once(' ')
+    {
+        return format!("~ [{}] {} replies", parent_id, count);
+    }
+
+    if let Ok(reactions) = parse_resp(line, REACTIONS_RESP)
+        && let Some((msg_id, summary)) = reactions.split_once(' ')
+    {
+        return format!("~ [{}] reactions: {}", msg_id, summary);
+    }
+
+    if let Ok(unread) = parse_resp(line, UNREAD_RESP)
+        && let Some((label, count)) = unread.rsplit_once(' ')
+    {
+        let name = split_chan_label(label).1.unwrap_or(label.to_string());
+        return format!("~ #{}: {} unread", name, count);
+    }
+
+    if let Ok(mention) = parse_resp(line, MENTION_RESP)
+        && let Some((chan_id, msg)) = mention.split_once(' ')
+    {
+        return format!("@ you were mentioned in {}: {}", chan_id, msg);
+    }
+
+    if let Ok(token) = parse_resp(line, TOKEN_RESP) {
+        return format!("(keep this token, log in as this name again with: client <name> {})", token);
+    }
+
+    if let Ok(msg) = parse_resp(line, REPLAY_RESP) {
+        return format!("(history) {}", msg);
+    }
+
+    // `<chan_id> <msg>`, queued while we were offline
+    if let Ok(queued) = parse_resp(line, OFFLINE_RESP) {
+        let msg = queued.split_once(' ').map_or(queued.as_str(), |(_, msg)| msg);
+        return format!("(while you were away) {}", msg);
+    }
+
+    if let Ok(dropped) = parse_resp(line, OFFLINE_OVERFLOW_RESP) {
+        return format!("({} older messages sent while you were away were dropped)", dropped);
+    }
+
+    // `<total> <chan_id>:<missed> ... [server:<missed>]`, the missed messages follow as history
+    if let Ok(lagged) = parse_resp(line, LAGGED_RESP) {
+        let total = lagged.split(' ').next().unwrap_or_default();
+        return format!("(fell behind and missed {} messages, catching up)", total);
     }
 
-    Err("not create_chan resp".to_string())
-}
\ No newline at end of file
+    if parse_resp(line, SHUTDOWN_RESP).is_ok() {
+        return "(server is shutting down, reconnect in a moment)".to_string();
+    }
+
+    if let Ok(found) = parse_resp(line, SEARCH_RESP) {
+        return format!("(found) {}", found);
+    }
+
+    if let Ok(count) = parse_resp(line, SEARCH_END_RESP) {
+        return format!("({} results)", count);
+    }
+
+    // `<chan_id> <date> <time> <msg>`, the chan is always the current one
+    if let Ok(msg) = parse_resp(line, HISTORY_RESP) {
+        let msg = msg.split_once(' ').map_or(msg.as_str(), |(_, msg)| msg);
+        return format!("(older) {}", msg);
+    }
+
+    // thread views: the root, then its replies indented below it
+    if let Ok(thread) = parse_resp(line, THREAD_RESP) {
+        let root = thread.split_once(' ').map_or(thread.as_str(), |(_, root)| root);
+        return format!("thread: {}", root);
+    }
+
+    if let Ok(reply) = parse_resp(line, THREAD_MSG_RESP) {
+        return format!("    | {}", reply);
+    }
+
+    line.to_string()
+}
+
+// `<chan_id> #<name>` -> (chan_id, name)
+fn split_chan_label(label: &str) -> (String, Option<String>) {
+    match label.split_once(" #") {
+        Some((chan_id, name)) => (chan_id.to_string(), Some(name.to_string())),
+        None => (label.to_string(), None),
+    }
+}
+
+fn parse_join_resp(line: &str) -> Result<String, String> {
+    parse_resp(line, JOIN_RESP)
+}
+
+fn parse_auto_join_resp(line: &str) -> Result<String, String> {
+    parse_resp(line, AUTO_JOIN_RESP)
+}
+
+fn parse_leave_resp(line: &str) -> Result<String, String> {
+    parse_resp(line, LEAVE_RESP)
+}
+
+fn parse_create_chan_resp(line: &str) -> Result<String, String> {
+    parse_resp(line, CREATE_CHAN_RESP)
+}
+
+fn parse_delete_chan_resp(line: &str) -> Result<String, String> {
+    parse_resp(line, DELETE_CHAN_RESP)
+}
+
+fn parse_archive_chan_resp(line: &str) -> Result<String, String> {
+    parse_resp(line, ARCHIVE_CHAN_RESP)
+}
+
+fn parse_error_resp(line: &str) -> Result<String, String> {
+    parse_resp(line, ERROR_RESP)
+}
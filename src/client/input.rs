@@ -0,0 +1,236 @@
+use std::io::{IsTerminal, Write};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::terminal::{self, Clear, ClearType};
+use crossterm::{cursor, queue};
+use futures::StreamExt;
+use tokio::io::{AsyncBufReadExt, BufReader, Stdin};
+
+pub enum Input {
+    Line(String),
+    Typing, // the user changed a chat line that is not sent yet
+    Eof,
+}
+
+/// The line being typed, shared with whatever prints server lines so they
+/// land above it instead of through it.
+#[derive(Clone, Default)]
+pub struct Console {
+    line: Arc<Mutex<Line>>,
+}
+
+#[derive(Default)]
+struct Line {
+    buf: String,
+    raw: bool, // the terminal is in raw mode, so output needs `\r\n` and a redraw
+}
+
+impl Console {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Print `text` above the line being typed, then redraw that line.
+    pub fn print(&self, text: &str) {
+        let line = self.lock();
+        let mut out = std::io::stdout().lock();
+        if line.raw {
+            let _ = queue!(out, cursor::MoveToColumn(0), Clear(ClearType::CurrentLine));
+            let _ = write!(out, "{}\r\n{}", text.replace('\n', "\r\n"), line.buf);
+        } else {
+            let _ = writeln!(out, "{}", text);
+        }
+        let _ = out.flush();
+    }
+
+    /// Writer for the log layer, printing each event like `print` does.
+    pub fn writer(&self) -> ConsoleWriter {
+        ConsoleWriter {
+            console: self.clone(),
+            buf: Vec::new(),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Line> {
+        self.line.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Collects one log event and prints it on drop.
+pub struct ConsoleWriter {
+    console: Console,
+    buf: Vec<u8>,
+}
+
+impl Write for ConsoleWriter {
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        self.buf.extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for ConsoleWriter {
+    fn drop(&mut self) {
+        if !self.buf.is_empty() {
+            let text = String::from_utf8_lossy(&self.buf);
+            self.console.print(text.trim_end_matches('\n'));
+        }
+    }
+}
+
+/// Reads stdin key by key when it is a terminal, so the client can send typing
+/// indicators before a line is finished. Falls back to plain lines otherwise.
+pub struct InputReader {
+    console: Console,
+    events: Option<EventStream>, // set while the terminal is in raw mode
+    stdin: BufReader<Stdin>,
+    partial: Vec<u8>, // line read so far without a terminal, kept when `next` is cancelled
+}
+
+impl InputReader {
+    pub fn new(console: Console) -> Self {
+        let raw = std::io::stdin().is_terminal() && terminal::enable_raw_mode().is_ok();
+        console.lock().raw = raw;
+        Self {
+            console,
+            events: raw.then(EventStream::new),
+            stdin: BufReader::new(tokio::io::stdin()),
+            partial: Vec::new(),
+        }
+    }
+
+    /// Cancel safe, so it can be raced against timers.
+    pub async fn next(&mut self) -> Input {
+        if self.events.is_none() {
+            return match self.stdin.read_until(b'\n', &mut self.partial).await {
+                Ok(0) | Err(_) => Input::Eof,
+                Ok(_) => {
+                    let line = String::from_utf8_lossy(&std::mem::take(&mut self.partial)).into_owned();
+                    Input::Line(line.trim_end_matches(['\r', '\n']).to_string())
+                }
+            };
+        }
+
+        loop {
+            let event = match self.events.as_mut() {
+                Some(events) => events.next().await,
+                None => None,
+            };
+            match event {
+                Some(Ok(Event::Key(key))) if key.kind != KeyEventKind::Release => {
+                    if let Some(input) = self.handle_key(key) {
+                        return input;
+                    }
+                }
+                Some(Ok(_)) => {}
+                None | Some(Err(_)) => return Input::Eof,
+            }
+        }
+    }
+
+    /// Apply one key to the line being typed. Keys without a meaning here,
+    /// arrows and escape included, are dropped rather than echoed.
+    fn handle_key(&mut self, key: KeyEvent) -> Option<Input> {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        let mut line = self.console.lock();
+        let mut out = std::io::stdout().lock();
+
+        let input = match key.code {
+            KeyCode::Enter => {
+                let _ = write!(out, "\r\n");
+                Some(Input::Line(std::mem::take(&mut line.buf)))
+            }
+            KeyCode::Char('c') if ctrl => {
+                // leave the terminal as we found it, then exit like SIGINT would
+                drop(line);
+                self.restore();
+                std::process::exit(130);
+            }
+            KeyCode::Char('d') if ctrl => line.buf.is_empty().then_some(Input::Eof),
+            KeyCode::Backspace if line.buf.pop().is_some() => {
+                let _ = queue!(out, cursor::MoveToColumn(0), Clear(ClearType::CurrentLine));
+                let _ = write!(out, "{}", line.buf);
+                typing(&line.buf)
+            }
+            KeyCode::Char(c) if !ctrl && !key.modifiers.contains(KeyModifiers::ALT) => {
+                line.buf.push(c);
+                let _ = write!(out, "{}", c);
+                typing(&line.buf)
+            }
+            KeyCode::Tab => {
+                line.buf.push('\t');
+                let _ = write!(out, "\t");
+                typing(&line.buf)
+            }
+            _ => None,
+        };
+
+        let _ = out.flush();
+        input
+    }
+
+    fn restore(&mut self) {
+        if self.events.take().is_some() {
+            self.console.lock().raw = false;
+            let _ = terminal::disable_raw_mode();
+        }
+    }
+}
+
+impl Drop for InputReader {
+    fn drop(&mut self) {
+        self.restore();
+    }
+}
+
+/// Commands starting with `$` are not chat lines, so they do not count as typing.
+fn typing(buf: &str) -> Option<Input> {
+    (!buf.is_empty() && !buf.starts_with('$')).then_some(Input::Typing)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reader() -> InputReader {
+        InputReader {
+            console: Console::new(),
+            events: None,
+            stdin: BufReader::new(tokio::io::stdin()),
+            partial: Vec::new(),
+        }
+    }
+
+    fn press(reader: &mut InputReader, code: KeyCode) -> Option<Input> {
+        reader.handle_key(KeyEvent::new(code, KeyModifiers::NONE))
+    }
+
+    #[tokio::test]
+    async fn escape_and_arrow_keys_stay_out_of_the_line() {
+        let mut reader = reader();
+        for code in [KeyCode::Char('h'), KeyCode::Esc, KeyCode::Up, KeyCode::Left, KeyCode::Char('é')] {
+            press(&mut reader, code);
+        }
+        assert!(matches!(press(&mut reader, KeyCode::Backspace), Some(Input::Typing)));
+        assert!(matches!(press(&mut reader, KeyCode::Char('i')), Some(Input::Typing)));
+        assert!(matches!(press(&mut reader, KeyCode::Enter), Some(Input::Line(line)) if line == "hi"));
+        assert!(reader.console.lock().buf.is_empty());
+    }
+
+    #[tokio::test]
+    async fn commands_are_not_typing() {
+        let mut reader = reader();
+        assert!(press(&mut reader, KeyCode::Char('$')).is_none());
+        assert!(press(&mut reader, KeyCode::Char('j')).is_none());
+        assert!(matches!(press(&mut reader, KeyCode::Enter), Some(Input::Line(line)) if line == "$j"));
+        assert!(press(&mut reader, KeyCode::Backspace).is_none());
+
+        let ctrl_d = KeyEvent::new(KeyCode::Char('d'), KeyModifiers::CONTROL);
+        assert!(matches!(reader.handle_key(ctrl_d), Some(Input::Eof)));
+    }
+}
@@ -0,0 +1,259 @@
+use std::time::Duration;
+
+use crate::chatsvc::{
+    DEFAULT_HANDSHAKE_TIMEOUT, DEFAULT_HISTORY_SIZE, DEFAULT_IDLE_TIMEOUT, DEFAULT_MAX_CONNS, DEFAULT_MAX_CONNS_PER_IP,
+    DEFAULT_MAX_LAG, DEFAULT_MAX_LINE_LEN, DEFAULT_MAX_MSG_LEN, DEFAULT_OFFLINE_QUEUE_CAP, DEFAULT_SHUTDOWN_TIMEOUT,
+    DEFAULT_SNAPSHOT_PATH, PING_INTERVAL,
+};
+use crate::errors::ChatErrors;
+
+pub const DEFAULT_ADDR: &str = "0.0.0.0:9090";
+pub const DEFAULT_CHANS: &str = "lobby";
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub addr: String,
+    pub default_chans: Vec<String>, // chans every new user joins on register
+    pub read_only_chans: Vec<String>, // default chans only moderators can post to
+    pub admins: Vec<String>,        // usernames that moderate every chan
+    pub admin_key_file: Option<String>, // holds the key admins log in with
+    pub history_size: usize,        // messages kept per chan and replayed on join
+    pub sqlite_path: Option<String>, // keep state in this sqlite file instead of memory only
+    pub wal_path: Option<String>,    // or in this append-only log
+    pub snapshot_path: String,       // where the admin `snapshot` command writes to
+    pub restore_path: Option<String>, // snapshot to restore on startup
+    pub offline_queue_cap: usize,     // messages kept per offline user
+    pub queue_all_offline: bool,      // queue all chan traffic for offline users, not only DMs and mentions
+    pub max_lag: usize,               // messages a slow reader may miss before it is disconnected
+    pub max_line_len: usize,          // bytes per line read from a client
+    pub max_msg_len: usize,           // bytes per message content
+    pub max_conns: usize,
+    pub max_conns_per_ip: usize,
+    pub handshake_timeout: Option<Duration>, // to register after connecting, none if 0 is given
+    pub idle_timeout: Option<Duration>,      // without a line from the client, none if 0 is given
+    pub shutdown_timeout: Duration,          // to drain conns on SIGINT/SIGTERM before exiting anyway
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            addr: DEFAULT_ADDR.to_string(),
+            default_chans: split_list(DEFAULT_CHANS),
+            read_only_chans: Vec::new(),
+            admins: Vec::new(),
+            admin_key_file: None,
+            history_size: DEFAULT_HISTORY_SIZE,
+            sqlite_path: None,
+            wal_path: None,
+            snapshot_path: DEFAULT_SNAPSHOT_PATH.to_string(),
+            restore_path: None,
+            offline_queue_cap: DEFAULT_OFFLINE_QUEUE_CAP,
+            queue_all_offline: false,
+            max_lag: DEFAULT_MAX_LAG,
+            max_line_len: DEFAULT_MAX_LINE_LEN,
+            max_msg_len: DEFAULT_MAX_MSG_LEN,
+            max_conns: DEFAULT_MAX_CONNS,
+            max_conns_per_ip: DEFAULT_MAX_CONNS_PER_IP,
+            handshake_timeout: Some(DEFAULT_HANDSHAKE_TIMEOUT),
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+        }
+    }
+}
+
+impl ServerConfig {
+    // txt-chat --addr 0.0.0.0:9090 --default-chans lobby,announcements
+    //   --read-only-chans announcements --admins alice,bob --admin-key-file admin.key --history-size 100
+    //   --sqlite txt-chat.db | --wal txt-chat.wal
+    //   --snapshot txt-chat.snapshot --restore backup.snapshot
+    //   --offline-queue-cap 100 --queue-all-offline --max-lag 1000
+    //   --max-line-len 8192 --max-msg-len 4096 --max-conns 10000 --max-conns-per-ip 100
+    //   --handshake-timeout 10 --idle-timeout 3600 (seconds, 0 for none) --shutdown-timeout 10
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Self, ChatErrors> {
+        let mut config = Self::default();
+        let mut args = args.skip(1);
+
+        while let Some(flag) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| ChatErrors::InvalidArgument(format!("{} need a value", flag)))
+            };
+
+            match flag.as_str() {
+                "--addr" => config.addr = value()?,
+                "--default-chans" => config.default_chans = split_list(&value()?),
+                "--read-only-chans" => config.read_only_chans = split_list(&value()?),
+                "--admins" => config.admins = split_list(&value()?),
+                "--admin-key-file" => config.admin_key_file = Some(value()?),
+                "--history-size" => config.history_size = parse_num(&flag, &value()?)?,
+                "--sqlite" => config.sqlite_path = Some(value()?),
+                "--wal" => config.wal_path = Some(value()?),
+                "--snapshot" => config.snapshot_path = value()?,
+                "--restore" => config.restore_path = Some(value()?),
+                "--offline-queue-cap" => config.offline_queue_cap = parse_num(&flag, &value()?)?,
+                "--queue-all-offline" => config.queue_all_offline = true,
+                "--max-lag" => config.max_lag = parse_num(&flag, &value()?)?,
+                "--max-line-len" => config.max_line_len = parse_num(&flag, &value()?)?,
+                "--max-msg-len" => config.max_msg_len = parse_num(&flag, &value()?)?,
+                "--max-conns" => config.max_conns = parse_num(&flag, &value()?)?,
+                "--max-conns-per-ip" => config.max_conns_per_ip = parse_num(&flag, &value()?)?,
+                "--handshake-timeout" => config.handshake_timeout = parse_secs(&flag, &value()?)?,
+                "--idle-timeout" => config.idle_timeout = parse_secs(&flag, &value()?)?,
+                "--shutdown-timeout" => config.shutdown_timeout = Duration::from_secs(parse_num(&flag, &value()?)?),
+                _ => return Err(ChatErrors::InvalidArgument(format!("unknown flag: {}", flag))),
+            }
+        }
+
+        if config.idle_timeout.is_some_and(|idle| idle <= PING_INTERVAL) {
+            return Err(ChatErrors::InvalidArgument(format!(
+                "--idle-timeout must be longer than the clients' ping interval: {:?}",
+                PING_INTERVAL
+            )));
+        }
+        if !config.admins.is_empty() && config.admin_key_file.is_none() {
+            return Err(ChatErrors::InvalidArgument(
+                "--admins need --admin-key-file, admins log in with its key".to_string(),
+            ));
+        }
+        if config.sqlite_path.is_some() && config.wal_path.is_some() {
+            return Err(ChatErrors::InvalidArgument(
+                "--sqlite and --wal can not be used together".to_string(),
+            ));
+        }
+        Ok(config)
+    }
+
+    pub fn is_read_only(&self, chan_name: &str) -> bool {
+        self.read_only_chans
+            .iter()
+            .any(|name| name.eq_ignore_ascii_case(chan_name))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ExportConfig {
+    pub sqlite_path: Option<String>,
+    pub wal_path: Option<String>,
+    pub chan: String,
+    pub format: String,
+    pub out: Option<String>, // stdout if not set
+}
+
+impl ExportConfig {
+    // txt-chat export --sqlite txt-chat.db | --wal txt-chat.wal
+    //   --chan #incident-42 --format md --out incident-42.md
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Self, ChatErrors> {
+        let mut config = Self {
+            format: "txt".to_string(),
+            ..Self::default()
+        };
+        let mut args = args.skip(2);
+
+        while let Some(flag) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| ChatErrors::InvalidArgument(format!("{} need a value", flag)))
+            };
+
+            match flag.as_str() {
+                "--sqlite" => config.sqlite_path = Some(value()?),
+                "--wal" => config.wal_path = Some(value()?),
+                "--chan" => config.chan = value()?,
+                "--format" => config.format = value()?,
+                "--out" => config.out = Some(value()?),
+                _ => return Err(ChatErrors::InvalidArgument(format!("unknown flag: {}", flag))),
+            }
+        }
+
+        if config.chan.is_empty() {
+            return Err(ChatErrors::InvalidArgument("export need --chan".to_string()));
+        }
+        if config.sqlite_path.is_some() == config.wal_path.is_some() {
+            return Err(ChatErrors::InvalidArgument(
+                "export need exactly one of --sqlite and --wal".to_string(),
+            ));
+        }
+        Ok(config)
+    }
+}
+
+fn parse_num<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, ChatErrors> {
+    value
+        .parse()
+        .map_err(|_| ChatErrors::InvalidArgument(format!("{} need a number, got: {}", flag, value)))
+}
+
+fn parse_secs(flag: &str, value: &str) -> Result<Option<Duration>, ChatErrors> {
+    let secs: u64 = parse_num(flag, value)?;
+    Ok((secs > 0).then(|| Duration::from_secs(secs)))
+}
+
+fn split_list(s: &str) -> Vec<String> {
+    s.split(',')
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> impl Iterator<Item = String> {
+        line.split(' ').map(|v| v.to_string()).collect::<Vec<_>>().into_iter()
+    }
+
+    #[test]
+    fn parses_server_flags() {
+        let config = ServerConfig::from_args(args(
+            "txt-chat --addr 127.0.0.1:9000 --default-chans lobby,,news --read-only-chans News --admins root --admin-key-file admin.key --history-size 20 --sqlite chat.db --max-lag 50 --max-conns-per-ip 3 --idle-timeout 0 --shutdown-timeout 3",
+        ))
+        .unwrap();
+        assert_eq!(config.addr, "127.0.0.1:9000");
+        assert_eq!(config.default_chans, ["lobby", "news"]);
+        assert!(config.is_read_only("news"));
+        assert!(!config.is_read_only("lobby"));
+        assert_eq!(config.admins, ["root"]);
+        assert_eq!(config.admin_key_file.as_deref(), Some("admin.key"));
+        assert_eq!(config.history_size, 20);
+        assert_eq!(config.sqlite_path.as_deref(), Some("chat.db"));
+        assert_eq!(config.max_lag, 50);
+        assert_eq!(config.max_conns_per_ip, 3);
+        assert_eq!(config.idle_timeout, None);
+        assert_eq!(config.handshake_timeout, Some(DEFAULT_HANDSHAKE_TIMEOUT));
+        assert_eq!(config.shutdown_timeout, Duration::from_secs(3));
+
+        assert_eq!(ServerConfig::from_args(args("txt-chat")).unwrap().default_chans, ["lobby"]);
+    }
+
+    #[test]
+    fn refuses_bad_server_flags() {
+        for line in [
+            "txt-chat --addr",
+            "txt-chat --verbose",
+            "txt-chat --history-size lots",
+            "txt-chat --idle-timeout -1",
+            "txt-chat --idle-timeout 10",
+            "txt-chat --admins root",
+            "txt-chat --sqlite a.db --wal a.wal",
+        ] {
+            assert!(matches!(ServerConfig::from_args(args(line)), Err(ChatErrors::InvalidArgument(_))), "{}", line);
+        }
+    }
+
+    #[test]
+    fn parses_export_flags() {
+        let config = ExportConfig::from_args(args("txt-chat export --wal chat.wal --chan #incident-42 --format md")).unwrap();
+        assert_eq!(config.wal_path.as_deref(), Some("chat.wal"));
+        assert_eq!((config.chan.as_str(), config.format.as_str()), ("#incident-42", "md"));
+        assert!(config.out.is_none());
+
+        for line in [
+            "txt-chat export --sqlite chat.db",
+            "txt-chat export --chan #room",
+            "txt-chat export --sqlite chat.db --wal chat.wal --chan #room",
+        ] {
+            assert!(matches!(ExportConfig::from_args(args(line)), Err(ChatErrors::InvalidArgument(_))), "{}", line);
+        }
+    }
+}
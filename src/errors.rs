@@ -8,6 +8,60 @@ pub enum ChatErrors {
     #[error("cmd: {0} is not support")]
     CommandNotSupport(String),
 
+    #[error("invalid argument: {0}")]
+    InvalidArgument(String),
+
     #[error("not set current channel yet")]
     UnknownCurrentChan,
+
+    #[error("chan: {0} not found")]
+    ChannelNotFound(String),
+
+    #[error("chan name: #{0} is already taken")]
+    ChannelNameTaken(String),
+
+    #[error("invalid chan name: {0}")]
+    InvalidChannelName(String),
+
+    #[error("permission denied: {0}")]
+    PermissionDenied(String),
+
+    #[error("chan: {0} is archived")]
+    ChannelArchived(String),
+
+    #[error("msg: {0} not found")]
+    MessageNotFound(String),
+
+    #[error("invalid reaction: {0}")]
+    InvalidReaction(String),
+
+    #[error("rate limited, retry in {}ms", .0.as_millis())]
+    RateLimited(std::time::Duration),
+
+    #[error("not registered yet")]
+    NotRegistered,
+
+    #[error("user: {0} is already logged in")]
+    AlreadyLoggedIn(String),
+
+    #[error("storage error: {0}")]
+    Storage(String),
+
+    #[error("disconnected for reading too slowly, missed {0} messages")]
+    SlowConsumer(usize),
+
+    #[error("line is longer than {0} bytes")]
+    LineTooLong(usize),
+
+    #[error("msg is longer than {0} bytes")]
+    MessageTooLong(usize),
+
+    #[error("too many connections: {0}")]
+    TooManyConnections(String),
+
+    #[error("not registered within {}s", .0.as_secs())]
+    HandshakeTimeout(std::time::Duration),
+
+    #[error("disconnected after {}s idle", .0.as_secs())]
+    IdleTimeout(std::time::Duration),
 }
@@ -1,28 +1,260 @@
 use std::sync::Arc;
 
 use tokio::sync::RwLock;
-use tracing::info;
+use tracing::{info, warn};
 
-use crate::{chatsvc::ChatService, event::Event};
+use crate::{
+    chatsvc::{ChatService, ERROR_RESP},
+    errors::ChatErrors,
+    event::Event,
+    search::SearchQuery,
+};
 
-pub async fn handle_event(uid: String, svc: Arc<RwLock<ChatService>>, event: Event) {
+/// Handle a line read from the connection `conn_id`. Every command but `reg`
+/// acts as the user logged in on the connection, whatever user id the client
+/// put in the line.
+///
+/// Commands wait on storage writes, so they run on the blocking pool. Events
+/// listed by `Event::is_shared` only lock the service shared, the others
+/// exclusively.
+pub async fn handle_event(conn_id: String, svc: Arc<RwLock<ChatService>>, event: Event) {
     info!("start handle event");
-    let mut svc = svc.write().await;
-    info!("lock service...");
+    let handled = tokio::task::spawn_blocking(move || {
+        if event.is_shared() {
+            let svc = svc.blocking_read();
+            info!("lock service shared...");
 
+            let res = check_session(&svc, &conn_id, &event).and_then(|()| apply_shared_event(&svc, event));
+            report_error(&svc, &conn_id, res);
+        } else {
+            let mut svc = svc.blocking_write();
+            info!("lock service...");
+
+            let res = if let Event::Register { username, token } = event {
+                svc.login(conn_id.clone(), username, token.map(|token| token.0)).map(|_| ())
+            } else {
+                check_session(&svc, &conn_id, &event).and_then(|()| apply_event(&mut svc, event))
+            };
+            report_error(&svc, &conn_id, res);
+        }
+    })
+    .await;
+
+    if let Err(e) = handled {
+        warn!("event handler failed: {}", e);
+    }
+}
+
+fn check_session(svc: &ChatService, conn_id: &str, event: &Event) -> Result<(), ChatErrors> {
+    match svc.session(conn_id) {
+        None => Err(ChatErrors::NotRegistered),
+        Some(uid) if event.user_id().is_some_and(|user_id| user_id != uid) => {
+            let e = "user id does not match the connection".to_string();
+            Err(ChatErrors::PermissionDenied(e))
+        }
+        Some(_) => Ok(()),
+    }
+}
+
+fn report_error(svc: &ChatService, conn_id: &str, res: Result<(), ChatErrors>) {
+    if let Err(e) = res {
+        warn!("failed to handle event for conn: {}, {}", conn_id, e);
+        svc.send_to_conn(conn_id, format!("{}: {}", ERROR_RESP, e));
+    }
+}
+
+fn apply_event(svc: &mut ChatService, event: Event) -> Result<(), ChatErrors> {
     match event {
-        Event::Register { username } => {
-            info!("adding user to service");
-            svc.add_user(username, uid);
+        Event::CreateChan { user_id, chan_name } => {
+            svc.create_named_chan(user_id, chan_name)?;
+        }
+        Event::JoinChan { user_id, chan_id } => {
+            let chan_id = svc.resolve_chan(chan_id)?;
+            svc.join_chan(user_id, chan_id)?;
+        }
+        Event::LeaveChan { user_id, chan_id } => {
+            let chan_id = svc.resolve_chan(chan_id)?;
+            svc.leave_chan(user_id, chan_id)?;
         }
-        Event::CreateChan { user_id, chan_name } => {svc.create_chan(user_id, chan_name, None);},
-        Event::JoinChan { user_id, chan_id } => svc.join_chan(user_id, chan_id),
-        Event::LeaveChan { user_id, chan_id } => svc.leave_chan(user_id, chan_id),
+        Event::DeleteChan { user_id, chan_id } => {
+            let chan_id = svc.resolve_chan(chan_id)?;
+            svc.delete_chan(user_id, chan_id)?;
+        }
+        Event::ArchiveChan { user_id, chan_id } => {
+            let chan_id = svc.resolve_chan(chan_id)?;
+            svc.archive_chan(user_id, chan_id)?;
+        }
+        Event::AddModerator { user_id, chan_id, target } => {
+            let chan_id = svc.resolve_chan(chan_id)?;
+            svc.add_moderator(user_id, chan_id, target)?;
+        }
+        Event::SlowMode { user_id, chan_id, secs } => {
+            let chan_id = svc.resolve_chan(chan_id)?;
+            svc.set_slow_mode(user_id, chan_id, secs)?;
+        }
+        Event::RateLimit { user_id, chan_id, per_sec } => {
+            let chan_id = svc.resolve_chan(chan_id)?;
+            svc.set_rate_limit(user_id, chan_id, per_sec)?;
+        }
+        Event::ReadOnly { user_id, chan_id, read_only } => {
+            let chan_id = svc.resolve_chan(chan_id)?;
+            svc.set_read_only(user_id, chan_id, read_only)?;
+        }
+        Event::Retention { user_id, chan_id, policy } => {
+            let chan_id = svc.resolve_chan(chan_id)?;
+            svc.set_retention(user_id, chan_id, policy.parse()?)?;
+        }
+        // a point in time copy, no post may land halfway through
+        Event::Snapshot { user_id } => svc.save_snapshot(user_id)?,
+        // `reg` logs the conn in before a uid exists, see `handle_event`
+        Event::Register { .. } => {}
+        event => apply_shared_event(svc, event)?,
+    }
+
+    Ok(())
+}
+
+/// Events listed by `Event::is_shared`.
+fn apply_shared_event(svc: &ChatService, event: Event) -> Result<(), ChatErrors> {
+    match event {
         Event::SendMsg {
             user_id,
             chan_id,
             msg,
-        } => svc.send_msg(false, user_id, chan_id, msg),
-        Event::Unknown => {},
+            parent_id,
+        } => {
+            let chan_id = svc.resolve_chan(chan_id)?;
+            svc.post_msg(user_id, chan_id, msg, parent_id)?;
+        }
+        Event::EditMsg { user_id, chan_id, msg_id, msg } => {
+            let chan_id = svc.resolve_chan(chan_id)?;
+            svc.edit_msg(user_id, chan_id, msg_id, msg)?;
+        }
+        Event::DeleteMsg { user_id, chan_id, msg_id } => {
+            let chan_id = svc.resolve_chan(chan_id)?;
+            svc.delete_msg(user_id, chan_id, msg_id)?;
+        }
+        Event::GetThread { user_id, chan_id, parent_id } => {
+            let chan_id = svc.resolve_chan(chan_id)?;
+            svc.get_thread(user_id, chan_id, parent_id)?;
+        }
+        Event::React { user_id, chan_id, msg_id, reaction } => {
+            let chan_id = svc.resolve_chan(chan_id)?;
+            svc.react(user_id, chan_id, msg_id, reaction, true)?;
+        }
+        Event::Unreact { user_id, chan_id, msg_id, reaction } => {
+            let chan_id = svc.resolve_chan(chan_id)?;
+            svc.react(user_id, chan_id, msg_id, reaction, false)?;
+        }
+        Event::Typing { user_id, chan_id } => {
+            let chan_id = svc.resolve_chan(chan_id)?;
+            svc.typing(user_id, chan_id)?;
+        }
+        Event::MarkRead { user_id, chan_id, msg_id } => {
+            let chan_id = svc.resolve_chan(chan_id)?;
+            svc.mark_read(user_id, chan_id, msg_id)?;
+        }
+        Event::Unread { user_id } => svc.send_unread(&user_id),
+        Event::Stats { user_id } => svc.send_stats(user_id)?,
+        Event::Search { user_id, terms, filters } => {
+            // parsed here rather than in `Event` so bad filters are reported back
+            let query = SearchQuery::parse(&terms, &filters)?;
+            svc.search(user_id, query)?;
+        }
+        Event::Export { user_id, chan_id, format } => {
+            let chan_id = svc.resolve_chan(chan_id)?;
+            svc.export_chan(user_id, chan_id, format.parse()?)?;
+        }
+        Event::History { user_id, chan_id, before_id, limit } => {
+            let chan_id = svc.resolve_chan(chan_id)?;
+            svc.send_history(&user_id, chan_id, before_id, limit)?;
+        }
+        Event::Ping | Event::Unknown => {}
+        event => warn!("event needs the service locked exclusively: {:?}", event),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chatsvc::testing::{connect, drain, login, service};
+
+    async fn handle(svc: &Arc<RwLock<ChatService>>, conn_id: &str, line: String) {
+        let event = Event::from_string(line).unwrap();
+        handle_event(conn_id.to_string(), svc.clone(), event).await;
+    }
+
+    #[tokio::test]
+    async fn commands_act_as_the_connection_user() {
+        let mut svc = service();
+        let (alice, _a) = login(&mut svc, "c1", "alice");
+        let (_bob, mut b) = login(&mut svc, "c2", "bob");
+        let chan_id = svc.create_chan(alice.clone(), "room".to_string(), None).unwrap();
+        let svc = Arc::new(RwLock::new(svc));
+        drain(&mut b);
+
+        handle(&svc, "c2", format!("delete_chan${}${}", alice, chan_id)).await;
+
+        let svc = svc.read().await;
+        assert!(svc.channels.contains_key(&chan_id));
+        let lines = drain(&mut b);
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].contains("user id does not match the connection"), "{:?}", lines);
+    }
+
+    #[tokio::test]
+    async fn posts_with_another_user_id_are_refused() {
+        let mut svc = service();
+        let (alice, mut a) = login(&mut svc, "c1", "alice");
+        let (bob, _b) = login(&mut svc, "c2", "bob");
+        let chan_id = svc.create_chan(alice.clone(), "room".to_string(), None).unwrap();
+        svc.join_chan(bob.clone(), chan_id.clone()).unwrap();
+        let svc = Arc::new(RwLock::new(svc));
+        drain(&mut a);
+
+        handle(&svc, "c2", format!("send_msg${}${}$hi", alice, chan_id)).await;
+        assert!(drain(&mut a).is_empty());
+
+        handle(&svc, "c2", format!("send_msg${}${}$hi", bob, chan_id)).await;
+        let lines = drain(&mut a);
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].ends_with("bob: hi"), "{:?}", lines);
+    }
+
+    #[tokio::test]
+    async fn only_the_owner_deletes_a_chan() {
+        let mut svc = service();
+        let (alice, _a) = login(&mut svc, "c1", "alice");
+        let (bob, mut b) = login(&mut svc, "c2", "bob");
+        let chan_id = svc.create_chan(alice.clone(), "room".to_string(), None).unwrap();
+        let svc = Arc::new(RwLock::new(svc));
+        drain(&mut b);
+
+        handle(&svc, "c2", format!("delete_chan${}${}", bob, chan_id)).await;
+        assert!(svc.read().await.channels.contains_key(&chan_id));
+        assert!(drain(&mut b)[0].contains("only the owner can delete"));
+
+        handle(&svc, "c1", format!("delete_chan${}${}", alice, chan_id)).await;
+        assert!(!svc.read().await.channels.contains_key(&chan_id));
+    }
+
+    #[tokio::test]
+    async fn commands_before_register_are_refused() {
+        let mut svc = service();
+        let mut conn = connect(&mut svc, "c1");
+        let svc = Arc::new(RwLock::new(svc));
+
+        handle(&svc, "c1", "create_chan$c1$room".to_string()).await;
+        assert!(svc.read().await.chan_names.is_empty());
+        let lines = drain(&mut conn);
+        assert_eq!(lines, vec![format!("{}: not registered yet", ERROR_RESP)]);
+
+        handle(&svc, "c1", "reg$alice".to_string()).await;
+        let uid = svc.read().await.session("c1").cloned().unwrap();
+        assert_eq!(drain(&mut conn)[0], uid);
+        handle(&svc, "c1", format!("create_chan${}$room", uid)).await;
+        assert!(svc.read().await.chan_names.contains_key("room"));
     }
 }
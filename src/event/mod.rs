@@ -1,3 +1,5 @@
+use std::fmt;
+
 use crate::errors::ChatErrors;
 
 pub mod handler;
@@ -5,23 +7,54 @@ pub mod handler;
 
 #[derive(Debug, Clone)]
 pub enum Event {
-    Register{username: String}, // reg${{username}}
+    Register{username: String, token: Option<Token>}, // reg${{username}}, or reg${{username}}${{token}} once the name is claimed
     CreateChan{user_id: String, chan_name: String}, // create_chan$123$MyChat
-    JoinChan{user_id: String, chan_id: String}, // join$123$456
+    JoinChan{user_id: String, chan_id: String}, // join$123$456 or join$123$#general
     LeaveChan{user_id: String, chan_id: String}, // leave$123$456
-    SendMsg{user_id: String, chan_id: String, msg: String}, // send_msg$123$456$Hello
+    SendMsg{user_id: String, chan_id: String, msg: String, parent_id: Option<String>}, // send_msg$123$456$Hello, or reply$123$456$789$Hello to reply to 789
+    DeleteChan{user_id: String, chan_id: String}, // delete_chan$123$456
+    ArchiveChan{user_id: String, chan_id: String}, // archive_chan$123$456
+    AddModerator{user_id: String, chan_id: String, target: String}, // add_mod$123$456$bob, by username
+    SlowMode{user_id: String, chan_id: String, secs: u64}, // slow_mode$123$456$5
+    RateLimit{user_id: String, chan_id: String, per_sec: u32}, // rate_limit$123$456$10
+    ReadOnly{user_id: String, chan_id: String, read_only: bool}, // read_only$123$456$on
+    Retention{user_id: String, chan_id: String, policy: String}, // retention$123$456$days=30, or forever or last=1000
+    EditMsg{user_id: String, chan_id: String, msg_id: String, msg: String}, // edit_msg$123$456$789$Hello
+    DeleteMsg{user_id: String, chan_id: String, msg_id: String}, // delete_msg$123$456$789
+    GetThread{user_id: String, chan_id: String, parent_id: String}, // thread$123$456$789
+    React{user_id: String, chan_id: String, msg_id: String, reaction: String}, // react$123$456$789$:+1:
+    Unreact{user_id: String, chan_id: String, msg_id: String, reaction: String}, // unreact$123$456$789$:+1:
+    Typing{user_id: String, chan_id: String}, // typing$123$456
+    MarkRead{user_id: String, chan_id: String, msg_id: Option<String>}, // mark_read$123$456 or mark_read$123$456$789
+    Unread{user_id: String}, // unread$123
+    Snapshot{user_id: String}, // snapshot$123
+    Stats{user_id: String}, // stats$123
+    Search{user_id: String, terms: String, filters: Vec<String>}, // search$123$some link$in=#general$from=alice$after=2025-10-01$before=2025-10-08
+    Export{user_id: String, chan_id: String, format: String}, // export$123$456$md, or jsonl or txt
+    History{user_id: String, chan_id: String, before_id: Option<String>, limit: usize}, // history$123$456$789$20, or history$123$456$$20 for the latest
+    Ping, // ping, only keeps an idle conn open
     Unknown,
 }
 
+/// The secret `reg` carries, left out of the logs events are printed to.
+#[derive(Clone)]
+pub struct Token(pub String);
+
+impl fmt::Debug for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Token(..)")
+    }
+}
+
 impl Event {
     pub fn from_string(line: String) -> Result<Self, ChatErrors> {
-        let parts: Vec<&str> = line.split("$").into_iter().collect();
+        let parts: Vec<&str> = line.split("$").collect();
         match parts[0] {
             "reg" => {
                 if parts.len() < 2 {
                     return Err(ChatErrors::InvalidCommand("reg need username".to_string()));
                 }
-                Ok(Self::Register { username: parts[1].to_string()})
+                Ok(Self::Register { username: parts[1].to_string(), token: parts.get(2).map(|v| Token(v.to_string())) })
             }
 
             "create_chan" => {
@@ -49,10 +82,282 @@ impl Event {
                 if parts.len() < 4 {
                     return Err(ChatErrors::InvalidCommand("send_msg need user id and chan id and msg content".to_string()));
                 }
-                Ok(Self::SendMsg { user_id: parts[1].to_string(), chan_id: parts[2].to_string() , msg: parts[3].to_string() })
+                Ok(Self::SendMsg { user_id: parts[1].to_string(), chan_id: parts[2].to_string() , msg: parts[3..].join("$"), parent_id: None })
+            }
+
+            "reply" => {
+                if parts.len() < 5 {
+                    return Err(ChatErrors::InvalidCommand("reply need user id and chan id and parent msg id and msg content".to_string()));
+                }
+                Ok(Self::SendMsg { user_id: parts[1].to_string(), chan_id: parts[2].to_string(), msg: parts[4..].join("$"), parent_id: Some(parts[3].to_string()) })
+            }
+
+            "delete_chan" => {
+                if parts.len() < 3 {
+                    return Err(ChatErrors::InvalidCommand("delete_chan need user id and chan id".to_string()));
+                }
+                Ok(Self::DeleteChan { user_id: parts[1].to_string(), chan_id: parts[2].to_string() })
+            }
+
+            "archive_chan" => {
+                if parts.len() < 3 {
+                    return Err(ChatErrors::InvalidCommand("archive_chan need user id and chan id".to_string()));
+                }
+                Ok(Self::ArchiveChan { user_id: parts[1].to_string(), chan_id: parts[2].to_string() })
+            }
+
+            "add_mod" => {
+                if parts.len() < 4 {
+                    return Err(ChatErrors::InvalidCommand("add_mod need user id and chan id and target username".to_string()));
+                }
+                Ok(Self::AddModerator { user_id: parts[1].to_string(), chan_id: parts[2].to_string(), target: parts[3].to_string() })
+            }
+
+            "slow_mode" => {
+                if parts.len() < 4 {
+                    return Err(ChatErrors::InvalidCommand("slow_mode need user id and chan id and seconds".to_string()));
+                }
+                let secs = parts[3].parse().map_err(|_| ChatErrors::InvalidCommand(format!("invalid seconds: {}", parts[3])))?;
+                Ok(Self::SlowMode { user_id: parts[1].to_string(), chan_id: parts[2].to_string(), secs })
+            }
+
+            "rate_limit" => {
+                if parts.len() < 4 {
+                    return Err(ChatErrors::InvalidCommand("rate_limit need user id and chan id and messages per second".to_string()));
+                }
+                let per_sec = parts[3].parse().map_err(|_| ChatErrors::InvalidCommand(format!("invalid messages per second: {}", parts[3])))?;
+                Ok(Self::RateLimit { user_id: parts[1].to_string(), chan_id: parts[2].to_string(), per_sec })
+            }
+
+            "read_only" => {
+                if parts.len() < 4 {
+                    return Err(ChatErrors::InvalidCommand("read_only need user id and chan id and on/off".to_string()));
+                }
+                let read_only = match parts[3] {
+                    "on" => true,
+                    "off" => false,
+                    v => return Err(ChatErrors::InvalidCommand(format!("read_only need on/off, got: {}", v))),
+                };
+                Ok(Self::ReadOnly { user_id: parts[1].to_string(), chan_id: parts[2].to_string(), read_only })
+            }
+
+            "edit_msg" => {
+                if parts.len() < 5 {
+                    return Err(ChatErrors::InvalidCommand("edit_msg need user id and chan id and msg id and msg content".to_string()));
+                }
+                Ok(Self::EditMsg { user_id: parts[1].to_string(), chan_id: parts[2].to_string(), msg_id: parts[3].to_string(), msg: parts[4..].join("$") })
+            }
+
+            "delete_msg" => {
+                if parts.len() < 4 {
+                    return Err(ChatErrors::InvalidCommand("delete_msg need user id and chan id and msg id".to_string()));
+                }
+                Ok(Self::DeleteMsg { user_id: parts[1].to_string(), chan_id: parts[2].to_string(), msg_id: parts[3].to_string() })
             }
+
+            "thread" => {
+                if parts.len() < 4 {
+                    return Err(ChatErrors::InvalidCommand("thread need user id and chan id and parent msg id".to_string()));
+                }
+                Ok(Self::GetThread { user_id: parts[1].to_string(), chan_id: parts[2].to_string(), parent_id: parts[3].to_string() })
+            }
+
+            "react" | "unreact" => {
+                if parts.len() < 5 {
+                    return Err(ChatErrors::InvalidCommand(format!("{} need user id and chan id and msg id and reaction", parts[0])));
+                }
+                let (user_id, chan_id, msg_id, reaction) = (parts[1].to_string(), parts[2].to_string(), parts[3].to_string(), parts[4].to_string());
+                if parts[0] == "react" {
+                    Ok(Self::React { user_id, chan_id, msg_id, reaction })
+                } else {
+                    Ok(Self::Unreact { user_id, chan_id, msg_id, reaction })
+                }
+            }
+
+            "typing" => {
+                if parts.len() < 3 {
+                    return Err(ChatErrors::InvalidCommand("typing need user id and chan id".to_string()));
+                }
+                Ok(Self::Typing { user_id: parts[1].to_string(), chan_id: parts[2].to_string() })
+            }
+
+            "mark_read" => {
+                if parts.len() < 3 {
+                    return Err(ChatErrors::InvalidCommand("mark_read need user id and chan id".to_string()));
+                }
+                Ok(Self::MarkRead { user_id: parts[1].to_string(), chan_id: parts[2].to_string(), msg_id: parts.get(3).map(|v| v.to_string()) })
+            }
+
+            "unread" => {
+                if parts.len() < 2 {
+                    return Err(ChatErrors::InvalidCommand("unread need user id".to_string()));
+                }
+                Ok(Self::Unread { user_id: parts[1].to_string() })
+            }
+            "snapshot" => {
+                if parts.len() < 2 {
+                    return Err(ChatErrors::InvalidCommand("snapshot need user id".to_string()));
+                }
+                Ok(Self::Snapshot { user_id: parts[1].to_string() })
+            }
+            "stats" => {
+                if parts.len() < 2 {
+                    return Err(ChatErrors::InvalidCommand("stats need user id".to_string()));
+                }
+                Ok(Self::Stats { user_id: parts[1].to_string() })
+            }
+            "retention" => {
+                if parts.len() < 4 {
+                    return Err(ChatErrors::InvalidCommand("retention need user id and chan id and policy".to_string()));
+                }
+                Ok(Self::Retention { user_id: parts[1].to_string(), chan_id: parts[2].to_string(), policy: parts[3].to_string() })
+            }
+            "search" => {
+                if parts.len() < 3 {
+                    return Err(ChatErrors::InvalidCommand("search need user id and keywords".to_string()));
+                }
+                let filters = parts[3..].iter().map(|f| f.to_string()).collect();
+                Ok(Self::Search { user_id: parts[1].to_string(), terms: parts[2].to_string(), filters })
+            }
+            "export" => {
+                if parts.len() < 4 {
+                    return Err(ChatErrors::InvalidCommand("export need user id and chan id and format".to_string()));
+                }
+                Ok(Self::Export { user_id: parts[1].to_string(), chan_id: parts[2].to_string(), format: parts[3].to_string() })
+            }
+            "history" => {
+                if parts.len() < 5 {
+                    return Err(ChatErrors::InvalidCommand("history need user id and chan id and before msg id and limit".to_string()));
+                }
+                let limit = parts[4].parse().map_err(|_| ChatErrors::InvalidCommand(format!("invalid limit: {}", parts[4])))?;
+                let before_id = (!parts[3].is_empty()).then(|| parts[3].to_string());
+                Ok(Self::History { user_id: parts[1].to_string(), chan_id: parts[2].to_string(), before_id, limit })
+            }
+            "ping" => Ok(Self::Ping),
             _ => Err(ChatErrors::CommandNotSupport(parts[0].to_string()))
         }
     }
+
+    /// The user the client claims to act as, checked against the connection's
+    /// user before the event is handled.
+    pub fn user_id(&self) -> Option<&str> {
+        match self {
+            Self::CreateChan { user_id, .. }
+            | Self::JoinChan { user_id, .. }
+            | Self::LeaveChan { user_id, .. }
+            | Self::SendMsg { user_id, .. }
+            | Self::DeleteChan { user_id, .. }
+            | Self::ArchiveChan { user_id, .. }
+            | Self::AddModerator { user_id, .. }
+            | Self::SlowMode { user_id, .. }
+            | Self::RateLimit { user_id, .. }
+            | Self::ReadOnly { user_id, .. }
+            | Self::Retention { user_id, .. }
+            | Self::EditMsg { user_id, .. }
+            | Self::DeleteMsg { user_id, .. }
+            | Self::GetThread { user_id, .. }
+            | Self::React { user_id, .. }
+            | Self::Unreact { user_id, .. }
+            | Self::Typing { user_id, .. }
+            | Self::MarkRead { user_id, .. }
+            | Self::Unread { user_id, .. }
+            | Self::Snapshot { user_id, .. }
+            | Self::Stats { user_id, .. }
+            | Self::History { user_id, .. }
+            | Self::Search { user_id, .. }
+            | Self::Export { user_id, .. } => Some(user_id),
+            Self::Register { .. } | Self::Ping | Self::Unknown => None,
+        }
+    }
+
+    /// Whether the event can run with the service locked shared: it posts to,
+    /// changes messages of or reads from one chan, without touching users,
+    /// memberships or chan settings.
+    pub fn is_shared(&self) -> bool {
+        matches!(
+            self,
+            Self::SendMsg { .. }
+                | Self::EditMsg { .. }
+                | Self::DeleteMsg { .. }
+                | Self::GetThread { .. }
+                | Self::React { .. }
+                | Self::Unreact { .. }
+                | Self::Typing { .. }
+                | Self::MarkRead { .. }
+                | Self::Unread { .. }
+                | Self::Stats { .. }
+                | Self::Search { .. }
+                | Self::Export { .. }
+                | Self::History { .. }
+                | Self::Ping
+                | Self::Unknown
+        )
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Event {
+        Event::from_string(line.to_string()).unwrap()
+    }
+
+    #[test]
+    fn send_msg_keeps_dollars_in_content() {
+        match parse("send_msg$1$2$costs $5$ today") {
+            Event::SendMsg { user_id, chan_id, msg, parent_id } => {
+                assert_eq!((user_id.as_str(), chan_id.as_str()), ("1", "2"));
+                assert_eq!(msg, "costs $5$ today");
+                assert_eq!(parent_id, None);
+            }
+            event => panic!("unexpected event: {:?}", event),
+        }
+    }
+
+    #[test]
+    fn reply_takes_parent_before_content() {
+        match parse("reply$1$2$789$a $ b") {
+            Event::SendMsg { msg, parent_id, .. } => {
+                assert_eq!(msg, "a $ b");
+                assert_eq!(parent_id.as_deref(), Some("789"));
+            }
+            event => panic!("unexpected event: {:?}", event),
+        }
+    }
+
+    #[test]
+    fn reply_needs_content() {
+        assert!(matches!(
+            Event::from_string("reply$1$2$789".to_string()),
+            Err(ChatErrors::InvalidCommand(_))
+        ));
+    }
+
+    #[test]
+    fn edit_msg_keeps_dollars_in_content() {
+        match parse("edit_msg$1$2$3$x$y") {
+            Event::EditMsg { msg_id, msg, .. } => assert_eq!((msg_id.as_str(), msg.as_str()), ("3", "x$y")),
+            event => panic!("unexpected event: {:?}", event),
+        }
+    }
+
+    #[test]
+    fn history_with_empty_before_id() {
+        match parse("history$123$456$$20") {
+            Event::History { user_id, chan_id, before_id, limit } => {
+                assert_eq!((user_id.as_str(), chan_id.as_str()), ("123", "456"));
+                assert_eq!(before_id, None);
+                assert_eq!(limit, 20);
+            }
+            event => panic!("unexpected event: {:?}", event),
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_and_short_commands() {
+        assert!(matches!(Event::from_string("nope$1".to_string()), Err(ChatErrors::CommandNotSupport(_))));
+        assert!(matches!(Event::from_string("join$1".to_string()), Err(ChatErrors::InvalidCommand(_))));
+        assert!(matches!(Event::from_string("read_only$1$2$maybe".to_string()), Err(ChatErrors::InvalidCommand(_))));
+    }
+}
@@ -0,0 +1,170 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::chatsvc::Message;
+use crate::errors::ChatErrors;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Jsonl,
+    Markdown,
+    Text,
+}
+
+impl ExportFormat {
+    /// File extension the client and the CLI use by default.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Jsonl => "jsonl",
+            ExportFormat::Markdown => "md",
+            ExportFormat::Text => "txt",
+        }
+    }
+}
+
+impl fmt::Display for ExportFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.extension())
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = ChatErrors;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "jsonl" | "json" => Ok(ExportFormat::Jsonl),
+            "md" | "markdown" => Ok(ExportFormat::Markdown),
+            "txt" | "text" => Ok(ExportFormat::Text),
+            _ => Err(ChatErrors::InvalidArgument(format!(
+                "unknown export format: {}, expect jsonl, md or txt",
+                s
+            ))),
+        }
+    }
+}
+
+/// One JSON Lines record.
+#[derive(Serialize)]
+struct Record<'a> {
+    id: &'a str,
+    chan: &'a str,
+    sender: &'a str,
+    sender_id: &'a str,
+    send_time: DateTime<Utc>,
+    content: &'a str,
+    parent_id: Option<&'a str>,
+    edited: bool,
+    deleted: bool,
+}
+
+/// Transcript of `messages` (oldest first), one output line per element so it
+/// can be sent over the line based protocol as is.
+pub fn render(chan_name: &str, messages: &[Message], format: ExportFormat) -> Vec<String> {
+    let mut lines = Vec::with_capacity(messages.len() + 2);
+    if format == ExportFormat::Markdown {
+        lines.push(format!("# #{} transcript", chan_name));
+        lines.push(String::new());
+    }
+
+    for msg in messages {
+        let line = match format {
+            ExportFormat::Jsonl => {
+                let record = Record {
+                    id: &msg.id,
+                    chan: chan_name,
+                    sender: &msg.sender,
+                    sender_id: &msg.sender_id,
+                    send_time: msg.send_time,
+                    content: &msg.content,
+                    parent_id: msg.parent_id.as_deref(),
+                    edited: msg.edited,
+                    deleted: msg.deleted,
+                };
+                serde_json::to_string(&record).unwrap_or_default()
+            }
+            ExportFormat::Markdown => format!(
+                "- `{}` **{}**{}: {}",
+                msg.send_time.format("%Y-%m-%d %H:%M:%S UTC"),
+                msg.sender,
+                msg.parent_id.as_ref().map_or(String::new(), |p| format!(" (reply to {})", p)),
+                body(msg),
+            ),
+            ExportFormat::Text => format!(
+                "[{}] {}{}: {}",
+                msg.send_time.format("%Y-%m-%d %H:%M:%S"),
+                msg.sender,
+                msg.parent_id.as_ref().map_or(String::new(), |p| format!(" (reply to {})", p)),
+                body(msg),
+            ),
+        };
+        lines.push(line);
+    }
+
+    lines
+}
+
+fn body(msg: &Message) -> String {
+    if msg.deleted {
+        "(deleted)".to_string()
+    } else if msg.edited {
+        format!("{} (edited)", msg.content)
+    } else {
+        msg.content.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(content: &str) -> Message {
+        let mut msg = Message::from_user("u1".to_string(), "alice".to_string(), "c1".to_string(), content.to_string());
+        msg.id = "0000000000010000".to_string();
+        msg.send_time = "2024-03-01T09:30:00Z".parse().unwrap();
+        msg
+    }
+
+    #[test]
+    fn parses_formats_and_aliases() {
+        for (name, format) in [("JSON", ExportFormat::Jsonl), ("markdown", ExportFormat::Markdown), ("txt", ExportFormat::Text)] {
+            assert_eq!(name.parse::<ExportFormat>().unwrap(), format);
+            assert_eq!(format.to_string().parse::<ExportFormat>().unwrap(), format);
+        }
+        assert!("csv".parse::<ExportFormat>().is_err());
+    }
+
+    #[test]
+    fn renders_each_format() {
+        let mut reply = msg("fixed");
+        reply.parent_id = Some("0000000000000001".to_string());
+        reply.edited = true;
+        let mut gone = msg("secret");
+        gone.deleted = true;
+        let messages = [msg("hi"), reply, gone];
+
+        assert_eq!(
+            render("room", &messages, ExportFormat::Text),
+            [
+                "[2024-03-01 09:30:00] alice: hi",
+                "[2024-03-01 09:30:00] alice (reply to 0000000000000001): fixed (edited)",
+                "[2024-03-01 09:30:00] alice: (deleted)",
+            ]
+        );
+
+        let md = render("room", &messages, ExportFormat::Markdown);
+        assert_eq!(md[..3], ["# #room transcript", "", "- `2024-03-01 09:30:00 UTC` **alice**: hi"]);
+        assert_eq!(md.len(), 5);
+
+        let jsonl = render("room", &messages, ExportFormat::Jsonl);
+        let record: serde_json::Value = serde_json::from_str(&jsonl[1]).unwrap();
+        assert_eq!(record["chan"], "room");
+        assert_eq!(record["parent_id"], "0000000000000001");
+        assert_eq!(record["edited"], true);
+        // deleted content is already cleared by the service, the flag says why
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&jsonl[2]).unwrap()["deleted"], true);
+    }
+}
@@ -1,3 +1,7 @@
 pub mod event;
 pub mod chatsvc;
-pub mod errors;
\ No newline at end of file
+pub mod errors;
+pub mod config;
+pub mod storage;
+pub mod search;
+pub mod export;
@@ -1,15 +1,26 @@
+use std::pin::pin;
 use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
 
 use anyhow::anyhow;
 use futures::{SinkExt, StreamExt};
-use tokio::io::{self, WriteHalf};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWriteExt, WriteHalf};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::broadcast::Sender;
-use tokio::sync::{RwLock, broadcast};
-use tokio_util::codec::{FramedRead, FramedWrite, LinesCodec};
+use tokio::sync::RwLock;
+use tokio::task::JoinSet;
+use tokio::time::{Instant, timeout, timeout_at};
+use tokio_util::codec::{FramedRead, FramedWrite, LinesCodec, LinesCodecError};
+use tokio_util::sync::CancellationToken;
 use tracing::{info, level_filters::LevelFilter, warn};
 use tracing_subscriber::{Layer as _, fmt::Layer, layer::SubscriberExt, util::SubscriberInitExt};
-use txt_chat::chatsvc::Message;
+use txt_chat::chatsvc::{
+    ConnLimits, ERROR_RESP, KICK_WRITE_TIMEOUT, LINGER_TIMEOUT, Outbox, PRUNE_INTERVAL, hash_token, write_outbox,
+};
+use txt_chat::config::{ExportConfig, ServerConfig};
+use txt_chat::errors::ChatErrors;
+use txt_chat::export::{self, ExportFormat};
+use txt_chat::storage::{Snapshot, SqliteStorage, Storage, WalStorage};
 use txt_chat::{
     chatsvc::ChatService,
     event::{Event, handler::handle_event},
@@ -17,77 +28,294 @@ use txt_chat::{
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    if std::env::args().nth(1).as_deref() == Some("export") {
+        // keep stdout for the transcript
+        let layer = Layer::new().with_writer(std::io::stderr).with_filter(LevelFilter::INFO);
+        tracing_subscriber::registry().with(layer).init();
+        return export_chan(ExportConfig::from_args(std::env::args())?);
+    }
+
     let layer = Layer::new().with_filter(LevelFilter::INFO);
     tracing_subscriber::registry().with(layer).init();
 
-    let addr = "0.0.0.0:9090";
+    let config = ServerConfig::from_args(std::env::args())?;
+    let addr = config.addr.as_str();
     let listener = TcpListener::bind(&addr)
         .await
         .map_err(|e| anyhow!("Faile to listen on {}: {}", addr, e))?;
 
     info!("server listen on: {}", addr);
 
-    let (tx, _) = broadcast::channel::<Message>(1000);
-    let tx1 = tx.clone();
-    let chat_sevice = Arc::new(RwLock::new(ChatService::new(1000, tx)));
+    let mut svc = ChatService::new(1000);
+    svc.history_size = config.history_size;
+    if let Some(path) = config.sqlite_path.as_ref() {
+        svc.load_storage(Box::new(SqliteStorage::open(path)?))?;
+        info!("using sqlite storage: {}", path);
+    }
+    if let Some(path) = config.wal_path.as_ref() {
+        svc.load_storage(Box::new(WalStorage::open(path)?))?;
+        info!("using wal storage: {}", path);
+    }
+    if let Some(path) = config.restore_path.as_ref() {
+        svc.restore(Snapshot::load(path)?)?;
+    }
+    svc.snapshot_path = config.snapshot_path.clone();
+    svc.offline_queue_cap = config.offline_queue_cap;
+    svc.queue_all_offline = config.queue_all_offline;
+    svc.max_lag = config.max_lag;
+    svc.max_msg_len = config.max_msg_len;
+    for name in config.default_chans.iter() {
+        svc.add_default_chan(name.clone(), config.is_read_only(name))?;
+    }
+    svc.admins.extend(config.admins.iter().map(|name| name.to_lowercase()));
+    if let Some(path) = config.admin_key_file.as_ref() {
+        let key = std::fs::read_to_string(path).map_err(|e| anyhow!("failed to read admin key {}: {}", path, e))?;
+        if key.trim().is_empty() {
+            return Err(anyhow!("admin key file {} is empty", path));
+        }
+        svc.admin_key_hash = Some(hash_token(key.trim()));
+    }
+    let conn_metrics = svc.conn_metrics.clone();
+    let chat_sevice = Arc::new(RwLock::new(svc));
 
+    let prune_svc = chat_sevice.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+        loop {
+            interval.tick().await;
+            // pruning writes to storage, keep it off the async workers
+            let svc = prune_svc.clone();
+            let _ = tokio::task::spawn_blocking(move || svc.blocking_write().prune_history()).await;
+        }
+    });
+
+    let limits = Arc::new(ConnLimits::new(config.max_conns, config.max_conns_per_ip));
+    let config = Arc::new(config);
+    let shutdown = CancellationToken::new();
+    let mut conns = JoinSet::new();
+    let mut signal = pin!(shutdown_signal());
     loop {
-        match listener.accept().await {
-            Ok((socket, client_addr)) => {
-                info!("accept conn from: {}", client_addr.clone());
-
-                let uid = format!("{:?}", client_addr);
-                let tx_clone = tx1.clone();
-                let svc = chat_sevice.clone();
-                tokio::spawn(async move {
-                    serve_conn(socket, uid, tx_clone, &svc).await;
-                });
+        tokio::select! {
+            res = listener.accept() => match res {
+                Ok((socket, client_addr)) => {
+                    info!("accept conn from: {}", client_addr.clone());
+
+                    let permit = match limits.admit(client_addr.ip()) {
+                        Ok(permit) => permit,
+                        Err(e) => {
+                            warn!("reject conn from: {}, {}", client_addr, e);
+                            conn_metrics.rejected.fetch_add(1, Ordering::Relaxed);
+                            // tracked with the conns, so shutdown waits for it too
+                            conns.spawn(reject_conn(socket, e));
+                            continue;
+                        }
+                    };
+                    let conn_id = format!("{:?}", client_addr);
+                    let svc = chat_sevice.clone();
+                    let config = config.clone();
+                    let shutdown = shutdown.clone();
+                    conns.spawn(async move {
+                        serve_conn(socket, conn_id, &svc, &config, &shutdown).await;
+                        drop(permit);
+                    });
+                }
+                Err(e) => warn!("Faield to accept conn: {}", e),
+            },
+            // reap closed conns, so only live ones are waited for on shutdown
+            Some(_) = conns.join_next(), if !conns.is_empty() => {}
+            name = &mut signal => {
+                info!("received {}, shutting down", name);
+                break;
             }
-            Err(e) => warn!("Faield to accept conn: {}", e),
         }
     }
+
+    // stop accepting, tell every client, then let each conn send what is
+    // queued for it and close, all within the shutdown timeout
+    drop(listener);
+    let deadline = Instant::now() + config.shutdown_timeout;
+    let announce = chat_sevice.read().await.announce_shutdown();
+    if timeout_at(deadline, announce).await.is_err() {
+        warn!("shutdown notice not queued for every conn after {:?}", config.shutdown_timeout);
+    }
+    shutdown.cancel();
+    let drain = async { while conns.join_next().await.is_some() {} };
+    if timeout_at(deadline, drain).await.is_err() {
+        warn!("{} conns still open after {:?}, closing them", conns.len(), config.shutdown_timeout);
+        conns.shutdown().await;
+    }
+
+    if let Err(e) = chat_sevice.read().await.flush_storage() {
+        warn!("failed to flush storage: {}", e);
+    }
+    info!("server stopped");
+    Ok(())
+}
+
+/// Wait for SIGINT, or SIGTERM on unix, returning its name.
+async fn shutdown_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => tokio::select! {
+                _ = ctrl_c() => "SIGINT",
+                _ = term.recv() => "SIGTERM",
+            },
+            Err(e) => {
+                warn!("failed to listen for SIGTERM: {}", e);
+                ctrl_c().await;
+                "SIGINT"
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        ctrl_c().await;
+        "SIGINT"
+    }
+}
+
+async fn ctrl_c() {
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        // never shut down because of it
+        warn!("failed to listen for SIGINT: {}", e);
+        std::future::pending::<()>().await;
+    }
+}
+
+/// `txt-chat export ...`: write a chan's full transcript from storage and exit.
+fn export_chan(config: ExportConfig) -> anyhow::Result<()> {
+    let format: ExportFormat = config.format.parse()?;
+    let storage: Box<dyn Storage> = match (config.sqlite_path.as_ref(), config.wal_path.as_ref()) {
+        (Some(path), _) => Box::new(SqliteStorage::open(path)?),
+        (_, Some(path)) => Box::new(WalStorage::read_only(path)?),
+        _ => unreachable!("checked by ExportConfig::from_args"),
+    };
+
+    let mut svc = ChatService::new(0);
+    svc.load_storage(storage)?;
+    let chan_id = svc.resolve_chan(config.chan.clone())?;
+    let chan = svc
+        .channels
+        .get(&chan_id)
+        .ok_or_else(|| anyhow!("chan: {} not found", config.chan))?;
+
+    let history = svc.chan_history(&chan_id)?;
+    let mut transcript = export::render(&chan.name, &history, format).join("\n");
+    transcript.push('\n');
+    match config.out.as_ref() {
+        Some(path) => {
+            std::fs::write(path, transcript)?;
+            info!("exported {} messages of chan: {} to {}", history.len(), chan.label(), path);
+        }
+        None => print!("{}", transcript),
+    }
+    Ok(())
+}
+
+/// Tell a conn over `ConnLimits` why it is closed.
+async fn reject_conn(socket: TcpStream, err: ChatErrors) {
+    let mut framed_write = FramedWrite::new(socket, LinesCodec::new());
+    let _ = timeout(KICK_WRITE_TIMEOUT, framed_write.send(format!("{}: {}", ERROR_RESP, err))).await;
+    let _ = framed_write.get_mut().shutdown().await;
+    linger(framed_write.into_inner()).await;
+}
+
+/// Read and drop what the client still sends until it closes too: closing with
+/// unread data resets the conn, which can lose the last line sent to it.
+async fn linger(mut reader: impl AsyncRead + Unpin) {
+    let mut buf = [0; 4096];
+    let _ = timeout(LINGER_TIMEOUT, async {
+        while reader.read(&mut buf).await.is_ok_and(|n| n > 0) {}
+    })
+    .await;
+}
+
+/// Sleep until `timeout` after `since`, or never return without a timeout.
+async fn expire(timeout: Option<Duration>, since: Instant) -> Option<Duration> {
+    let timeout = timeout?;
+    tokio::time::sleep_until(since + timeout).await;
+    Some(timeout)
 }
 
 async fn serve_conn(
     socket: TcpStream,
-    uid: String,
-    tx: Sender<Message>,
+    conn_id: String,
     chat_sevice: &Arc<RwLock<ChatService>>,
+    config: &ServerConfig,
+    shutdown: &CancellationToken,
 ) {
     // Split the socket into read and write halves
     let (reader, writer) = io::split(socket);
 
-    let mut framed_read = FramedRead::new(reader, LinesCodec::new());
+    // a longer line fails the read rather than being buffered without bound
+    let mut framed_read = FramedRead::new(reader, LinesCodec::new_with_max_length(config.max_line_len));
     let mut framed_write = FramedWrite::new(writer, LinesCodec::new());
 
-    let user_id1 = uid.clone();
-    let mut rx = tx.subscribe();
-
+    let mut outbox = chat_sevice.write().await.connect(conn_id.clone());
+    let kick = outbox.lag.kick.clone();
     let svc1 = chat_sevice.clone();
     let svc2 = chat_sevice.clone();
 
-    tokio::spawn(async move {
-        info!("recv msg for user: {}", user_id1);
-        recv_msg(svc1, user_id1, &mut rx, &mut framed_write).await;
+    let conn_id1 = conn_id.clone();
+    let mut writer = tokio::spawn(async move {
+        info!("recv msg for conn: {}", conn_id1);
+        recv_msg(svc1, &mut outbox, &mut framed_write).await;
     });
 
+    let connected = Instant::now();
+    let mut registered = false;
+    let mut close_err = None;
     loop {
-        match framed_read.next().await {
+        // register within the handshake timeout, then send a line at least every idle timeout
+        let (limit, since) = if registered {
+            (config.idle_timeout, Instant::now())
+        } else {
+            (config.handshake_timeout, connected)
+        };
+        // a reader kicked for lagging stops being served too
+        let frame = tokio::select! {
+            _ = kick.cancelled() => {
+                warn!("conn: {} kicked for lagging", conn_id);
+                break;
+            }
+            frame = framed_read.next() => frame,
+            // the shutdown notice is already queued
+            _ = shutdown.cancelled() => break,
+            Some(limit) = expire(limit, since) => {
+                close_err = Some(if registered {
+                    ChatErrors::IdleTimeout(limit)
+                } else {
+                    ChatErrors::HandshakeTimeout(limit)
+                });
+                break;
+            }
+        };
+        match frame {
             Some(frame_res) => match frame_res {
                 Ok(message) => {
-                    info!("read message from client: {:?}", message);
-
+                    // not the raw line, `reg` carries the user's token
                     let event = Event::from_string(message);
                     match event {
+                        // reading it was enough to reset the idle timer
+                        Ok(Event::Ping) => {}
                         Ok(ev) => {
                             info!("handle event: {:?}", ev);
-                            handle_event(uid.clone(), svc2.clone(), ev).await;
+                            let is_register = matches!(ev, Event::Register { .. });
+                            handle_event(conn_id.clone(), svc2.clone(), ev).await;
+                            // a failed register, say a taken name, still needs one within the handshake
+                            registered = registered || (is_register && svc2.read().await.session(&conn_id).is_some());
                         }
                         Err(e) => {
                             warn!("error: {}", e);
                         }
                     }
                 }
+                Err(LinesCodecError::MaxLineLengthExceeded) => {
+                    close_err = Some(ChatErrors::LineTooLong(config.max_line_len));
+                    break;
+                }
                 Err(e) => {
                     warn!("fail read frame: {:?}", e);
                     break;
@@ -99,12 +327,35 @@ async fn serve_conn(
             }
         }
     }
+
+    if let Some(err) = close_err {
+        warn!("close conn: {}, {}", conn_id, err);
+        let svc = svc2.read().await;
+        let counter = match err {
+            ChatErrors::LineTooLong(_) => &svc.conn_metrics.too_long,
+            _ => &svc.conn_metrics.timed_out,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        svc.send_to_conn(&conn_id, format!("{}: {}", ERROR_RESP, err));
+    }
+    svc2.write().await.disconnect(&conn_id);
+
+    // the outbox is closed now, so the writer sends what is left and shuts down,
+    // on shutdown with as long as the server waits for conns to drain
+    let flush = if shutdown.is_cancelled() {
+        config.shutdown_timeout
+    } else {
+        LINGER_TIMEOUT
+    };
+    if timeout(flush, &mut writer).await.is_err() {
+        writer.abort();
+    }
+    linger(framed_read.into_inner()).await;
 }
 
 async fn recv_msg(
     svc: Arc<RwLock<ChatService>>,
-    uid: String,
-    rx: &mut broadcast::Receiver<Message>,
+    outbox: &mut Outbox,
     framed_write: &mut FramedWrite<WriteHalf<TcpStream>, LinesCodec>,
 ) {
     let _ = framed_write
@@ -112,17 +363,51 @@ async fn recv_msg(
         .await
         .map_err(|e| anyhow!("Failed to send response: {}", e));
 
-    while let Ok(msg) = rx.recv().await {
-        let svc = svc.read().await;
-        if !svc.is_user_sub(&uid, &msg.chan_id) {
-            drop(svc);
-            continue;
+    // ends once the service drops the conn's sender on disconnect, or the reader is kicked
+    write_outbox(&svc, outbox, framed_write).await;
+    let _ = framed_write.get_mut().shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_util::codec::Framed;
+
+    use super::*;
+
+    /// Serve one conn of a fresh service, returning the client end of it.
+    async fn connect(config: ServerConfig) -> Framed<TcpStream, LinesCodec> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let svc = Arc::new(RwLock::new(ChatService::new(16)));
+            serve_conn(socket, "c1".to_string(), &svc, &config, &CancellationToken::new()).await;
+        });
+        Framed::new(TcpStream::connect(addr).await.unwrap(), LinesCodec::new())
+    }
+
+    #[tokio::test]
+    async fn pings_keep_a_passive_reader_connected() {
+        let idle = Duration::from_millis(300);
+        let mut client = connect(ServerConfig {
+            idle_timeout: Some(idle),
+            ..ServerConfig::default()
+        })
+        .await;
+        client.send("reg$alice".to_string()).await.unwrap();
+        for _ in 0..6 {
+            tokio::time::sleep(idle / 3).await;
+            client.send("ping".to_string()).await.unwrap();
         }
-        drop(svc);
 
-        let _ = framed_write
-            .send(msg.to_string())
-            .await
-            .map_err(|e| anyhow!("Failed to send response: {}", e));
+        // silent from here on, so it is closed a full idle timeout after the last ping
+        let last_ping = Instant::now();
+        let mut lines = Vec::new();
+        while let Some(line) = timeout(idle * 10, client.next()).await.unwrap() {
+            lines.push(line.unwrap());
+        }
+        assert!(last_ping.elapsed() >= idle, "closed after {:?}", last_ping.elapsed());
+        let errors: Vec<&String> = lines.iter().filter(|line| line.starts_with(ERROR_RESP)).collect();
+        assert_eq!(errors, [&format!("{}: {}", ERROR_RESP, ChatErrors::IdleTimeout(idle))]);
     }
 }
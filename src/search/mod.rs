@@ -0,0 +1,299 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use chrono::{DateTime, NaiveDate, Utc};
+
+use crate::chatsvc::Message;
+use crate::errors::ChatErrors;
+
+pub const MAX_SEARCH_RESULTS: usize = 50;
+pub const SEARCH_OVERFETCH: usize = 10; // ids taken past MAX_SEARCH_RESULTS, in case some can no longer be loaded
+
+/// What the index keeps per message to apply filters without loading it.
+#[derive(Debug)]
+struct Doc {
+    chan_id: String,
+    sender_id: String,
+    sender: String,
+    send_time: DateTime<Utc>,
+    terms: HashSet<String>,
+}
+
+/// Inverted index over user messages: term -> ids of the messages containing it.
+/// Ids are time sortable, so iterating a posting list backwards goes newest first.
+#[derive(Debug, Default)]
+pub struct SearchIndex {
+    postings: HashMap<String, BTreeSet<String>>,
+    docs: HashMap<String, Doc>,
+    chan_docs: HashMap<String, BTreeSet<String>>, // chan id -> its indexed message ids
+    max_docs_per_chan: Option<usize>,             // oldest messages dropped beyond it
+}
+
+/// `search$<uid>$<terms>` followed by optional `in=<chan>`, `from=<username>`,
+/// `after=<date>` and `before=<date>` parts. Dates are `YYYY-MM-DD` or RFC 3339.
+#[derive(Debug, Clone, Default)]
+pub struct SearchQuery {
+    pub terms: String,
+    pub chan: Option<String>,
+    pub sender: Option<String>,
+    pub after: Option<DateTime<Utc>>,
+    pub before: Option<DateTime<Utc>>,
+}
+
+impl SearchQuery {
+    pub fn parse(terms: &str, filters: &[String]) -> Result<Self, ChatErrors> {
+        let mut query = Self {
+            terms: terms.to_string(),
+            ..Self::default()
+        };
+
+        for filter in filters.iter().filter(|f| !f.is_empty()) {
+            let (key, value) = filter
+                .split_once('=')
+                .ok_or_else(|| ChatErrors::InvalidArgument(format!("invalid search filter: {}", filter)))?;
+            match key {
+                "in" => query.chan = Some(value.to_string()),
+                "from" => query.sender = Some(value.to_string()),
+                "after" => query.after = Some(parse_date(value)?),
+                "before" => query.before = Some(parse_date(value)?),
+                _ => return Err(ChatErrors::InvalidArgument(format!("unknown search filter: {}", key))),
+            }
+        }
+
+        Ok(query)
+    }
+}
+
+impl SearchIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Index only the newest `max_docs_per_chan` messages of each chan, the
+    /// same bound as a bounded `MemoryStorage` so the index does not outgrow it.
+    pub fn bounded(max_docs_per_chan: usize) -> Self {
+        Self {
+            max_docs_per_chan: Some(max_docs_per_chan),
+            ..Self::default()
+        }
+    }
+
+    /// Drop every message, keeping the bound.
+    pub fn clear(&mut self) {
+        self.postings.clear();
+        self.docs.clear();
+        self.chan_docs.clear();
+    }
+
+    /// Index a new message, or re-index an edited one.
+    pub fn add(&mut self, msg: &Message) {
+        self.remove(&msg.id);
+        if msg.is_cmd || msg.deleted {
+            return;
+        }
+
+        let terms = tokenize(&msg.content);
+        for term in terms.iter() {
+            self.postings
+                .entry(term.clone())
+                .or_default()
+                .insert(msg.id.clone());
+        }
+        self.docs.insert(
+            msg.id.clone(),
+            Doc {
+                chan_id: msg.chan_id.clone(),
+                sender_id: msg.sender_id.clone(),
+                sender: msg.sender.clone(),
+                send_time: msg.send_time,
+                terms,
+            },
+        );
+
+        let ids = self.chan_docs.entry(msg.chan_id.clone()).or_default();
+        ids.insert(msg.id.clone());
+        let evicted: Vec<String> = match self.max_docs_per_chan {
+            Some(max) if ids.len() > max => ids.iter().take(ids.len() - max).cloned().collect(),
+            _ => Vec::new(),
+        };
+        for id in evicted {
+            self.remove(&id);
+        }
+    }
+
+    pub fn remove(&mut self, msg_id: &str) {
+        let Some(doc) = self.docs.remove(msg_id) else {
+            return;
+        };
+        if let Some(ids) = self.chan_docs.get_mut(&doc.chan_id) {
+            ids.remove(msg_id);
+            if ids.is_empty() {
+                self.chan_docs.remove(&doc.chan_id);
+            }
+        }
+        for term in doc.terms {
+            if let Some(ids) = self.postings.get_mut(&term) {
+                ids.remove(msg_id);
+                if ids.is_empty() {
+                    self.postings.remove(&term);
+                }
+            }
+        }
+    }
+
+    pub fn remove_chan(&mut self, chan_id: &str) {
+        for id in self.chan_docs.remove(chan_id).unwrap_or_default() {
+            self.remove(&id);
+        }
+    }
+
+    /// Ids of messages in `chans` containing every term of the query, newest
+    /// first. Lazy, so callers can skip ids they can no longer load and still
+    /// fill a page.
+    pub fn search<'a>(
+        &'a self,
+        query: &'a SearchQuery,
+        chans: &'a HashSet<String>,
+    ) -> impl Iterator<Item = &'a String> + 'a {
+        let terms = tokenize(&query.terms);
+        let mut postings = Vec::with_capacity(terms.len());
+        for term in terms.iter() {
+            match self.postings.get(term) {
+                Some(ids) => postings.push(ids),
+                None => {
+                    postings.clear();
+                    break;
+                }
+            }
+        }
+        // walk the shortest list, probe the others
+        postings.sort_by_key(|ids| ids.len());
+        let (shortest, rest) = match postings.split_first() {
+            Some((shortest, rest)) => (Some(*shortest), rest.to_vec()),
+            None => (None, Vec::new()),
+        };
+
+        shortest
+            .into_iter()
+            .flat_map(|ids| ids.iter().rev())
+            .filter(move |id| rest.iter().all(|ids| ids.contains(*id)))
+            .filter(|id| self.docs.get(*id).is_some_and(|doc| doc.matches(query, chans)))
+    }
+}
+
+impl Doc {
+    fn matches(&self, query: &SearchQuery, chans: &HashSet<String>) -> bool {
+        chans.contains(&self.chan_id)
+            && query.sender.as_ref().is_none_or(|sender| {
+                sender.eq_ignore_ascii_case(&self.sender) || *sender == self.sender_id
+            })
+            && query.after.is_none_or(|after| self.send_time >= after)
+            && query.before.is_none_or(|before| self.send_time < before)
+    }
+}
+
+/// Lowercased alphanumeric runs, so `https://example.com/a` is found by `example.com`.
+pub fn tokenize(text: &str) -> HashSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|term| !term.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+fn parse_date(value: &str) -> Result<DateTime<Utc>, ChatErrors> {
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Ok(date.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc());
+    }
+    DateTime::parse_from_rfc3339(value)
+        .map(|t| t.with_timezone(&Utc))
+        .map_err(|_| ChatErrors::InvalidArgument(format!("invalid date: {}, expect YYYY-MM-DD", value)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(sender: &str, content: &str) -> Message {
+        Message::from_user(format!("{}-id", sender), sender.to_string(), "room".to_string(), content.to_string())
+    }
+
+    fn search(index: &SearchIndex, terms: &str, filters: &[&str]) -> Vec<String> {
+        let filters: Vec<String> = filters.iter().map(|f| f.to_string()).collect();
+        let query = SearchQuery::parse(terms, &filters).unwrap();
+        let chans = HashSet::from(["room".to_string()]);
+        index.search(&query, &chans).cloned().collect()
+    }
+
+    #[test]
+    fn tokenizes_on_anything_but_alphanumerics() {
+        let terms = tokenize("See https://Example.com/a, ok?");
+        let expected: HashSet<String> = ["see", "https", "example", "com", "a", "ok"].map(String::from).into();
+        assert_eq!(terms, expected);
+    }
+
+    #[test]
+    fn parses_filters() {
+        let filters = ["in=#room", "from=alice", "after=2024-01-02", "before=2024-02-01T10:00:00Z", ""].map(String::from);
+        let query = SearchQuery::parse("deploy", &filters).unwrap();
+        assert_eq!(query.chan.as_deref(), Some("#room"));
+        assert_eq!(query.sender.as_deref(), Some("alice"));
+        assert_eq!(query.after.unwrap().to_rfc3339(), "2024-01-02T00:00:00+00:00");
+        assert_eq!(query.before.unwrap().to_rfc3339(), "2024-02-01T10:00:00+00:00");
+
+        for filter in ["to=bob", "after=yesterday", "alice"] {
+            assert!(SearchQuery::parse("deploy", &[filter.to_string()]).is_err(), "{}", filter);
+        }
+    }
+
+    #[test]
+    fn finds_messages_with_every_term_newest_first() {
+        let mut index = SearchIndex::new();
+        let first = msg("alice", "deploy the api");
+        let second = msg("bob", "Deploy the web app");
+        let third = msg("alice", "api is down");
+        for m in [&first, &second, &third] {
+            index.add(m);
+        }
+
+        assert_eq!(search(&index, "deploy", &[]), [second.id.clone(), first.id.clone()]);
+        assert_eq!(search(&index, "deploy api", &[]), vec![first.id.clone()]);
+        assert!(search(&index, "deploy nothing", &[]).is_empty());
+        assert_eq!(search(&index, "deploy", &["from=ALICE"]), vec![first.id.clone()]);
+        assert_eq!(search(&index, "api", &["from=alice-id"]), [third.id.clone(), first.id.clone()]);
+        assert!(search(&index, "api", &["after=2999-01-01"]).is_empty());
+        assert_eq!(search(&index, "api", &["before=2999-01-01"]).len(), 2);
+    }
+
+    #[test]
+    fn reindexes_edits_and_drops_deleted_and_command_messages() {
+        let mut index = SearchIndex::new();
+        let mut m = msg("alice", "old words");
+        index.add(&m);
+        m.content = "new words".to_string();
+        index.add(&m);
+        assert!(search(&index, "old", &[]).is_empty());
+        assert_eq!(search(&index, "new words", &[]), vec![m.id.clone()]);
+
+        m.deleted = true;
+        index.add(&m);
+        assert!(search(&index, "words", &[]).is_empty());
+        assert!(index.postings.is_empty() && index.docs.is_empty());
+
+        index.add(&Message::new("server".to_string(), "room".to_string(), "$$joined: words".to_string()));
+        assert!(search(&index, "words", &[]).is_empty());
+    }
+
+    #[test]
+    fn bounded_drops_the_oldest_per_chan() {
+        let mut index = SearchIndex::bounded(2);
+        let msgs = [msg("alice", "deploy 1"), msg("alice", "deploy 2"), msg("alice", "deploy 3")];
+        for m in msgs.iter() {
+            index.add(m);
+        }
+        index.add(&Message::from_user("u".to_string(), "bob".to_string(), "other".to_string(), "deploy".to_string()));
+
+        assert_eq!(search(&index, "deploy", &[]), [msgs[2].id.clone(), msgs[1].id.clone()]);
+        assert_eq!(index.docs.len(), 3);
+        index.remove_chan("room");
+        assert_eq!(index.docs.len(), 1);
+    }
+}
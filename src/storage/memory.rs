@@ -0,0 +1,323 @@
+use std::collections::{BTreeSet, HashMap};
+use std::ops::Bound;
+use std::sync::Mutex;
+
+use chrono::Utc;
+
+use crate::chatsvc::{Channel, Message, OfflineQueue, Retention, SHARDS, Shards, UserInfo};
+use crate::errors::ChatErrors;
+use crate::storage::{Storage, StoredState};
+
+#[derive(Default)]
+struct MemoryState {
+    users: HashMap<String, UserInfo>,
+    channels: HashMap<String, Channel>,
+    members: BTreeSet<(String, String)>,
+    offline_queues: HashMap<String, OfflineQueue>,
+}
+
+/// The messages and read markers of the chans hashed to one shard.
+#[derive(Default)]
+struct ChanShard {
+    messages: HashMap<String, Message>,
+    chan_msgs: HashMap<String, BTreeSet<String>>, // chan id -> its message ids
+    read_markers: HashMap<String, HashMap<String, String>>, // chan id -> uid -> msg id
+}
+
+/// Keeps a copy of everything in process memory, gone on restart. Messages
+/// and read markers are sharded by chan, so writes to different chans rarely
+/// wait on each other.
+pub struct MemoryStorage {
+    state: Mutex<MemoryState>,
+    chans: Shards<ChanShard>,
+    max_msgs_per_chan: Option<usize>, // oldest messages dropped beyond it
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        Self {
+            state: Mutex::default(),
+            chans: Shards::new(SHARDS, ChanShard::default),
+            max_msgs_per_chan: None,
+        }
+    }
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep only the newest `max_msgs_per_chan` messages of each chan, so a
+    /// server without a storage backend does not grow without bound.
+    pub fn bounded(max_msgs_per_chan: usize) -> Self {
+        Self {
+            max_msgs_per_chan: Some(max_msgs_per_chan),
+            ..Self::default()
+        }
+    }
+
+    /// Insert or replace the message, returning the ids dropped for the bound.
+    pub fn insert_message(&self, msg: &Message) -> Result<Vec<String>, ChatErrors> {
+        let mut shard = self.chans.get(&msg.chan_id);
+        let shard = &mut *shard;
+        shard.messages.insert(msg.id.clone(), msg.clone());
+        let ids = shard.chan_msgs.entry(msg.chan_id.clone()).or_default();
+        ids.insert(msg.id.clone());
+
+        let over = self.max_msgs_per_chan.map_or(0, |max| ids.len().saturating_sub(max));
+        let dropped: Vec<String> = (0..over).filter_map(|_| ids.pop_first()).collect();
+        for id in dropped.iter() {
+            shard.messages.remove(id);
+        }
+        Ok(dropped)
+    }
+
+    /// How many of the chan's messages are kept.
+    pub fn chan_len(&self, chan_id: &str) -> Result<usize, ChatErrors> {
+        Ok(self.chans.get(chan_id).chan_msgs.get(chan_id).map_or(0, BTreeSet::len))
+    }
+
+    pub fn remove_messages(&self, msg_ids: &[String]) -> Result<(), ChatErrors> {
+        for mut shard in self.chans.iter() {
+            for id in msg_ids {
+                let Some(msg) = shard.messages.remove(id) else {
+                    continue;
+                };
+                if let Some(ids) = shard.chan_msgs.get_mut(&msg.chan_id) {
+                    ids.remove(id);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace the user's queue, dropped count included.
+    pub fn put_offline_queue(&self, uid: &str, queue: OfflineQueue) -> Result<(), ChatErrors> {
+        self.lock()?.offline_queues.insert(uid.to_string(), queue);
+        Ok(())
+    }
+
+    /// Ids of the chan's messages that fall outside `retention`, oldest first.
+    pub fn expired(&self, chan_id: &str, retention: Retention) -> Result<Vec<String>, ChatErrors> {
+        let shard = self.chans.get(chan_id);
+        let Some(ids) = shard.chan_msgs.get(chan_id) else {
+            return Ok(Vec::new());
+        };
+
+        let expired = match retention {
+            Retention::Forever => 0,
+            Retention::Days(_) => {
+                let cutoff = retention.cutoff_id(Utc::now()).unwrap_or_default();
+                ids.iter().take_while(|id| **id < cutoff).count()
+            }
+            Retention::LastN(n) => ids.len().saturating_sub(n),
+        };
+        Ok(ids.iter().take(expired).cloned().collect())
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, MemoryState>, ChatErrors> {
+        self.state
+            .lock()
+            .map_err(|e| ChatErrors::Storage(e.to_string()))
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn load(&self) -> Result<StoredState, ChatErrors> {
+        let mut messages = Vec::new();
+        let mut read_markers: HashMap<String, HashMap<String, String>> = HashMap::new();
+        for shard in self.chans.iter() {
+            messages.extend(shard.messages.values().cloned());
+            for (chan_id, markers) in shard.read_markers.iter() {
+                for (uid, msg_id) in markers {
+                    read_markers.entry(uid.clone()).or_default().insert(chan_id.clone(), msg_id.clone());
+                }
+            }
+        }
+        messages.sort_by(|a, b| a.id.cmp(&b.id));
+
+        let state = self.lock()?;
+        Ok(StoredState {
+            users: state.users.values().cloned().collect(),
+            channels: state.channels.values().map(Channel::settings).collect(),
+            members: state.members.iter().cloned().collect(),
+            messages,
+            read_markers,
+            offline_queues: state.offline_queues.clone(),
+        })
+    }
+
+    fn clear(&self) -> Result<(), ChatErrors> {
+        *self.lock()? = MemoryState::default();
+        for mut shard in self.chans.iter() {
+            *shard = ChanShard::default();
+        }
+        Ok(())
+    }
+
+    fn put_user(&self, user: &UserInfo) -> Result<(), ChatErrors> {
+        self.lock()?.users.insert(user.id.clone(), user.clone());
+        Ok(())
+    }
+
+    fn put_channel(&self, chan: &Channel) -> Result<(), ChatErrors> {
+        self.lock()?.channels.insert(chan.id.clone(), chan.settings());
+        Ok(())
+    }
+
+    fn remove_channel(&self, chan_id: &str) -> Result<(), ChatErrors> {
+        {
+            let mut state = self.lock()?;
+            state.channels.remove(chan_id);
+            state.members.retain(|(_, id)| id != chan_id);
+        }
+        let mut shard = self.chans.get(chan_id);
+        shard.read_markers.remove(chan_id);
+        for id in shard.chan_msgs.remove(chan_id).unwrap_or_default() {
+            shard.messages.remove(&id);
+        }
+        Ok(())
+    }
+
+    fn put_member(&self, uid: &str, chan_id: &str) -> Result<(), ChatErrors> {
+        self.lock()?
+            .members
+            .insert((uid.to_string(), chan_id.to_string()));
+        Ok(())
+    }
+
+    fn remove_member(&self, uid: &str, chan_id: &str) -> Result<(), ChatErrors> {
+        self.lock()?.members.remove(&(uid.to_string(), chan_id.to_string()));
+        if let Some(markers) = self.chans.get(chan_id).read_markers.get_mut(chan_id) {
+            markers.remove(uid);
+        }
+        Ok(())
+    }
+
+    fn put_read_marker(&self, uid: &str, chan_id: &str, msg_id: &str) -> Result<(), ChatErrors> {
+        self.chans
+            .get(chan_id)
+            .read_markers
+            .entry(chan_id.to_string())
+            .or_default()
+            .insert(uid.to_string(), msg_id.to_string());
+        Ok(())
+    }
+
+    fn push_offline(&self, uid: &str, line: &str, keep: usize) -> Result<(), ChatErrors> {
+        self.lock()?
+            .offline_queues
+            .entry(uid.to_string())
+            .or_default()
+            .push(line.to_string(), keep);
+        Ok(())
+    }
+
+    fn remove_offline(&self, uid: &str) -> Result<(), ChatErrors> {
+        self.lock()?.offline_queues.remove(uid);
+        Ok(())
+    }
+
+    fn put_message(&self, msg: &Message) -> Result<(), ChatErrors> {
+        self.insert_message(msg).map(|_| ())
+    }
+
+    fn get_message(&self, msg_id: &str) -> Result<Option<Message>, ChatErrors> {
+        // ids do not say their chan, ask every shard
+        Ok(self.chans.iter().find_map(|shard| shard.messages.get(msg_id).cloned()))
+    }
+
+    fn thread_replies(&self, chan_id: &str, parent_id: &str) -> Result<Vec<Message>, ChatErrors> {
+        let shard = self.chans.get(chan_id);
+        let Some(ids) = shard.chan_msgs.get(chan_id) else {
+            return Ok(Vec::new());
+        };
+        Ok(ids
+            .iter()
+            .filter_map(|id| shard.messages.get(id))
+            .filter(|msg| msg.parent_id.as_deref() == Some(parent_id))
+            .cloned()
+            .collect())
+    }
+
+    fn prune_messages(&self, chan_id: &str, retention: Retention) -> Result<Vec<String>, ChatErrors> {
+        let removed = self.expired(chan_id, retention)?;
+        self.remove_messages(&removed)?;
+        Ok(removed)
+    }
+
+    fn messages_before(&self, chan_id: &str, before: Option<&str>, limit: usize) -> Result<Vec<Message>, ChatErrors> {
+        let shard = self.chans.get(chan_id);
+        let Some(ids) = shard.chan_msgs.get(chan_id) else {
+            return Ok(Vec::new());
+        };
+        let upper = before.map_or(Bound::Unbounded, |id| Bound::Excluded(id.to_string()));
+        let mut page: Vec<Message> = ids
+            .range((Bound::Unbounded, upper))
+            .rev()
+            .take(limit)
+            .filter_map(|id| shard.messages.get(id))
+            .cloned()
+            .collect();
+        page.reverse();
+        Ok(page)
+    }
+
+    fn count_messages(&self, chan_id: &str) -> Result<usize, ChatErrors> {
+        self.chan_len(chan_id)
+    }
+
+    fn count_unread(&self, chan_id: &str, after: Option<&str>, uid: &str) -> Result<usize, ChatErrors> {
+        let shard = self.chans.get(chan_id);
+        let Some(ids) = shard.chan_msgs.get(chan_id) else {
+            return Ok(0);
+        };
+        let lower = after.map_or(Bound::Unbounded, |id| Bound::Excluded(id.to_string()));
+        Ok(ids
+            .range((lower, Bound::Unbounded))
+            .filter_map(|id| shard.messages.get(id))
+            .filter(|msg| !msg.deleted && msg.sender_id != uid)
+            .count())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post(storage: &MemoryStorage, chan_id: &str, content: &str) -> Message {
+        let msg = Message::from_user("u1".to_string(), "alice".to_string(), chan_id.to_string(), content.to_string());
+        storage.put_message(&msg).unwrap();
+        msg
+    }
+
+    fn contents(msgs: Vec<Message>) -> Vec<String> {
+        msgs.into_iter().map(|msg| msg.content).collect()
+    }
+
+    #[test]
+    fn bounded_drops_the_oldest_per_chan() {
+        let storage = MemoryStorage::bounded(2);
+        post(&storage, "a", "1");
+        post(&storage, "b", "kept");
+        post(&storage, "a", "2");
+        post(&storage, "a", "3");
+
+        assert_eq!(contents(storage.load().unwrap().messages), ["kept", "2", "3"]);
+    }
+
+    #[test]
+    fn remove_channel_drops_members_and_messages() {
+        let storage = MemoryStorage::new();
+        post(&storage, "a", "1");
+        post(&storage, "b", "2");
+        storage.put_member("u1", "a").unwrap();
+        storage.put_member("u1", "b").unwrap();
+
+        storage.remove_channel("a").unwrap();
+        let state = storage.load().unwrap();
+        assert_eq!(state.members, [("u1".to_string(), "b".to_string())]);
+        assert_eq!(contents(state.messages), ["2"]);
+    }
+}
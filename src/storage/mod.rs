@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+
+use crate::chatsvc::{Channel, Message, OfflineQueue, Retention, UserInfo};
+use crate::errors::ChatErrors;
+
+pub mod memory;
+pub mod snapshot;
+pub mod sqlite;
+pub mod wal;
+
+pub use memory::MemoryStorage;
+pub use snapshot::{SNAPSHOT_VERSION, Snapshot};
+pub use sqlite::SqliteStorage;
+pub use wal::WalStorage;
+
+/// Everything a storage backend hands back on startup.
+#[derive(Debug, Default)]
+pub struct StoredState {
+    pub users: Vec<UserInfo>,
+    pub channels: Vec<Channel>,        // settings only, `messages` is empty
+    pub members: Vec<(String, String)>, // (uid, chan id)
+    pub messages: Vec<Message>,        // oldest first
+    pub read_markers: HashMap<String, HashMap<String, String>>, // uid -> chan id -> last read msg id
+    pub offline_queues: HashMap<String, OfflineQueue>,          // uid -> queued lines
+}
+
+/// Durable home of `ChatService` state. `ChatService` keeps working from memory
+/// and writes every change through to the backend.
+pub trait Storage: Send + Sync {
+    fn load(&self) -> Result<StoredState, ChatErrors>;
+
+    /// Drop everything, before a snapshot is written over the store.
+    fn clear(&self) -> Result<(), ChatErrors>;
+
+    fn put_user(&self, user: &UserInfo) -> Result<(), ChatErrors>;
+
+    /// Insert or replace the chan's settings.
+    fn put_channel(&self, chan: &Channel) -> Result<(), ChatErrors>;
+
+    /// Remove the chan along with its memberships, read markers and messages.
+    fn remove_channel(&self, chan_id: &str) -> Result<(), ChatErrors>;
+
+    fn put_member(&self, uid: &str, chan_id: &str) -> Result<(), ChatErrors>;
+
+    /// Remove the membership along with the user's read marker in the chan.
+    fn remove_member(&self, uid: &str, chan_id: &str) -> Result<(), ChatErrors>;
+
+    fn put_read_marker(&self, uid: &str, chan_id: &str, msg_id: &str) -> Result<(), ChatErrors>;
+
+    /// Queue a line for an offline user, keeping the newest `keep` and counting
+    /// the ones pushed out.
+    fn push_offline(&self, uid: &str, line: &str, keep: usize) -> Result<(), ChatErrors>;
+
+    /// Forget the user's queue once it is delivered.
+    fn remove_offline(&self, uid: &str) -> Result<(), ChatErrors>;
+
+    /// Insert or replace a message, edits and tombstones included.
+    fn put_message(&self, msg: &Message) -> Result<(), ChatErrors>;
+
+    fn get_message(&self, msg_id: &str) -> Result<Option<Message>, ChatErrors>;
+
+    /// Replies to the thread root `parent_id`, oldest first.
+    fn thread_replies(&self, chan_id: &str, parent_id: &str) -> Result<Vec<Message>, ChatErrors>;
+
+    /// Remove the chan's messages that fall outside `retention`, returning their ids.
+    fn prune_messages(&self, chan_id: &str, retention: Retention) -> Result<Vec<String>, ChatErrors>;
+
+    /// Up to `limit` messages of the chan older than `before` (newest ones if
+    /// `None`), oldest first.
+    fn messages_before(&self, chan_id: &str, before: Option<&str>, limit: usize) -> Result<Vec<Message>, ChatErrors>;
+
+    /// How many messages of the chan are stored.
+    fn count_messages(&self, chan_id: &str) -> Result<usize, ChatErrors>;
+
+    /// How many stored messages of the chan newer than `after` (all if
+    /// `None`) are neither deleted nor sent by `uid`.
+    fn count_unread(&self, chan_id: &str, after: Option<&str>, uid: &str) -> Result<usize, ChatErrors>;
+
+    /// Called once on shutdown after the last write. Writes are already durable,
+    /// this only leaves the store cheap to load on the next start.
+    fn flush(&self) -> Result<(), ChatErrors> {
+        Ok(())
+    }
+}
+
+/// Make a rename in `path`'s directory durable.
+pub(crate) fn sync_dir(path: &Path) -> Result<(), ChatErrors> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)
+        .and_then(|d| d.sync_all())
+        .map_err(|e| ChatErrors::Storage(e.to_string()))
+}
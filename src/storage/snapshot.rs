@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::chatsvc::{Channel, Message, UserInfo};
+use crate::errors::ChatErrors;
+use crate::storage::sync_dir;
+
+/// Bump when the layout changes, snapshots of any other version are refused.
+pub const SNAPSHOT_VERSION: u32 = 2;
+
+/// Full `ChatService` state at one point in time, for backups and moving hosts.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub version: u32,
+    pub created_at: DateTime<Utc>,
+    pub users: Vec<UserInfo>,
+    pub channels: Vec<Channel>,         // settings only
+    pub members: Vec<(String, String)>, // (uid, chan id)
+    pub messages: Vec<Message>,         // the full stored history
+    #[serde(default)]
+    pub read_markers: HashMap<String, HashMap<String, String>>,
+}
+
+impl Snapshot {
+    /// Write to a temp file first so a crash never leaves a half written
+    /// snapshot, and sync the directory so the rename survives one too.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), ChatErrors> {
+        let path = path.as_ref();
+        let json = serde_json::to_vec(self).map_err(|e| ChatErrors::Storage(e.to_string()))?;
+
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp).map_err(to_err)?;
+        file.write_all(&json).map_err(to_err)?;
+        file.sync_all().map_err(to_err)?;
+        fs::rename(&tmp, path).map_err(to_err)?;
+        sync_dir(path)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ChatErrors> {
+        let data = fs::read(path).map_err(to_err)?;
+
+        #[derive(Deserialize)]
+        struct Version {
+            version: u32,
+        }
+        let Version { version } =
+            serde_json::from_slice(&data).map_err(|e| ChatErrors::Storage(e.to_string()))?;
+        if version != SNAPSHOT_VERSION {
+            return Err(ChatErrors::Storage(format!(
+                "snapshot version {} is not the supported version {}",
+                version, SNAPSHOT_VERSION
+            )));
+        }
+
+        serde_json::from_slice(&data).map_err(|e| ChatErrors::Storage(e.to_string()))
+    }
+}
+
+fn to_err(e: std::io::Error) -> ChatErrors {
+    ChatErrors::Storage(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn snapshot() -> Snapshot {
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            created_at: Utc::now(),
+            users: vec![UserInfo {
+                id: "u1".to_string(),
+                name: "alice".to_string(),
+                token_hash: String::new(),
+                offered_chans: HashSet::new(),
+            }],
+            channels: vec![Channel::new("room".to_string(), "u1".to_string())],
+            members: vec![("u1".to_string(), "c1".to_string())],
+            messages: Vec::new(),
+            read_markers: HashMap::from([("u1".to_string(), HashMap::from([("c1".to_string(), "m1".to_string())]))]),
+        }
+    }
+
+    #[test]
+    fn saves_and_loads() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("chat.snapshot");
+        let saved = snapshot();
+        saved.save(&path).unwrap();
+        assert!(!path.with_extension("tmp").exists());
+
+        let loaded = Snapshot::load(&path).unwrap();
+        assert_eq!(loaded.created_at, saved.created_at);
+        assert_eq!(loaded.users[0].name, saved.users[0].name);
+        assert_eq!(loaded.channels[0].id, saved.channels[0].id);
+        assert_eq!(loaded.members, saved.members);
+        assert_eq!(loaded.read_markers, saved.read_markers);
+    }
+
+    #[test]
+    fn refuses_other_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("chat.snapshot");
+        for version in [SNAPSHOT_VERSION - 1, SNAPSHOT_VERSION + 1] {
+            let mut other = snapshot();
+            other.version = version;
+            other.save(&path).unwrap();
+            let Err(ChatErrors::Storage(err)) = Snapshot::load(&path) else {
+                panic!("loaded a version {} snapshot", version);
+            };
+            assert!(err.contains("not the supported"), "{}", err);
+        }
+
+        fs::write(&path, "{}").unwrap();
+        assert!(Snapshot::load(&path).is_err());
+    }
+}
@@ -0,0 +1,749 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use rusqlite::{Connection, OptionalExtension, params};
+
+use crate::chatsvc::{Channel, Message, OfflineQueue, Retention, Shards, UserInfo};
+use crate::errors::ChatErrors;
+use crate::storage::{Storage, StoredState};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS users (
+    id         TEXT PRIMARY KEY,
+    name       TEXT NOT NULL,
+    token_hash TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS offered_chans (
+    uid     TEXT NOT NULL,
+    chan_id TEXT NOT NULL,
+    PRIMARY KEY (uid, chan_id)
+);
+CREATE TABLE IF NOT EXISTS channels (
+    id               TEXT PRIMARY KEY,
+    name             TEXT NOT NULL,
+    owner            TEXT NOT NULL,
+    archived         INTEGER NOT NULL,
+    read_only        INTEGER NOT NULL,
+    slow_mode_secs   INTEGER,
+    max_msgs_per_sec INTEGER,
+    retention        TEXT NOT NULL DEFAULT 'forever'
+);
+CREATE TABLE IF NOT EXISTS moderators (
+    chan_id TEXT NOT NULL,
+    uid     TEXT NOT NULL,
+    PRIMARY KEY (chan_id, uid)
+);
+CREATE TABLE IF NOT EXISTS members (
+    uid     TEXT NOT NULL,
+    chan_id TEXT NOT NULL,
+    PRIMARY KEY (uid, chan_id)
+);
+CREATE TABLE IF NOT EXISTS messages (
+    id          TEXT PRIMARY KEY,
+    chan_id     TEXT NOT NULL,
+    chan_name   TEXT NOT NULL,
+    sender      TEXT NOT NULL,
+    sender_id   TEXT NOT NULL,
+    content     TEXT NOT NULL,
+    send_time   TEXT NOT NULL,
+    parent_id   TEXT,
+    reply_count INTEGER NOT NULL,
+    edited      INTEGER NOT NULL,
+    deleted     INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS messages_chan ON messages (chan_id, id);
+CREATE TABLE IF NOT EXISTS reactions (
+    msg_id   TEXT NOT NULL,
+    reaction TEXT NOT NULL,
+    uid      TEXT NOT NULL,
+    PRIMARY KEY (msg_id, reaction, uid)
+);
+CREATE TABLE IF NOT EXISTS read_markers (
+    uid     TEXT NOT NULL,
+    chan_id TEXT NOT NULL,
+    msg_id  TEXT NOT NULL,
+    PRIMARY KEY (uid, chan_id)
+);
+CREATE TABLE IF NOT EXISTS offline_msgs (
+    seq  INTEGER PRIMARY KEY AUTOINCREMENT,
+    uid  TEXT NOT NULL,
+    line TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS offline_msgs_uid ON offline_msgs (uid, seq);
+CREATE TABLE IF NOT EXISTS offline_dropped (
+    uid     TEXT PRIMARY KEY,
+    dropped INTEGER NOT NULL
+);
+";
+
+const MESSAGE_COLUMNS: &str = "id, chan_id, chan_name, sender, sender_id, content, send_time,
+    parent_id, reply_count, edited, deleted";
+
+/// Connections that only read, each chan's reads go to one of them.
+const READERS: usize = 4;
+
+/// SQLite backed storage, one file per server. SQLite takes one writer at a
+/// time, so writes share a connection; a file is opened in WAL mode with a few
+/// more connections for reads, which then never wait on writes or on reads of
+/// chans in other shards.
+pub struct SqliteStorage {
+    conn: Mutex<Connection>,
+    readers: Option<Shards<Connection>>, // none for an in memory database, only `conn` sees it
+}
+
+impl SqliteStorage {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, ChatErrors> {
+        let path = path.as_ref();
+        let conn = Connection::open(path).map_err(to_err)?;
+        conn.execute_batch(SCHEMA).map_err(to_err)?;
+
+        let readers = if path.as_os_str().is_empty() || path == Path::new(":memory:") {
+            None
+        } else {
+            conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))
+                .map_err(to_err)?;
+            let mut readers = Vec::with_capacity(READERS);
+            for _ in 0..READERS {
+                let reader = Connection::open(path).map_err(to_err)?;
+                // only a checkpoint can hold a reader up
+                reader.busy_timeout(Duration::from_secs(5)).map_err(to_err)?;
+                readers.push(reader);
+            }
+            Some(readers.into())
+        };
+
+        Ok(Self {
+            conn: Mutex::new(conn),
+            readers,
+        })
+    }
+
+    fn conn(&self) -> Result<MutexGuard<'_, Connection>, ChatErrors> {
+        self.conn
+            .lock()
+            .map_err(|e| ChatErrors::Storage(e.to_string()))
+    }
+
+    /// A connection to read `key`'s rows with, a chan or message id.
+    fn reader(&self, key: &str) -> Result<MutexGuard<'_, Connection>, ChatErrors> {
+        match self.readers.as_ref() {
+            Some(readers) => Ok(readers.get(key)),
+            None => self.conn(),
+        }
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn load(&self) -> Result<StoredState, ChatErrors> {
+        let conn = self.conn()?;
+        let mut state = StoredState::default();
+
+        let mut stmt = conn.prepare("SELECT id, name, token_hash FROM users").map_err(to_err)?;
+        state.users = stmt
+            .query_map([], |row| {
+                Ok(UserInfo {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    token_hash: row.get(2)?,
+                    offered_chans: HashSet::new(),
+                })
+            })
+            .and_then(Iterator::collect)
+            .map_err(to_err)?;
+        let mut offered: HashMap<String, HashSet<String>> = HashMap::new();
+        let mut stmt = conn.prepare("SELECT uid, chan_id FROM offered_chans").map_err(to_err)?;
+        let rows: Vec<(String, String)> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .and_then(Iterator::collect)
+            .map_err(to_err)?;
+        for (uid, chan_id) in rows {
+            offered.entry(uid).or_default().insert(chan_id);
+        }
+        for user in state.users.iter_mut() {
+            user.offered_chans = offered.remove(&user.id).unwrap_or_default();
+        }
+
+        let mut moderators: HashMap<String, HashSet<String>> = HashMap::new();
+        let mut stmt = conn.prepare("SELECT chan_id, uid FROM moderators").map_err(to_err)?;
+        let rows: Vec<(String, String)> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .and_then(Iterator::collect)
+            .map_err(to_err)?;
+        for (chan_id, uid) in rows {
+            moderators.entry(chan_id).or_default().insert(uid);
+        }
+
+        let mut stmt = conn
+            .prepare("SELECT id, name, owner, archived, read_only, slow_mode_secs, max_msgs_per_sec, retention FROM channels")
+            .map_err(to_err)?;
+        let rows: Vec<(Channel, String)> = stmt
+            .query_map([], |row| {
+                let mut chan = Channel::new(row.get(1)?, row.get(2)?);
+                chan.id = row.get(0)?;
+                chan.archived = row.get(3)?;
+                chan.read_only = row.get(4)?;
+                chan.slow_mode = row.get::<_, Option<u64>>(5)?.map(Duration::from_secs);
+                chan.max_msgs_per_sec = row.get(6)?;
+                Ok((chan, row.get(7)?))
+            })
+            .and_then(Iterator::collect)
+            .map_err(to_err)?;
+        for (mut chan, retention) in rows {
+            // a bad policy must not quietly become `forever` and keep everything
+            chan.retention = retention.parse().map_err(|_| {
+                ChatErrors::Storage(format!("chan: {} has a corrupt retention: {}", chan.id, retention))
+            })?;
+            chan.moderators = moderators.remove(&chan.id).unwrap_or_default();
+            state.channels.push(chan);
+        }
+
+        let mut stmt = conn.prepare("SELECT uid, chan_id FROM members").map_err(to_err)?;
+        state.members = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .and_then(Iterator::collect)
+            .map_err(to_err)?;
+
+        let mut reactions: HashMap<String, BTreeMap<String, HashSet<String>>> = HashMap::new();
+        let mut stmt = conn
+            .prepare("SELECT msg_id, reaction, uid FROM reactions")
+            .map_err(to_err)?;
+        let rows: Vec<(String, String, String)> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .and_then(Iterator::collect)
+            .map_err(to_err)?;
+        for (msg_id, reaction, uid) in rows {
+            reactions
+                .entry(msg_id)
+                .or_default()
+                .entry(reaction)
+                .or_default()
+                .insert(uid);
+        }
+
+        let mut stmt = conn
+            .prepare(&format!("SELECT {} FROM messages ORDER BY id", MESSAGE_COLUMNS))
+            .map_err(to_err)?;
+        state.messages = stmt
+            .query_map([], message_from_row)
+            .and_then(Iterator::collect)
+            .map_err(to_err)?;
+        for msg in state.messages.iter_mut() {
+            msg.reactions = reactions.remove(&msg.id).unwrap_or_default();
+        }
+
+        let mut stmt = conn.prepare("SELECT uid, chan_id, msg_id FROM read_markers").map_err(to_err)?;
+        let rows: Vec<(String, String, String)> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .and_then(Iterator::collect)
+            .map_err(to_err)?;
+        for (uid, chan_id, msg_id) in rows {
+            state.read_markers.entry(uid).or_default().insert(chan_id, msg_id);
+        }
+
+        let mut stmt = conn.prepare("SELECT uid, line FROM offline_msgs ORDER BY seq").map_err(to_err)?;
+        let rows: Vec<(String, String)> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .and_then(Iterator::collect)
+            .map_err(to_err)?;
+        for (uid, line) in rows {
+            state.offline_queues.entry(uid).or_default().msgs.push_back(line);
+        }
+        let mut stmt = conn.prepare("SELECT uid, dropped FROM offline_dropped").map_err(to_err)?;
+        let rows: Vec<(String, usize)> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .and_then(Iterator::collect)
+            .map_err(to_err)?;
+        for (uid, dropped) in rows {
+            state.offline_queues.entry(uid).or_insert_with(OfflineQueue::default).dropped = dropped;
+        }
+
+        Ok(state)
+    }
+
+    fn clear(&self) -> Result<(), ChatErrors> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction().map_err(to_err)?;
+        for table in [
+            "users",
+            "offered_chans",
+            "channels",
+            "moderators",
+            "members",
+            "messages",
+            "reactions",
+            "read_markers",
+            "offline_msgs",
+            "offline_dropped",
+        ] {
+            tx.execute(&format!("DELETE FROM {}", table), []).map_err(to_err)?;
+        }
+        tx.commit().map_err(to_err)
+    }
+
+    fn put_user(&self, user: &UserInfo) -> Result<(), ChatErrors> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction().map_err(to_err)?;
+        tx.execute(
+            "INSERT OR REPLACE INTO users (id, name, token_hash) VALUES (?1, ?2, ?3)",
+            params![user.id, user.name, user.token_hash],
+        )
+        .map_err(to_err)?;
+
+        tx.execute("DELETE FROM offered_chans WHERE uid = ?1", params![user.id])
+            .map_err(to_err)?;
+        for chan_id in user.offered_chans.iter() {
+            tx.execute(
+                "INSERT INTO offered_chans (uid, chan_id) VALUES (?1, ?2)",
+                params![user.id, chan_id],
+            )
+            .map_err(to_err)?;
+        }
+
+        tx.commit().map_err(to_err)
+    }
+
+    fn put_channel(&self, chan: &Channel) -> Result<(), ChatErrors> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction().map_err(to_err)?;
+        tx.execute(
+            "INSERT OR REPLACE INTO channels
+                (id, name, owner, archived, read_only, slow_mode_secs, max_msgs_per_sec, retention)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                chan.id,
+                chan.name,
+                chan.owner,
+                chan.archived,
+                chan.read_only,
+                chan.slow_mode.map(|d| d.as_secs()),
+                chan.max_msgs_per_sec,
+                chan.retention.to_string(),
+            ],
+        )
+        .map_err(to_err)?;
+
+        tx.execute("DELETE FROM moderators WHERE chan_id = ?1", params![chan.id])
+            .map_err(to_err)?;
+        for uid in chan.moderators.iter() {
+            tx.execute(
+                "INSERT INTO moderators (chan_id, uid) VALUES (?1, ?2)",
+                params![chan.id, uid],
+            )
+            .map_err(to_err)?;
+        }
+
+        tx.commit().map_err(to_err)
+    }
+
+    fn remove_channel(&self, chan_id: &str) -> Result<(), ChatErrors> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction().map_err(to_err)?;
+        tx.execute(
+            "DELETE FROM reactions WHERE msg_id IN (SELECT id FROM messages WHERE chan_id = ?1)",
+            params![chan_id],
+        )
+        .map_err(to_err)?;
+        for table in ["messages", "members", "moderators", "read_markers"] {
+            tx.execute(&format!("DELETE FROM {} WHERE chan_id = ?1", table), params![chan_id])
+                .map_err(to_err)?;
+        }
+        tx.execute("DELETE FROM channels WHERE id = ?1", params![chan_id])
+            .map_err(to_err)?;
+
+        tx.commit().map_err(to_err)
+    }
+
+    fn put_member(&self, uid: &str, chan_id: &str) -> Result<(), ChatErrors> {
+        self.conn()?
+            .execute(
+                "INSERT OR IGNORE INTO members (uid, chan_id) VALUES (?1, ?2)",
+                params![uid, chan_id],
+            )
+            .map_err(to_err)?;
+        Ok(())
+    }
+
+    fn remove_member(&self, uid: &str, chan_id: &str) -> Result<(), ChatErrors> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction().map_err(to_err)?;
+        for table in ["members", "read_markers"] {
+            tx.execute(
+                &format!("DELETE FROM {} WHERE uid = ?1 AND chan_id = ?2", table),
+                params![uid, chan_id],
+            )
+            .map_err(to_err)?;
+        }
+        tx.commit().map_err(to_err)
+    }
+
+    fn put_read_marker(&self, uid: &str, chan_id: &str, msg_id: &str) -> Result<(), ChatErrors> {
+        self.conn()?
+            .execute(
+                "INSERT OR REPLACE INTO read_markers (uid, chan_id, msg_id) VALUES (?1, ?2, ?3)",
+                params![uid, chan_id, msg_id],
+            )
+            .map_err(to_err)?;
+        Ok(())
+    }
+
+    fn push_offline(&self, uid: &str, line: &str, keep: usize) -> Result<(), ChatErrors> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction().map_err(to_err)?;
+        tx.execute("INSERT INTO offline_msgs (uid, line) VALUES (?1, ?2)", params![uid, line])
+            .map_err(to_err)?;
+        // everything but the newest `keep` of the user's lines
+        let pushed_out = tx
+            .execute(
+                "DELETE FROM offline_msgs WHERE uid = ?1 AND seq NOT IN
+                    (SELECT seq FROM offline_msgs WHERE uid = ?1 ORDER BY seq DESC LIMIT ?2)",
+                params![uid, keep as i64],
+            )
+            .map_err(to_err)?;
+        if pushed_out > 0 {
+            tx.execute(
+                "INSERT INTO offline_dropped (uid, dropped) VALUES (?1, ?2)
+                 ON CONFLICT (uid) DO UPDATE SET dropped = dropped + ?2",
+                params![uid, pushed_out as i64],
+            )
+            .map_err(to_err)?;
+        }
+        tx.commit().map_err(to_err)
+    }
+
+    fn remove_offline(&self, uid: &str) -> Result<(), ChatErrors> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction().map_err(to_err)?;
+        for table in ["offline_msgs", "offline_dropped"] {
+            tx.execute(&format!("DELETE FROM {} WHERE uid = ?1", table), params![uid])
+                .map_err(to_err)?;
+        }
+        tx.commit().map_err(to_err)
+    }
+
+    fn put_message(&self, msg: &Message) -> Result<(), ChatErrors> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction().map_err(to_err)?;
+        tx.execute(
+            "INSERT OR REPLACE INTO messages
+                (id, chan_id, chan_name, sender, sender_id, content, send_time,
+                 parent_id, reply_count, edited, deleted)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            params![
+                msg.id,
+                msg.chan_id,
+                msg.chan_name,
+                msg.sender,
+                msg.sender_id,
+                msg.content,
+                msg.send_time.to_rfc3339(),
+                msg.parent_id,
+                msg.reply_count,
+                msg.edited,
+                msg.deleted,
+            ],
+        )
+        .map_err(to_err)?;
+
+        tx.execute("DELETE FROM reactions WHERE msg_id = ?1", params![msg.id])
+            .map_err(to_err)?;
+        for (reaction, users) in msg.reactions.iter() {
+            for uid in users {
+                tx.execute(
+                    "INSERT INTO reactions (msg_id, reaction, uid) VALUES (?1, ?2, ?3)",
+                    params![msg.id, reaction, uid],
+                )
+                .map_err(to_err)?;
+            }
+        }
+
+        tx.commit().map_err(to_err)
+    }
+
+    fn get_message(&self, msg_id: &str) -> Result<Option<Message>, ChatErrors> {
+        let conn = self.reader(msg_id)?;
+        let msg = conn
+            .query_row(
+                &format!("SELECT {} FROM messages WHERE id = ?1", MESSAGE_COLUMNS),
+                params![msg_id],
+                message_from_row,
+            )
+            .optional()
+            .map_err(to_err)?;
+
+        match msg {
+            Some(mut msg) => {
+                load_reactions(&conn, &mut msg)?;
+                Ok(Some(msg))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn thread_replies(&self, chan_id: &str, parent_id: &str) -> Result<Vec<Message>, ChatErrors> {
+        let conn = self.reader(chan_id)?;
+        let mut stmt = conn
+            .prepare(&format!(
+                "SELECT {} FROM messages WHERE chan_id = ?1 AND parent_id = ?2 ORDER BY id",
+                MESSAGE_COLUMNS
+            ))
+            .map_err(to_err)?;
+        let mut replies: Vec<Message> = stmt
+            .query_map(params![chan_id, parent_id], message_from_row)
+            .and_then(Iterator::collect)
+            .map_err(to_err)?;
+
+        for msg in replies.iter_mut() {
+            load_reactions(&conn, msg)?;
+        }
+        Ok(replies)
+    }
+
+    fn prune_messages(&self, chan_id: &str, retention: Retention) -> Result<Vec<String>, ChatErrors> {
+        let mut conn = self.conn()?;
+        let cutoff: Option<String> = match retention {
+            Retention::Forever => None,
+            Retention::Days(_) => retention.cutoff_id(Utc::now()),
+            // the oldest of the last n messages, none if there are fewer
+            Retention::LastN(n) => conn
+                .query_row(
+                    "SELECT id FROM messages WHERE chan_id = ?1 ORDER BY id DESC LIMIT 1 OFFSET ?2",
+                    params![chan_id, n as i64 - 1],
+                    |row| row.get(0),
+                )
+                .optional()
+                .map_err(to_err)?,
+        };
+        let Some(cutoff) = cutoff else {
+            return Ok(Vec::new());
+        };
+
+        let tx = conn.transaction().map_err(to_err)?;
+        let removed: Vec<String> = tx
+            .prepare("SELECT id FROM messages WHERE chan_id = ?1 AND id < ?2")
+            .and_then(|mut stmt| {
+                stmt.query_map(params![chan_id, cutoff], |row| row.get(0))?
+                    .collect()
+            })
+            .map_err(to_err)?;
+        tx.execute(
+            "DELETE FROM reactions WHERE msg_id IN (SELECT id FROM messages WHERE chan_id = ?1 AND id < ?2)",
+            params![chan_id, cutoff],
+        )
+        .map_err(to_err)?;
+        tx.execute(
+            "DELETE FROM messages WHERE chan_id = ?1 AND id < ?2",
+            params![chan_id, cutoff],
+        )
+        .map_err(to_err)?;
+        tx.commit().map_err(to_err)?;
+
+        Ok(removed)
+    }
+
+    fn messages_before(&self, chan_id: &str, before: Option<&str>, limit: usize) -> Result<Vec<Message>, ChatErrors> {
+        let conn = self.reader(chan_id)?;
+        let mut stmt = conn
+            .prepare(&format!(
+                "SELECT {} FROM messages
+                 WHERE chan_id = ?1 AND (?2 IS NULL OR id < ?2)
+                 ORDER BY id DESC LIMIT ?3",
+                MESSAGE_COLUMNS
+            ))
+            .map_err(to_err)?;
+        let mut page: Vec<Message> = stmt
+            .query_map(params![chan_id, before, limit as i64], message_from_row)
+            .and_then(Iterator::collect)
+            .map_err(to_err)?;
+
+        for msg in page.iter_mut() {
+            load_reactions(&conn, msg)?;
+        }
+        page.reverse();
+        Ok(page)
+    }
+
+    fn count_messages(&self, chan_id: &str) -> Result<usize, ChatErrors> {
+        self.reader(chan_id)?
+            .query_row("SELECT COUNT(*) FROM messages WHERE chan_id = ?1", params![chan_id], |row| {
+                row.get::<_, i64>(0)
+            })
+            .map(|count| count as usize)
+            .map_err(to_err)
+    }
+
+    fn count_unread(&self, chan_id: &str, after: Option<&str>, uid: &str) -> Result<usize, ChatErrors> {
+        self.reader(chan_id)?
+            .query_row(
+                "SELECT COUNT(*) FROM messages
+                 WHERE chan_id = ?1 AND (?2 IS NULL OR id > ?2) AND deleted = 0 AND sender_id != ?3",
+                params![chan_id, after, uid],
+                |row| row.get::<_, i64>(0),
+            )
+            .map(|count| count as usize)
+            .map_err(to_err)
+    }
+}
+
+/// Maps a row selected with `MESSAGE_COLUMNS`, reactions are loaded separately.
+fn message_from_row(row: &rusqlite::Row) -> rusqlite::Result<Message> {
+    let mut msg = Message::from_user(row.get(4)?, row.get(3)?, row.get(1)?, row.get(5)?);
+    msg.id = row.get(0)?;
+    msg.chan_name = row.get(2)?;
+    msg.send_time = parse_time(row.get(6)?);
+    msg.parent_id = row.get(7)?;
+    msg.reply_count = row.get(8)?;
+    msg.edited = row.get(9)?;
+    msg.deleted = row.get(10)?;
+    Ok(msg)
+}
+
+fn load_reactions(conn: &Connection, msg: &mut Message) -> Result<(), ChatErrors> {
+    let mut stmt = conn
+        .prepare_cached("SELECT reaction, uid FROM reactions WHERE msg_id = ?1")
+        .map_err(to_err)?;
+    let rows: Vec<(String, String)> = stmt
+        .query_map(params![msg.id], |row| Ok((row.get(0)?, row.get(1)?)))
+        .and_then(Iterator::collect)
+        .map_err(to_err)?;
+    for (reaction, uid) in rows {
+        msg.reactions.entry(reaction).or_default().insert(uid);
+    }
+    Ok(())
+}
+
+fn parse_time(s: String) -> DateTime<Utc> {
+    DateTime::parse_from_rfc3339(&s)
+        .map(|t| t.with_timezone(&Utc))
+        .unwrap_or_default()
+}
+
+fn to_err(e: rusqlite::Error) -> ChatErrors {
+    ChatErrors::Storage(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_messages_and_thread_replies() {
+        let storage = SqliteStorage::open(":memory:").unwrap();
+        let mut root = Message::from_user("u1".to_string(), "alice".to_string(), "a".to_string(), "root".to_string());
+        root.reactions.entry(":+1:".to_string()).or_default().insert("u2".to_string());
+        storage.put_message(&root).unwrap();
+        let mut reply = Message::from_user("u2".to_string(), "bob".to_string(), "a".to_string(), "reply".to_string());
+        reply.parent_id = Some(root.id.clone());
+        storage.put_message(&reply).unwrap();
+
+        let found = storage.get_message(&root.id).unwrap().unwrap();
+        assert_eq!((found.content.as_str(), found.reaction_summary()), ("root", ":+1: 1".to_string()));
+        assert!(storage.get_message("missing").unwrap().is_none());
+        let replies = storage.thread_replies("a", &root.id).unwrap();
+        assert_eq!(replies.iter().map(|msg| msg.id.as_str()).collect::<Vec<_>>(), [reply.id.as_str()]);
+        assert!(storage.thread_replies("b", &root.id).unwrap().is_empty());
+    }
+
+    #[test]
+    fn reads_a_file_back_through_the_readers() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = SqliteStorage::open(dir.path().join("chat.db")).unwrap();
+        assert!(storage.readers.is_some());
+        let mut msg = Message::from_user("u1".to_string(), "alice".to_string(), "a".to_string(), "hi".to_string());
+        storage.put_message(&msg).unwrap();
+        msg.content = "edited".to_string();
+        storage.put_message(&msg).unwrap();
+
+        assert_eq!(storage.get_message(&msg.id).unwrap().unwrap().content, "edited");
+        assert_eq!(storage.messages_before("a", None, 10).unwrap().len(), 1);
+        assert_eq!(storage.count_unread("a", None, "u2").unwrap(), 1);
+    }
+
+    #[test]
+    fn keeps_the_chans_offered_to_users() {
+        let storage = SqliteStorage::open(":memory:").unwrap();
+        let mut user = UserInfo {
+            id: "u1".to_string(),
+            name: "alice".to_string(),
+            token_hash: "hash".to_string(),
+            offered_chans: HashSet::from(["a".to_string(), "b".to_string()]),
+        };
+        storage.put_user(&user).unwrap();
+        user.offered_chans.remove("b");
+        storage.put_user(&user).unwrap();
+
+        let users = storage.load().unwrap().users;
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].offered_chans, HashSet::from(["a".to_string()]));
+    }
+
+    #[test]
+    fn keeps_read_markers() {
+        let storage = SqliteStorage::open(":memory:").unwrap();
+        storage.put_member("u1", "a").unwrap();
+        storage.put_read_marker("u1", "a", "m1").unwrap();
+        storage.put_read_marker("u1", "a", "m2").unwrap();
+        storage.put_read_marker("u1", "b", "m3").unwrap();
+        assert_eq!(storage.load().unwrap().read_markers["u1"]["a"], "m2");
+
+        storage.remove_member("u1", "a").unwrap();
+        storage.remove_channel("b").unwrap();
+        assert!(storage.load().unwrap().read_markers.is_empty());
+    }
+
+    #[test]
+    fn prunes_to_the_retention_and_keeps_the_policy() {
+        let storage = SqliteStorage::open(":memory:").unwrap();
+        let mut chan = Channel::new("room".to_string(), "u1".to_string());
+        chan.retention = Retention::LastN(2);
+        storage.put_channel(&chan).unwrap();
+        let mut ids = Vec::new();
+        for i in 0..4 {
+            let msg = Message::from_user("u1".to_string(), "alice".to_string(), chan.id.clone(), i.to_string());
+            storage.put_message(&msg).unwrap();
+            ids.push(msg.id);
+        }
+
+        assert_eq!(storage.prune_messages(&chan.id, Retention::LastN(2)).unwrap(), ids[..2]);
+        assert!(storage.prune_messages(&chan.id, Retention::LastN(5)).unwrap().is_empty());
+        assert_eq!(storage.count_messages(&chan.id).unwrap(), 2);
+        let state = storage.load().unwrap();
+        assert_eq!(state.channels[0].retention, Retention::LastN(2));
+        assert_eq!(state.messages.len(), 2);
+    }
+
+    #[test]
+    fn refuses_a_corrupt_retention() {
+        let storage = SqliteStorage::open(":memory:").unwrap();
+        let chan = Channel::new("room".to_string(), "u1".to_string());
+        storage.put_channel(&chan).unwrap();
+        storage
+            .conn()
+            .unwrap()
+            .execute("UPDATE channels SET retention = 'last=x' WHERE id = ?1", params![chan.id])
+            .unwrap();
+
+        match storage.load() {
+            Err(ChatErrors::Storage(e)) => assert!(e.contains(&chan.id), "{}", e),
+            res => panic!("unexpected: {:?}", res.map(|state| state.channels.len())),
+        }
+    }
+
+    #[test]
+    fn keeps_offline_queues() {
+        let storage = SqliteStorage::open(":memory:").unwrap();
+        for line in ["a", "b", "c"] {
+            storage.push_offline("u2", line, 2).unwrap();
+        }
+        storage.push_offline("u3", "gone", 2).unwrap();
+        storage.remove_offline("u3").unwrap();
+
+        let state = storage.load().unwrap();
+        let queue = &state.offline_queues["u2"];
+        assert_eq!(queue.msgs, ["b", "c"]);
+        assert_eq!(queue.dropped, 1);
+        assert!(!state.offline_queues.contains_key("u3"));
+    }
+}
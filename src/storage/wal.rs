@@ -0,0 +1,934 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::chatsvc::{Channel, MEMORY_STORED_MSGS, Message, OfflineQueue, Retention, UserInfo};
+use crate::errors::ChatErrors;
+use crate::storage::{MemoryStorage, Storage, StoredState, sync_dir};
+
+/// Never compact a log shorter than this.
+const COMPACT_MIN_RECORDS: usize = 1024;
+/// Records written with one fsync at most.
+const MAX_GROUP: usize = 1024;
+/// Records queued for the writer before appending blocks.
+const MAX_PENDING: usize = 64 * 1024;
+
+type Done = mpsc::Sender<Result<(), ChatErrors>>;
+
+/// One state change, as written to the log.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Record {
+    Clear,
+    PutUser(UserInfo),
+    PutChannel(Channel),
+    RemoveChannel { chan_id: String },
+    PutMember { uid: String, chan_id: String },
+    RemoveMember { uid: String, chan_id: String },
+    PutMessage(Message),
+    PutReadMarker { uid: String, chan_id: String, msg_id: String },
+    RemoveMessages { msg_ids: Vec<String> },
+    PushOffline { uid: String, line: String, keep: usize },
+    RemoveOffline { uid: String },
+    PutOfflineQueue { uid: String, queue: OfflineQueue }, // written by compaction only
+}
+
+impl Record {
+    fn apply(&self, state: &MemoryStorage) -> Result<(), ChatErrors> {
+        match self {
+            Record::Clear => state.clear(),
+            Record::PutUser(user) => state.put_user(user),
+            Record::PutChannel(chan) => state.put_channel(chan),
+            Record::RemoveChannel { chan_id } => state.remove_channel(chan_id),
+            Record::PutMember { uid, chan_id } => state.put_member(uid, chan_id),
+            Record::RemoveMember { uid, chan_id } => state.remove_member(uid, chan_id),
+            Record::PutMessage(msg) => state.put_message(msg),
+            Record::PutReadMarker { uid, chan_id, msg_id } => state.put_read_marker(uid, chan_id, msg_id),
+            Record::RemoveMessages { msg_ids } => state.remove_messages(msg_ids),
+            Record::PushOffline { uid, line, keep } => state.push_offline(uid, line, *keep),
+            Record::RemoveOffline { uid } => state.remove_offline(uid),
+            Record::PutOfflineQueue { uid, queue } => state.put_offline_queue(uid, queue.clone()),
+        }
+    }
+
+    /// `<crc32 of json, 8 hex> <json>\n`
+    fn encode(&self) -> Result<String, ChatErrors> {
+        let json = serde_json::to_string(self).map_err(|e| ChatErrors::Storage(e.to_string()))?;
+        Ok(format!("{:08x} {}\n", crc32fast::hash(json.as_bytes()), json))
+    }
+
+    fn decode(line: &[u8]) -> Option<Self> {
+        let line = std::str::from_utf8(line).ok()?;
+        let (crc, json) = line.split_once(' ')?;
+        if u32::from_str_radix(crc, 16).ok()? != crc32fast::hash(json.as_bytes()) {
+            return None;
+        }
+        serde_json::from_str(json).ok()
+    }
+}
+
+/// What the writer thread is asked to do, in order.
+enum Op {
+    Append(Box<Record>, Done),
+    Compact(Done),
+}
+
+/// Append-only, fsync'd log of state changes. The live state is mirrored in
+/// memory and the log is periodically rewritten down to it.
+///
+/// The log holds storage writes rather than the events that caused them: an
+/// event's outcome depends on the runtime state it met (who was online, the
+/// clock, generated ids), while a write replays to the same state every time.
+///
+/// The mirror keeps message bodies for the newest `MEMORY_STORED_MSGS` of each
+/// chan only, older ones are read back from the log when asked for, at the
+/// offset the mirror keeps for each message.
+///
+/// A writer thread owns the log file: it writes whatever queued up since its
+/// last fsync with a single one, applies the records to the mirrored state and
+/// only then answers the callers, so a write returns once it is durable or
+/// with the error that lost it. Writes block, keep them off async workers.
+/// Compaction runs on the same thread.
+pub struct WalStorage {
+    mirror: Arc<Mirror>,
+    ops: Mutex<Option<SyncSender<Op>>>, // taken on drop
+    writer: Option<JoinHandle<()>>,
+}
+
+/// The live state, less the bodies of messages pushed out of a chan's bounded
+/// history: those are only in the log.
+struct Mirror {
+    state: MemoryStorage,
+    // taken before `state` so both are seen as of the same record
+    index: Mutex<Index>,
+}
+
+/// Where the last logged version of every message is.
+struct Index {
+    log: Arc<Mutex<File>>, // the file the offsets point into, swapped by compaction
+    chans: HashMap<String, ChanIndex>,
+}
+
+#[derive(Default)]
+struct ChanIndex {
+    mirrored: HashMap<String, Entry>,
+    evicted: BTreeMap<String, Entry>, // only in the log, all older than the mirrored ones
+}
+
+#[derive(Clone)]
+struct Entry {
+    offset: u64,
+    // so thread replies and unread messages are found without reading the log
+    parent_id: Option<String>,
+    sender_id: String,
+    deleted: bool,
+}
+
+struct Log {
+    path: PathBuf,
+    file: File,
+    records: usize,    // records in the log file
+    compact_at: usize, // rewrite the log once it grows to this many records
+}
+
+impl WalStorage {
+    /// Replay the log at `path`, creating it if missing. A last record cut short
+    /// by a crash is dropped, a bad checksum on a complete record is an error.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, ChatErrors> {
+        Self::open_bounded(path.as_ref(), MEMORY_STORED_MSGS)
+    }
+
+    fn open_bounded(path: &Path, max_msgs_per_chan: usize) -> Result<Self, ChatErrors> {
+        let mirror = Mirror::open(path, max_msgs_per_chan)?;
+        let replayed = replay(path, |offset, record| mirror.apply(&record, offset))?;
+
+        let mut log = Log {
+            file: open_append(path)?,
+            path: path.to_path_buf(),
+            records: replayed,
+            compact_at: COMPACT_MIN_RECORDS,
+        };
+        log.compact(&mirror)?;
+        Self::start(mirror, log)
+    }
+
+    fn start(mirror: Mirror, log: Log) -> Result<Self, ChatErrors> {
+        let mirror = Arc::new(mirror);
+        let (ops, rx) = mpsc::sync_channel(MAX_PENDING);
+        let writer = thread::Builder::new()
+            .name("wal-writer".to_string())
+            .spawn({
+                let mirror = mirror.clone();
+                move || write_log(log, &mirror, rx)
+            })
+            .map_err(to_err)?;
+
+        Ok(Self {
+            mirror,
+            ops: Mutex::new(Some(ops)),
+            writer: Some(writer),
+        })
+    }
+
+    /// Replay the log without taking it over, safe while a server is appending
+    /// to it. The whole history is kept, for exports.
+    pub fn read_only(path: impl AsRef<Path>) -> Result<MemoryStorage, ChatErrors> {
+        let state = MemoryStorage::new();
+        replay(path.as_ref(), |_, record| record.apply(&state))?;
+        Ok(state)
+    }
+
+    /// Rewrite the log with one record per live item, once everything queued
+    /// before is written.
+    pub fn compact(&self) -> Result<(), ChatErrors> {
+        self.call(Op::Compact)
+    }
+
+    /// Log the record and wait until it is durable and applied.
+    fn append(&self, record: Record) -> Result<(), ChatErrors> {
+        self.call(|done| Op::Append(Box::new(record), done))
+    }
+
+    fn call(&self, op: impl FnOnce(Done) -> Op) -> Result<(), ChatErrors> {
+        let gone = || ChatErrors::Storage("wal writer is gone".to_string());
+        let (done, result) = mpsc::channel();
+        let ops = lock(&self.ops).clone();
+        ops.ok_or_else(gone)?.send(op(done)).map_err(|_| gone())?;
+        result.recv().map_err(|_| gone())?
+    }
+}
+
+impl Drop for WalStorage {
+    /// Closing the queue lets the writer finish what is queued and exit.
+    fn drop(&mut self) {
+        lock(&self.ops).take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+impl Mirror {
+    fn open(path: &Path, max_msgs_per_chan: usize) -> Result<Self, ChatErrors> {
+        Ok(Self {
+            state: MemoryStorage::bounded(max_msgs_per_chan),
+            index: Mutex::new(Index {
+                log: Arc::new(Mutex::new(open_read(path)?)),
+                chans: HashMap::new(),
+            }),
+        })
+    }
+
+    /// Apply a record read from, or just written to, `offset` of the log.
+    fn apply(&self, record: &Record, offset: u64) -> Result<(), ChatErrors> {
+        let mut index = self.index();
+        match record {
+            Record::Clear => index.chans.clear(),
+            Record::RemoveChannel { chan_id } => {
+                index.chans.remove(chan_id);
+            }
+            Record::RemoveMessages { msg_ids } => {
+                for chan in index.chans.values_mut() {
+                    for id in msg_ids {
+                        chan.mirrored.remove(id);
+                        chan.evicted.remove(id);
+                    }
+                }
+            }
+            Record::PutMessage(msg) => {
+                // an edit of an evicted message drops it again right away
+                let dropped = self.state.insert_message(msg)?;
+                let chan = index.chans.entry(msg.chan_id.clone()).or_default();
+                chan.evicted.remove(&msg.id);
+                let entry = Entry {
+                    offset,
+                    parent_id: msg.parent_id.clone(),
+                    sender_id: msg.sender_id.clone(),
+                    deleted: msg.deleted,
+                };
+                chan.mirrored.insert(msg.id.clone(), entry);
+                for id in dropped {
+                    if let Some(entry) = chan.mirrored.remove(&id) {
+                        chan.evicted.insert(id, entry);
+                    }
+                }
+                return Ok(());
+            }
+            _ => {}
+        }
+        record.apply(&self.state)
+    }
+
+    /// Ids of the chan's messages that fall outside `retention`, oldest first.
+    fn expired(&self, chan_id: &str, retention: Retention) -> Result<Vec<String>, ChatErrors> {
+        let index = self.index();
+        let mirrored = self.state.expired(chan_id, retention)?;
+        let Some(ids) = index.chans.get(chan_id).map(|chan| &chan.evicted) else {
+            return Ok(mirrored);
+        };
+
+        let expired = match retention {
+            Retention::Forever => 0,
+            Retention::Days(_) => {
+                let cutoff = retention.cutoff_id(Utc::now()).unwrap_or_default();
+                ids.keys().take_while(|id| **id < cutoff).count()
+            }
+            Retention::LastN(n) => (ids.len() + self.state.chan_len(chan_id)?).saturating_sub(n).min(ids.len()),
+        };
+        Ok(ids.keys().take(expired).cloned().chain(mirrored).collect())
+    }
+
+    fn index(&self) -> MutexGuard<'_, Index> {
+        lock(&self.index)
+    }
+}
+
+impl Storage for WalStorage {
+    /// Messages only in the log are read back too, once on startup or for a snapshot.
+    fn load(&self) -> Result<StoredState, ChatErrors> {
+        let (mut state, log, offsets) = {
+            let index = self.mirror.index();
+            let offsets = index
+                .chans
+                .values()
+                .flat_map(|chan| chan.evicted.values().map(|entry| entry.offset))
+                .collect();
+            (self.mirror.state.load()?, index.log.clone(), offsets)
+        };
+        state.messages.splice(0..0, read_at(&log, offsets)?);
+        Ok(state)
+    }
+
+    fn clear(&self) -> Result<(), ChatErrors> {
+        self.append(Record::Clear)
+    }
+
+    fn put_user(&self, user: &UserInfo) -> Result<(), ChatErrors> {
+        self.append(Record::PutUser(user.clone()))
+    }
+
+    fn put_channel(&self, chan: &Channel) -> Result<(), ChatErrors> {
+        self.append(Record::PutChannel(chan.settings()))
+    }
+
+    fn remove_channel(&self, chan_id: &str) -> Result<(), ChatErrors> {
+        self.append(Record::RemoveChannel {
+            chan_id: chan_id.to_string(),
+        })
+    }
+
+    fn put_member(&self, uid: &str, chan_id: &str) -> Result<(), ChatErrors> {
+        self.append(Record::PutMember {
+            uid: uid.to_string(),
+            chan_id: chan_id.to_string(),
+        })
+    }
+
+    fn remove_member(&self, uid: &str, chan_id: &str) -> Result<(), ChatErrors> {
+        self.append(Record::RemoveMember {
+            uid: uid.to_string(),
+            chan_id: chan_id.to_string(),
+        })
+    }
+
+    fn put_read_marker(&self, uid: &str, chan_id: &str, msg_id: &str) -> Result<(), ChatErrors> {
+        self.append(Record::PutReadMarker {
+            uid: uid.to_string(),
+            chan_id: chan_id.to_string(),
+            msg_id: msg_id.to_string(),
+        })
+    }
+
+    fn push_offline(&self, uid: &str, line: &str, keep: usize) -> Result<(), ChatErrors> {
+        self.append(Record::PushOffline {
+            uid: uid.to_string(),
+            line: line.to_string(),
+            keep,
+        })
+    }
+
+    fn remove_offline(&self, uid: &str) -> Result<(), ChatErrors> {
+        self.append(Record::RemoveOffline { uid: uid.to_string() })
+    }
+
+    fn put_message(&self, msg: &Message) -> Result<(), ChatErrors> {
+        self.append(Record::PutMessage(msg.clone()))
+    }
+
+    fn get_message(&self, msg_id: &str) -> Result<Option<Message>, ChatErrors> {
+        let (log, offset) = {
+            let index = self.mirror.index();
+            match index.chans.values().find_map(|chan| chan.evicted.get(msg_id)) {
+                Some(entry) => (index.log.clone(), entry.offset),
+                None => return self.mirror.state.get_message(msg_id),
+            }
+        };
+        Ok(read_at(&log, vec![offset])?.pop())
+    }
+
+    fn thread_replies(&self, chan_id: &str, parent_id: &str) -> Result<Vec<Message>, ChatErrors> {
+        let (mirrored, log, offsets) = {
+            let index = self.mirror.index();
+            // replies are newer than their root
+            let offsets = index
+                .chans
+                .get(chan_id)
+                .map(|chan| {
+                    chan.evicted
+                        .range::<str, _>((Bound::Excluded(parent_id), Bound::Unbounded))
+                        .filter(|(_, entry)| entry.parent_id.as_deref() == Some(parent_id))
+                        .map(|(_, entry)| entry.offset)
+                        .collect()
+                })
+                .unwrap_or_default();
+            (self.mirror.state.thread_replies(chan_id, parent_id)?, index.log.clone(), offsets)
+        };
+
+        let mut replies = read_at(&log, offsets)?;
+        replies.extend(mirrored);
+        Ok(replies)
+    }
+
+    fn prune_messages(&self, chan_id: &str, retention: Retention) -> Result<Vec<String>, ChatErrors> {
+        let msg_ids = self.mirror.expired(chan_id, retention)?;
+        if !msg_ids.is_empty() {
+            self.append(Record::RemoveMessages { msg_ids: msg_ids.clone() })?;
+        }
+        Ok(msg_ids)
+    }
+
+    fn messages_before(&self, chan_id: &str, before: Option<&str>, limit: usize) -> Result<Vec<Message>, ChatErrors> {
+        let (page, log, offsets) = {
+            let index = self.mirror.index();
+            let page = self.mirror.state.messages_before(chan_id, before, limit)?;
+            // evicted messages are all older than the mirrored ones
+            let upper = before.map_or(Bound::Unbounded, Bound::Excluded);
+            let offsets = index
+                .chans
+                .get(chan_id)
+                .map(|chan| {
+                    chan.evicted
+                        .range::<str, _>((Bound::Unbounded, upper))
+                        .rev()
+                        .take(limit - page.len())
+                        .map(|(_, entry)| entry.offset)
+                        .collect()
+                })
+                .unwrap_or_default();
+            (page, index.log.clone(), offsets)
+        };
+
+        let mut msgs = read_at(&log, offsets)?;
+        msgs.extend(page);
+        Ok(msgs)
+    }
+
+    fn count_messages(&self, chan_id: &str) -> Result<usize, ChatErrors> {
+        let index = self.mirror.index();
+        let evicted = index.chans.get(chan_id).map_or(0, |chan| chan.evicted.len());
+        Ok(evicted + self.mirror.state.chan_len(chan_id)?)
+    }
+
+    fn count_unread(&self, chan_id: &str, after: Option<&str>, uid: &str) -> Result<usize, ChatErrors> {
+        let index = self.mirror.index();
+        let lower = after.map_or(Bound::Unbounded, Bound::Excluded);
+        let evicted = index.chans.get(chan_id).map_or(0, |chan| {
+            chan.evicted
+                .range::<str, _>((lower, Bound::Unbounded))
+                .filter(|(_, entry)| !entry.deleted && entry.sender_id != uid)
+                .count()
+        });
+        Ok(evicted + self.mirror.state.count_unread(chan_id, after, uid)?)
+    }
+
+    /// The next start replays one record per live item instead of the whole log.
+    fn flush(&self) -> Result<(), ChatErrors> {
+        self.compact()
+    }
+}
+
+impl Log {
+    /// Write the records and fsync once, returning the offset of each. On
+    /// failure the file is cut back to where it was, so a half written group
+    /// never sits before later records.
+    fn append(&mut self, records: &[Record]) -> Result<Vec<u64>, ChatErrors> {
+        let len = self.file.metadata().map_err(to_err)?.len();
+        let mut group = String::new();
+        let mut offsets = Vec::with_capacity(records.len());
+        for record in records {
+            offsets.push(len + group.len() as u64);
+            group.push_str(&record.encode()?);
+        }
+        let written = self
+            .file
+            .write_all(group.as_bytes())
+            .and_then(|_| self.file.sync_data());
+        if let Err(e) = written {
+            let _ = self.file.set_len(len);
+            return Err(to_err(e));
+        }
+        self.records += records.len();
+        Ok(offsets)
+    }
+
+    /// Rewrite the log with one record per live user, chan, membership,
+    /// message, read marker and offline queue. Messages only in the log are
+    /// copied over from it one at a time, as last written, and the mirror's
+    /// offsets moved to the new log.
+    fn compact(&mut self, mirror: &Mirror) -> Result<(), ChatErrors> {
+        let tmp = self.path.with_extension("compact");
+        let mut file = File::create(&tmp).map_err(to_err)?;
+        let mut written = 0;
+        let mut records = 0;
+        let mut moved = HashMap::new(); // msg id -> offset in the new log
+        for record in live_records(mirror.state.load()?) {
+            let line = record.encode()?;
+            if let Record::PutMessage(msg) = &record {
+                moved.insert(msg.id.clone(), written);
+            }
+            file.write_all(line.as_bytes()).map_err(to_err)?;
+            written += line.len() as u64;
+            records += 1;
+        }
+
+        // only the writer thread changes the index, it stays as read here until swapped below
+        let (log, mut evicted): (_, Vec<(u64, String)>) = {
+            let index = mirror.index();
+            let evicted = index
+                .chans
+                .values()
+                .flat_map(|chan| chan.evicted.iter().map(|(id, entry)| (entry.offset, id.clone())))
+                .collect();
+            (index.log.clone(), evicted)
+        };
+        evicted.sort_unstable();
+        {
+            let mut log = lock(&log);
+            let mut reader = BufReader::new(&mut *log);
+            let mut line = Vec::new();
+            for (offset, id) in evicted {
+                reader.seek(SeekFrom::Start(offset)).map_err(to_err)?;
+                line.clear();
+                reader.read_until(b'\n', &mut line).map_err(to_err)?;
+                file.write_all(&line).map_err(to_err)?;
+                moved.insert(id, written);
+                written += line.len() as u64;
+                records += 1;
+            }
+        }
+        file.sync_all().map_err(to_err)?;
+        fs::rename(&tmp, &self.path).map_err(to_err)?;
+        sync_dir(&self.path)?;
+
+        let reader = open_read(&self.path)?;
+        {
+            let mut index = mirror.index();
+            for chan in index.chans.values_mut() {
+                for (id, entry) in chan.mirrored.iter_mut().chain(chan.evicted.iter_mut()) {
+                    if let Some(offset) = moved.get(id) {
+                        entry.offset = *offset;
+                    }
+                }
+            }
+            // reads that took the old file keep reading it at the old offsets
+            index.log = Arc::new(Mutex::new(reader));
+        }
+        self.file = open_append(&self.path)?;
+        self.records = records;
+        self.compact_at = COMPACT_MIN_RECORDS.max(records * 2);
+        info!("compacted wal: {} to {} records", self.path.display(), records);
+        Ok(())
+    }
+}
+
+/// The writer thread: group commit whatever queued up, apply it, answer the
+/// callers, compact when due.
+fn write_log(mut log: Log, mirror: &Mirror, ops: Receiver<Op>) {
+    while let Ok(op) = ops.recv() {
+        let mut group = vec![op];
+        while group.len() < MAX_GROUP
+            && let Ok(op) = ops.try_recv()
+        {
+            group.push(op);
+        }
+
+        let mut records = Vec::with_capacity(group.len());
+        let mut appended = Vec::with_capacity(group.len());
+        let mut compactions = Vec::new();
+        for op in group {
+            match op {
+                Op::Append(record, done) => {
+                    records.push(*record);
+                    appended.push(done);
+                }
+                Op::Compact(done) => compactions.push(done),
+            }
+        }
+
+        match log.append(&records) {
+            Ok(offsets) => {
+                for ((record, offset), done) in records.iter().zip(offsets).zip(appended) {
+                    let _ = done.send(mirror.apply(record, offset));
+                }
+            }
+            Err(e) => {
+                warn!("failed to write {} records to wal: {}, {}", records.len(), log.path.display(), e);
+                for done in appended {
+                    let _ = done.send(Err(ChatErrors::Storage(e.to_string())));
+                }
+            }
+        }
+
+        if !compactions.is_empty() || log.records >= log.compact_at {
+            let compacted = log.compact(mirror);
+            if let Err(e) = &compacted {
+                warn!("failed to compact wal: {}, {}", log.path.display(), e);
+            }
+            for done in compactions {
+                let _ = done.send(compacted.as_ref().map(|_| ()).map_err(|e| ChatErrors::Storage(e.to_string())));
+            }
+        }
+    }
+}
+
+/// Apply every record of the log at `path` with its offset, returning how
+/// many there were.
+fn replay(path: &Path, apply: impl FnMut(u64, Record) -> Result<(), ChatErrors>) -> Result<usize, ChatErrors> {
+    let (replayed, torn) = read_log(path, apply)?;
+    if torn {
+        warn!("dropping torn record at the end of wal: {}", path.display());
+    }
+    info!("replayed {} records from wal: {}", replayed, path.display());
+    Ok(replayed)
+}
+
+/// Stream the records of the log at `path` to `visit`, with the offset of
+/// each. Returns how many were read and whether a last one cut short was
+/// dropped. A bad checksum on a complete record is an error.
+fn read_log(
+    path: &Path,
+    mut visit: impl FnMut(u64, Record) -> Result<(), ChatErrors>,
+) -> Result<(usize, bool), ChatErrors> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((0, false)),
+        Err(e) => return Err(to_err(e)),
+    };
+
+    let mut reader = BufReader::new(file);
+    let mut line = Vec::new();
+    let (mut offset, mut read) = (0, 0);
+    loop {
+        line.clear();
+        let len = reader.read_until(b'\n', &mut line).map_err(to_err)?;
+        if len == 0 {
+            return Ok((read, false));
+        }
+        // only the last line can miss its newline: the crash (or a write in progress) hit mid record
+        let Some(encoded) = line.strip_suffix(b"\n") else {
+            return Ok((read, true));
+        };
+        let record = Record::decode(encoded).ok_or_else(|| {
+            ChatErrors::Storage(format!("corrupt record at line {} of wal: {}", read + 1, path.display()))
+        })?;
+        visit(offset, record)?;
+        offset += len as u64;
+        read += 1;
+    }
+}
+
+/// The messages logged at `offsets` of the log, oldest first. One seek per
+/// message, in file order.
+fn read_at(log: &Mutex<File>, mut offsets: Vec<u64>) -> Result<Vec<Message>, ChatErrors> {
+    if offsets.is_empty() {
+        return Ok(Vec::new());
+    }
+    offsets.sort_unstable();
+
+    let mut log = lock(log);
+    let mut reader = BufReader::new(&mut *log);
+    let mut line = Vec::new();
+    let mut msgs = Vec::with_capacity(offsets.len());
+    for offset in offsets {
+        reader.seek(SeekFrom::Start(offset)).map_err(to_err)?;
+        line.clear();
+        reader.read_until(b'\n', &mut line).map_err(to_err)?;
+        match line.strip_suffix(b"\n").and_then(Record::decode) {
+            Some(Record::PutMessage(msg)) => msgs.push(msg),
+            _ => return Err(ChatErrors::Storage(format!("no message at offset {} of wal", offset))),
+        }
+    }
+    msgs.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(msgs)
+}
+
+/// Everything but the messages only in the log.
+fn live_records(state: StoredState) -> impl Iterator<Item = Record> {
+    let users = state.users.into_iter().map(Record::PutUser);
+    let channels = state.channels.into_iter().map(Record::PutChannel);
+    let members = state
+        .members
+        .into_iter()
+        .map(|(uid, chan_id)| Record::PutMember { uid, chan_id });
+    let messages = state.messages.into_iter().map(Record::PutMessage);
+    let read_markers = state.read_markers.into_iter().flat_map(|(uid, markers)| {
+        markers.into_iter().map(move |(chan_id, msg_id)| Record::PutReadMarker {
+            uid: uid.clone(),
+            chan_id,
+            msg_id,
+        })
+    });
+    let offline_queues = state
+        .offline_queues
+        .into_iter()
+        .map(|(uid, queue)| Record::PutOfflineQueue { uid, queue });
+
+    users
+        .chain(channels)
+        .chain(members)
+        .chain(messages)
+        .chain(read_markers)
+        .chain(offline_queues)
+}
+
+fn open_append(path: &Path) -> Result<File, ChatErrors> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(to_err)
+}
+
+/// A handle to read records back at their offsets, creating the log if missing.
+fn open_read(path: &Path) -> Result<File, ChatErrors> {
+    OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(path)
+        .map_err(to_err)
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn to_err(e: std::io::Error) -> ChatErrors {
+    ChatErrors::Storage(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn msg(chan_id: &str, content: &str) -> Message {
+        Message::from_user("u1".to_string(), "alice".to_string(), chan_id.to_string(), content.to_string())
+    }
+
+    fn user() -> UserInfo {
+        UserInfo {
+            id: "u1".to_string(),
+            name: "alice".to_string(),
+            token_hash: String::new(),
+            offered_chans: HashSet::new(),
+        }
+    }
+
+    #[test]
+    fn replays_everything_it_logged() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("chat.wal");
+        let first = msg("a", "1");
+        {
+            let wal = WalStorage::open(&path).unwrap();
+            wal.put_user(&user()).unwrap();
+            wal.put_member("u1", "a").unwrap();
+            wal.put_message(&first).unwrap();
+            wal.put_message(&msg("a", "2")).unwrap();
+            wal.put_read_marker("u1", "a", &first.id).unwrap();
+            // readers see their own writes
+            assert_eq!(wal.get_message(&first.id).unwrap().unwrap().content, "1");
+        }
+
+        let state = WalStorage::open(&path).unwrap().load().unwrap();
+        assert_eq!(state.users[0].name, user().name);
+        assert_eq!(state.members, [("u1".to_string(), "a".to_string())]);
+        assert_eq!(state.messages.len(), 2);
+        assert_eq!(state.read_markers["u1"]["a"], first.id);
+    }
+
+    #[test]
+    fn replays_a_clear() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("chat.wal");
+        {
+            let wal = WalStorage::open(&path).unwrap();
+            wal.put_member("u2", "a").unwrap();
+            wal.put_message(&msg("a", "old")).unwrap();
+            wal.clear().unwrap();
+            wal.put_user(&user()).unwrap();
+        }
+
+        let state = WalStorage::open(&path).unwrap().load().unwrap();
+        assert_eq!(state.users.len(), 1);
+        assert!(state.members.is_empty());
+        assert!(state.messages.is_empty());
+    }
+
+    #[test]
+    fn flush_on_shutdown_compacts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("chat.wal");
+        let wal = WalStorage::open(&path).unwrap();
+        wal.put_member("u1", "a").unwrap();
+        wal.remove_member("u1", "a").unwrap();
+        wal.put_message(&msg("a", "kept")).unwrap();
+        wal.flush().unwrap();
+        drop(wal);
+
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 1);
+        assert_eq!(WalStorage::open(&path).unwrap().load().unwrap().messages.len(), 1);
+    }
+
+    #[test]
+    fn compaction_keeps_the_live_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("chat.wal");
+        let wal = WalStorage::open(&path).unwrap();
+        for i in 0..3 {
+            wal.put_message(&msg("a", &i.to_string())).unwrap();
+        }
+        wal.put_member("u1", "a").unwrap();
+        wal.put_read_marker("u1", "a", "m1").unwrap();
+        wal.remove_member("u1", "a").unwrap();
+        wal.put_read_marker("u2", "a", "m1").unwrap();
+        wal.put_read_marker("u2", "a", "m2").unwrap();
+        wal.push_offline("u2", "x", 1).unwrap();
+        wal.push_offline("u2", "y", 1).unwrap();
+        wal.compact().unwrap();
+        drop(wal);
+
+        let lines = fs::read_to_string(&path).unwrap().lines().count();
+        assert_eq!(lines, 5); // 3 messages, u2's read marker and offline queue
+        let state = WalStorage::open(&path).unwrap().load().unwrap();
+        assert_eq!(state.messages.len(), 3);
+        assert_eq!(state.read_markers["u2"]["a"], "m2");
+        assert!(!state.read_markers.contains_key("u1"));
+        assert_eq!(state.offline_queues["u2"].dropped, 1);
+    }
+
+    #[test]
+    fn replays_pruned_messages_as_gone() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("chat.wal");
+        {
+            let wal = WalStorage::open(&path).unwrap();
+            for i in 0..5 {
+                wal.put_message(&msg("a", &i.to_string())).unwrap();
+            }
+            assert_eq!(wal.prune_messages("a", Retention::LastN(2)).unwrap().len(), 3);
+            assert!(wal.prune_messages("a", Retention::LastN(2)).unwrap().is_empty());
+        }
+
+        let state = WalStorage::open(&path).unwrap().load().unwrap();
+        let contents: Vec<&str> = state.messages.iter().map(|msg| msg.content.as_str()).collect();
+        assert_eq!(contents, ["3", "4"]);
+    }
+
+    #[test]
+    fn reads_evicted_messages_back_from_the_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("chat.wal");
+        let contents = |msgs: Vec<Message>| msgs.into_iter().map(|msg| msg.content).collect::<Vec<_>>();
+        let mut root = msg("a", "root");
+        let mut reply = msg("a", "reply");
+        reply.parent_id = Some(root.id.clone());
+        {
+            let wal = WalStorage::open_bounded(&path, 2).unwrap();
+            wal.put_message(&root).unwrap();
+            wal.put_message(&reply).unwrap();
+            for i in 0..3 {
+                wal.put_message(&msg("a", &i.to_string())).unwrap();
+            }
+            wal.put_message(&msg("b", "other")).unwrap();
+            root.content = "edited".to_string();
+            wal.put_message(&root).unwrap();
+            assert_eq!(wal.mirror.state.load().unwrap().messages.len(), 3);
+            wal.compact().unwrap();
+            // the offsets moved with the messages
+            assert_eq!(wal.get_message(&root.id).unwrap().unwrap().content, "edited");
+            assert_eq!(contents(wal.thread_replies("a", &root.id).unwrap()), ["reply"]);
+        }
+
+        let wal = WalStorage::open_bounded(&path, 2).unwrap();
+        assert_eq!(wal.mirror.state.load().unwrap().messages.len(), 3);
+        assert_eq!(wal.get_message(&root.id).unwrap().unwrap().content, "edited");
+        assert_eq!(contents(wal.thread_replies("a", &root.id).unwrap()), ["reply"]);
+        let all = wal.messages_before("a", None, 10).unwrap();
+        assert_eq!(contents(all.clone()), ["edited", "reply", "0", "1", "2"]);
+        assert_eq!(contents(wal.messages_before("a", Some(&all[2].id), 1).unwrap()), ["reply"]);
+        assert_eq!(wal.load().unwrap().messages.len(), 6);
+        assert_eq!(wal.count_messages("a").unwrap(), 5);
+        assert_eq!(wal.count_unread("a", Some(&all[0].id), "u2").unwrap(), 4);
+        assert_eq!(wal.count_unread("a", None, "u1").unwrap(), 0);
+
+        assert_eq!(wal.prune_messages("a", Retention::LastN(3)).unwrap(), [root.id.clone(), reply.id.clone()]);
+        assert!(wal.get_message(&root.id).unwrap().is_none());
+        assert_eq!(contents(wal.messages_before("a", None, 10).unwrap()), ["0", "1", "2"]);
+    }
+
+    #[test]
+    fn drops_a_torn_last_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("chat.wal");
+        let record = Record::PutMessage(msg("a", "1")).encode().unwrap();
+        let torn = &record[..record.len() / 2];
+        fs::write(&path, format!("{}{}", record, torn)).unwrap();
+
+        let state = WalStorage::open(&path).unwrap().load().unwrap();
+        assert_eq!(state.messages.len(), 1);
+    }
+
+    #[test]
+    fn refuses_a_corrupt_complete_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("chat.wal");
+        let record = Record::PutMessage(msg("a", "1")).encode().unwrap();
+        let corrupt = record.replace("\"1\"", "\"2\"");
+
+        // last record, but complete: not a crash mid write
+        fs::write(&path, format!("{}{}", record, corrupt)).unwrap();
+        assert!(matches!(WalStorage::open(&path), Err(ChatErrors::Storage(e)) if e.contains("line 2")));
+
+        fs::write(&path, format!("{}{}", corrupt, record)).unwrap();
+        assert!(matches!(WalStorage::open(&path), Err(ChatErrors::Storage(e)) if e.contains("line 1")));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn writes_fail_when_the_log_can_not_be_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = Log {
+            path: dir.path().join("chat.wal"),
+            file: OpenOptions::new().append(true).open("/dev/full").unwrap(),
+            records: 0,
+            compact_at: COMPACT_MIN_RECORDS,
+        };
+        let mirror = Mirror::open(&dir.path().join("chat.wal"), MEMORY_STORED_MSGS).unwrap();
+        let wal = WalStorage::start(mirror, log).unwrap();
+
+        let lost = msg("a", "1");
+        assert!(matches!(wal.put_message(&lost), Err(ChatErrors::Storage(_))));
+        assert!(wal.get_message(&lost.id).unwrap().is_none());
+    }
+}